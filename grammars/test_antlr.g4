@@ -0,0 +1,23 @@
+grammar Calc;
+
+prog : stat+ EOF ;
+
+stat : 'print' STRING ';'   # Print
+     | ID '=' expr ';'      # Assign
+     | expr ';'             # Eval
+     ;
+
+expr : expr ('*' | '/') expr
+     | expr ('+' | '-') expr
+     | INT
+     | ID
+     | '(' expr ')'
+     ;
+
+ID      : [a-zA-Z_] [a-zA-Z_0-9]* ;
+INT     : DIGIT+ ;
+STRING  : '"' (~["\\\r\n] | '\\' .)* '"' ;
+fragment DIGIT : [0-9] ;
+
+COMMENT : '/*' .*? '*/' -> skip ;
+WS      : [ \t\r\n]+ -> skip ;
@@ -1,4 +1,5 @@
-use crate::solver::{CoralStepCircuit, CoralWires, to_F};
+use crate::parser::NP_MAX_LOOKAHEAD;
+use crate::solver::{CoralStepCircuit, CoralWires, STACK_SPAN_LIMBS, to_F};
 use crate::util::ArkPrimeField;
 use ark_r1cs_std::{
     GR1CSVar,
@@ -141,6 +142,62 @@ pub fn vanishing_poly<F: ArkPrimeField>(
     Ok(vanish)
 }
 
+// The current character followed by the next characters of the committed document. The first
+// read binds the leaf to the document, so the lookahead can't disagree with the tree. Without
+// multi-character predicates there is no document ROM and the window is the character alone
+#[tracing::instrument(target = "gr1cs")]
+pub fn doc_lookahead<F: ArkPrimeField>(
+    csc: &mut CoralStepCircuit<F>,
+    doc_offset: &FpVar<F>,
+    terminal: &Boolean<F>,
+    is_np: &Boolean<F>,
+    cur_symbol: &FpVar<F>,
+    doc_ctr: &FpVar<F>,
+    memory: &mut RunningMemWires<F>,
+) -> Result<Vec<FpVar<F>>, SynthesisError> {
+    let mut window = vec![cur_symbol.clone()];
+    if csc.np_lookahead < 2 {
+        return Ok(window);
+    }
+
+    let is_char = terminal & cur_symbol.is_neq(&FpVar::constant(csc.epsilon_val))?;
+    let looking = terminal & is_np;
+    for j in 0..csc.np_lookahead {
+        let cond = if j == 0 { &is_char } else { &looking };
+        let addr = cond.select(
+            &(doc_ctr + doc_offset + FpVar::constant(F::from(j as u64))),
+            &FpVar::zero(),
+        )?;
+        let res =
+            csc.mem
+                .as_mut()
+                .unwrap()
+                .conditional_read(cond, &addr, csc.doc_ram_tag, memory)?;
+        if j == 0 {
+            res.vals[0].conditional_enforce_equal(cur_symbol, &is_char)?;
+        } else {
+            window.push(res.vals[0].clone());
+        }
+    }
+    Ok(window)
+}
+
+// Value fits in 21 bits, the width of a Unicode code point
+#[tracing::instrument(target = "gr1cs")]
+pub fn enforce_code_point<F: ArkPrimeField>(
+    val: &FpVar<F>,
+    cs: ConstraintSystemRef<F>,
+) -> Result<(), SynthesisError> {
+    let mut packed = FpVar::zero();
+    for i in 0..21 {
+        let bit = Boolean::new_witness(cs.clone(), || {
+            Ok((val.value()?.into_bigint().0[0] >> i) & 1 == 1)
+        })?;
+        packed += FpVar::from(bit) * F::from(1u64 << i);
+    }
+    packed.enforce_equal(val)
+}
+
 #[tracing::instrument(target = "gr1cs")]
 pub fn rule_pop_wrapper<F: ArkPrimeField>(
     csc: &mut CoralStepCircuit<F>,
@@ -208,22 +265,105 @@ pub fn trans_push_wrapper<F: ArkPrimeField>(
     Ok(())
 }
 
+#[tracing::instrument(target = "gr1cs")]
+pub fn delim_stack<F: ArkPrimeField>(
+    csc: &mut CoralStepCircuit<F>,
+    terminal: &Boolean<F>,
+    should_run: &Boolean<F>,
+    cur_symbol: &FpVar<F>,
+    cur_kind: &FpVar<F>,
+    wires: &mut CoralWires<F>,
+    memory: &mut RunningMemWires<F>,
+) -> Result<(), SynthesisError> {
+    let kind_is = |k: u64| wires.stk_kind.is_eq(&FpVar::constant(F::from(k)));
+    let in_scope = wires.stk_kind.is_neq(&FpVar::zero())?;
+    let (is_push, is_pop, is_peek, is_drop) = (kind_is(1)?, kind_is(2)?, kind_is(3)?, kind_is(4)?);
+    let non_terminal = !terminal & should_run;
+
+    //Every committed character under the scope is packed into the span
+    let is_epsilon = cur_symbol.is_eq(&FpVar::constant(csc.epsilon_val))?;
+    let record = terminal & should_run & &in_scope & !is_epsilon;
+
+    let span_full = wires
+        .stk_limb
+        .is_eq(&FpVar::constant(to_F(STACK_SPAN_LIMBS)))?;
+    span_full.conditional_enforce_equal(&Boolean::FALSE, &record)?;
+
+    for (i, limb) in wires.stk_span.iter_mut().enumerate() {
+        let is_cur_limb = wires.stk_limb.is_eq(&FpVar::constant(to_F(i)))?;
+        let packed = &*limb + cur_symbol * &wires.stk_mult;
+        *limb = (&record & is_cur_limb).select(&packed, limb)?;
+    }
+
+    let next_mult = &wires.stk_mult * FpVar::constant(csc.shift_powers[1]);
+    let limb_done = next_mult.is_eq(&FpVar::constant(csc.span_limb_cap))?;
+    let next_limb = limb_done.select(&(&wires.stk_limb + FpVar::one()), &wires.stk_limb)?;
+    let next_mult = limb_done.select(&FpVar::one(), &next_mult)?;
+    wires.stk_mult = record.select(&next_mult, &wires.stk_mult)?;
+    wires.stk_limb = record.select(&next_limb, &wires.stk_limb)?;
+
+    //stack_END closes the scope, POP and PEEK must match the top, PUSH and PEEK leave it there
+    let is_end = &non_terminal & cur_symbol.is_eq(&FpVar::constant(csc.stack_end_val))?;
+    in_scope.conditional_enforce_equal(&Boolean::TRUE, &is_end)?;
+
+    let popped = csc.mem.as_mut().unwrap().conditional_pop(
+        &(&is_end & (&is_pop | &is_peek | &is_drop)),
+        csc.delim_stack_tag,
+        memory,
+    )?;
+    let check_top = &is_end & (&is_pop | &is_peek);
+    for (top, limb) in popped.vals.iter().zip(wires.stk_span.iter()) {
+        top.conditional_enforce_equal(limb, &check_top)?;
+    }
+
+    let push_cond = &is_end & (&is_push | &is_peek);
+    let push_vals = wires
+        .stk_span
+        .iter()
+        .map(|limb| push_cond.select(limb, &FpVar::zero()))
+        .collect::<Result<Vec<_>, _>>()?;
+    csc.mem.as_mut().unwrap().conditional_push(
+        &push_cond,
+        csc.delim_stack_tag,
+        push_vals,
+        memory,
+    )?;
+
+    wires.stk_kind = is_end.select(&FpVar::zero(), &wires.stk_kind)?;
+    wires.stk_mult = is_end.select(&FpVar::one(), &wires.stk_mult)?;
+    wires.stk_limb = is_end.select(&FpVar::zero(), &wires.stk_limb)?;
+    for limb in wires.stk_span.iter_mut() {
+        *limb = is_end.select(&FpVar::zero(), limb)?;
+    }
+
+    //Scopes do not nest
+    let enter = cur_kind.is_neq(&FpVar::zero())?;
+    in_scope.conditional_enforce_equal(&Boolean::FALSE, &enter)?;
+    wires.stk_kind = enter.select(cur_kind, &wires.stk_kind)?;
+
+    Ok(())
+}
+
 #[tracing::instrument(target = "gr1cs")]
 pub fn extend_commit<F: ArkPrimeField>(
     csc: &mut CoralStepCircuit<F>,
     shift: &FpVar<F>,
     terminal: &Boolean<F>,
     wires: &CoralWires<F>,
-    memory: &mut RunningMemWires<F>,
     val: &FpVar<F>,
     cs: ConstraintSystemRef<F>,
 ) -> Result<FpVar<F>, SynthesisError> {
-    let blind = FpVar::new_witness(cs.clone(), || Ok(csc.blind))?;
     let running_eval = &wires.running_eval;
     let is_one = running_eval.is_eq(&FpVar::one())?;
-    let chal = memory.perm_chal.clone();
+    let chal = &wires.doc_chal;
 
-    let cond_running_eval = is_one.select(&(&chal[0] - &blind), running_eval)?;
+    //Blind and padding roots fold into a single factor
+    let blind_eval = FpVar::new_witness(cs.clone(), || {
+        let chal_val = chal.value()?;
+        Ok(csc.blind_roots.iter().map(|r| chal_val - r).product::<F>())
+    })?;
+
+    let cond_running_eval = is_one.select(&blind_eval, running_eval)?;
 
     let epsilon_val = FpVar::constant(csc.epsilon_val);
 
@@ -232,7 +372,7 @@ pub fn extend_commit<F: ArkPrimeField>(
     let same_eval = !terminal | &is_epsilon;
 
     let root = (val * shift) + &wires.doc_ctr; //To account for SOI 
-    let next_root = &chal[0] - root;
+    let next_root = chal - root;
     let eval = &wires.running_eval * next_root;
 
     same_eval.select(&cond_running_eval, &eval)
@@ -297,6 +437,7 @@ pub fn is_terminal<F: ArkPrimeField>(
     cur_symbol: &FpVar<F>,
     child: &FpVar<F>,
     sib: &FpVar<F>,
+    doc_offset: &FpVar<F>,
     wires: &CoralWires<F>,
     memory: &mut RunningMemWires<F>,
     cs: ConstraintSystemRef<F>,
@@ -333,9 +474,34 @@ pub fn is_terminal<F: ArkPrimeField>(
         cs.clone(),
     )?;
 
-    let poly_eval = vanishing_poly(&polys, cur_symbol)?;
+    //Row belongs to the predicate rule we are under
+    polys[0].conditional_enforce_equal(&wires.np_rule, &(terminal & &is_np))?;
+
+    let window = doc_lookahead(
+        csc,
+        doc_offset,
+        terminal,
+        &is_np,
+        cur_symbol,
+        &new_wires.doc_ctr,
+        memory,
+    )?;
+
+    //NegPred excludes the strings, PosPred requires one of them. Every prefix of the window is
+    //packed with its length, so a string only matches the prefix as long as itself
+    let mut poly_eval = FpVar::one();
+    let mut packed = FpVar::zero();
+    for (m, c) in window.iter().enumerate() {
+        packed += c * FpVar::constant(csc.shift_powers[m]);
+        let prefix = &packed
+            + FpVar::constant(
+                F::from((m + 1) as u64) * csc.shift_powers[1].pow([NP_MAX_LOOKAHEAD as u64]),
+            );
+        poly_eval *= vanishing_poly(&polys[2..], &prefix)?;
+    }
+    let in_set = FpVar::from(poly_eval.is_zero()?);
 
-    poly_eval.conditional_enforce_not_equal(&FpVar::zero(), &(terminal & &is_np))?;
+    in_set.conditional_enforce_equal(&polys[1], &(terminal & &is_np))?;
 
     let last = FpVar::<F>::new_witness(cs.clone(), || Ok(csc.tree_size))?;
 
@@ -343,26 +509,25 @@ pub fn is_terminal<F: ArkPrimeField>(
 
     let trans_stack_pop_values = trans_pop_wrapper(
         csc,
-        &(terminal & is_last.not() & &sib_not_null.clone().not()),
+        &(terminal & is_last.clone().not() & &sib_not_null.clone().not()),
         &mut new_wires,
         memory,
     )?;
 
-    let running_eval = extend_commit(
-        csc,
-        shift,
-        terminal,
-        &new_wires,
-        memory,
-        cur_symbol,
-        cs.clone(),
-    )?;
+    let running_eval = extend_commit(csc, shift, terminal, &new_wires, cur_symbol, cs.clone())?;
 
     new_wires.running_eval = running_eval;
     new_wires.parent_id = sib_not_null.select(&new_wires.parent_id, &trans_stack_pop_values[1])?;
     new_wires.cur_node_id = sib_not_null.select(sib, &trans_stack_pop_values[0])?;
+
+    //Tree is done, everything after points at null
+    let tree_done = terminal & &is_last;
+    new_wires.parent_id = tree_done.select(tree_null_val, &new_wires.parent_id)?;
+    new_wires.cur_node_id = tree_done.select(tree_null_val, &new_wires.cur_node_id)?;
     let is_epsilon = cur_symbol.is_eq(&FpVar::constant(csc.epsilon_val))?;
     new_wires.doc_ctr = is_epsilon.select(&new_wires.doc_ctr, &(&new_wires.doc_ctr + F::ONE))?;
+    //Final state must not reveal the document length
+    new_wires.doc_ctr = tree_done.select(&FpVar::zero(), &new_wires.doc_ctr)?;
 
     new_wires.np_rule = FpVar::zero();
 
@@ -381,9 +546,11 @@ pub fn not_terminal<F: ArkPrimeField>(
     parent: &FpVar<F>,
     is_root: &Boolean<F>,
     ws_val: &FpVar<F>,
+    comment_val: &FpVar<F>,
     round_num: usize,
     cur_is_atomic: &Boolean<F>,
     cur_is_np: &Boolean<F>,
+    cur_kind: &FpVar<F>,
     wires: &CoralWires<F>,
     memory: &mut RunningMemWires<F>,
     cs: ConstraintSystemRef<F>,
@@ -428,6 +595,35 @@ pub fn not_terminal<F: ArkPrimeField>(
     //np
     rule_lookup_vec.push(FpVar::from(cur_is_np & &is_not_root_check));
 
+    //delimiter stack op, already zero outside is_not_root_check
+    rule_lookup_vec.push(cur_kind.clone());
+
+    //Character class, the child is any code point in [range_lo, range_end)
+    let range_lo = FpVar::new_witness(cs.clone(), || {
+        Ok(csc.char_range(cur_symbol.value()?, rule_lookup_vec[0].value()?)[0])
+    })?;
+    let range_end = FpVar::new_witness(cs.clone(), || {
+        Ok(csc.char_range(cur_symbol.value()?, rule_lookup_vec[0].value()?)[1])
+    })?;
+    // Without classes every row has a zero range, so the table lookup alone pins it
+    let is_class = if csc.char_ranges.is_empty() {
+        Boolean::FALSE
+    } else {
+        range_end.is_neq(&FpVar::zero())?
+    };
+    if !csc.char_ranges.is_empty() {
+        let check_class = &is_class & should_run;
+
+        let above_lo = check_class.select(&(&rule_lookup_vec[0] - &range_lo), &FpVar::zero())?;
+        enforce_code_point(&above_lo, cs.clone())?;
+        let below_end =
+            check_class.select(&(&range_end - &rule_lookup_vec[0] - F::ONE), &FpVar::zero())?;
+        enforce_code_point(&below_end, cs.clone())?;
+    }
+
+    rule_lookup_vec.push(range_lo);
+    rule_lookup_vec.push(range_end);
+
     //Switch point for rule push
     let switch_var = FpVar::new_witness(cs.clone(), || Ok(csc.switch_wits[round_num]))?;
 
@@ -439,8 +635,8 @@ pub fn not_terminal<F: ArkPrimeField>(
         let switch_var_eq = switch_var.is_eq(&FpVar::constant(F::from(i as u64)))?;
         switch_flag = &switch_var_eq | &prev_round_flag;
 
-        //This should always be false - you shouldnt be passing ws here except for the ws rule itself
-        let is_ws = rule_lookup_vec[i].is_eq(ws_val)?;
+        //This should always be false - you shouldnt be passing ws or comments here except for the ws/comment rule itself
+        let is_ws = rule_lookup_vec[i].is_eq(ws_val)? | rule_lookup_vec[i].is_eq(comment_val)?;
 
         let is_not_ws_rule_itself = should_run & !switch_var_eq.clone();
 
@@ -479,7 +675,7 @@ pub fn not_terminal<F: ArkPrimeField>(
         cs.clone(),
     )?;
 
-    rule_lookup_vec[0] = is_any.select(&FpVar::zero(), &rule_lookup_vec[0])?;
+    rule_lookup_vec[0] = (is_any | is_class).select(&FpVar::zero(), &rule_lookup_vec[0])?;
 
     rule_read(
         csc,
@@ -559,7 +755,7 @@ pub fn node_circuit<F: ArkPrimeField>(
     wires.atom_sp = wires
         .atom_flag
         .select(&wires.atom_sp, &rule_is_atom_af_off)?;
-    wires.atom_flag = (af_on.clone()) | (!af_on & cur_is_atom.clone());
+    wires.atom_flag = (af_on.clone()) | (!af_on.clone() & cur_is_atom.clone());
 
     // //Update np rule
     //If we're out of the np subtree turn off
@@ -597,6 +793,19 @@ pub fn node_circuit<F: ArkPrimeField>(
 
     is_root.enforce_equal(&is_root_check)?;
 
+    //Delimiter stack op this node opens, bound through the rule table. Every row has 0 there
+    //when the grammar has no delimiter stack.
+    let cur_kind = if csc.has_stack {
+        let cur_kind_wit = FpVar::new_witness(cs.clone(), || {
+            Ok(to_F::<F>(csc.stack_kind(symbol.value()?)))
+        })?;
+        let is_any = symbol.is_eq(&FpVar::constant(csc.any_rule_val))?;
+        (!terminal.clone() & should_run & !is_root.clone() & !is_any)
+            .select(&cur_kind_wit, &FpVar::zero())?
+    } else {
+        FpVar::zero()
+    };
+
     // Assert stack is empty at root
     let rule_stack_is_empty = memory.stack_ptrs[csc.rule_stack_tag].is_eq(&offsets[0])?;
 
@@ -613,9 +822,10 @@ pub fn node_circuit<F: ArkPrimeField>(
         cs.clone(),
     )?;
 
-    // //Check if rule is WS
+    // //Check if rule is WS or COMMENT, both are implicit between tokens
     let ws_val = FpVar::constant(csc.whitespace_rule_val);
-    let is_ws = symbol.is_eq(&ws_val)?;
+    let comment_val = FpVar::constant(csc.comment_rule_val);
+    let is_ws = symbol.is_eq(&ws_val)? | symbol.is_eq(&comment_val)?;
 
     // pop from rule stack
     let (top_rule_pop_values, top_rule_pop_bool) = rule_pop_wrapper(
@@ -636,7 +846,8 @@ pub fn node_circuit<F: ArkPrimeField>(
 
     //Either rule popped == node and not zero (DOBULE CHECK SOME STUFF WITH WS)
     let not_root_stack_cond = pop_equal_node & (is_root.clone().not()) & &rule_end;
-    let ws_stack_cond = is_ws & &wires.atom_flag.clone().not();
+    //Trivia rules are atomic themselves, so check the flag of the enclosing context
+    let ws_stack_cond = is_ws & &af_on.clone().not();
 
     let stack_condition = not_root_stack_cond | rule_stack_is_empty | ws_stack_cond;
 
@@ -654,6 +865,7 @@ pub fn node_circuit<F: ArkPrimeField>(
         &symbol,
         &child,
         &sib,
+        &offsets[csc.doc_ram_tag],
         &wires,
         memory,
         cs.clone(),
@@ -674,9 +886,11 @@ pub fn node_circuit<F: ArkPrimeField>(
         &parent,
         &is_root,
         &ws_val,
+        &comment_val,
         round_num,
         &cur_is_atom,
         &cur_is_np,
+        &cur_kind,
         &wires,
         memory,
         cs.clone(),
@@ -702,6 +916,16 @@ pub fn node_circuit<F: ArkPrimeField>(
         csc,
     )?;
 
+    if csc.has_stack {
+        delim_stack(
+            csc, &terminal, should_run, &symbol, &cur_kind, &mut wires, memory,
+        )?;
+    }
+
+    //Dead slots keep pointing at null
+    wires.cur_node_id = should_run.select(&wires.cur_node_id, tree_null_val)?;
+    wires.parent_id = should_run.select(&wires.parent_id, tree_null_val)?;
+
     wires.count = &wires.count + FpVar::one();
 
     Ok(wires)
@@ -723,15 +947,17 @@ pub fn multi_node_step<F: ArkPrimeField>(
 
     let shift = FpVar::constant(csc.shift_powers[1]); // We want 2^{32}.
 
-    let offsets = [
-        FpVar::constant(F::ZERO),
-        FpVar::constant(F::ZERO),
+    //Indexed by tag, the stacks come first and start at zero
+    let mut offsets = vec![FpVar::constant(F::ZERO); csc.tree_ram_tag];
+    offsets.extend([
         FpVar::constant(F::from(csc.tree_ram_offset as u64)),
         FpVar::constant(F::from(csc.rule_ram_offset as u64)),
         FpVar::constant(F::from(csc.np_ram_offset as u64)),
-    ];
+        FpVar::constant(F::from(csc.doc_ram_offset as u64)),
+    ]);
 
-    let mut prev_round_flag = Boolean::new_witness(cs.clone(), || Ok(false))?;
+    //Steps past the end of a padded tree start out switched off
+    let mut prev_round_flag = wires.cur_node_id.is_eq(&tree_null_val)?;
 
     let mut switch_flag: Boolean<F>;
 
@@ -763,7 +989,7 @@ pub fn multi_node_step<F: ArkPrimeField>(
         &is_empty.not(),
     )?;
 
-    let is_last_round = next_wires.count.value()? >= csc.tree_size;
+    let is_last_round = next_wires.count.value()? >= to_F(csc.tree_null_val);
 
     ivcify(
         wires,
@@ -801,6 +1027,16 @@ pub fn ivcify<F: ArkPrimeField>(
     old_wires.running_eval.enforce_equal(&running_eval_in)?;
     new_wires.running_eval.enforce_equal(&running_eval_out)?;
 
+    //Doc challenge is carried unchanged, the verifier checks it in z_0
+    let (doc_chal_in, doc_chal_out) = FpVar::new_input_output_pair(
+        cs.clone(),
+        || old_wires.doc_chal.value(),
+        || new_wires.doc_chal.value(),
+    )?;
+    old_wires.doc_chal.enforce_equal(&doc_chal_in)?;
+    new_wires.doc_chal.enforce_equal(&doc_chal_out)?;
+    doc_chal_in.enforce_equal(&doc_chal_out)?;
+
     let (doc_ctr_in, doc_ctr_out) = FpVar::new_input_output_pair(
         cs.clone(),
         || old_wires.doc_ctr.value(),
@@ -881,16 +1117,70 @@ pub fn ivcify<F: ArkPrimeField>(
     l_vals.push(new_wires.count.clone());
     r_vals.push(count_out.clone());
 
-    chunk_cee(&Boolean::TRUE, &l_vals, &r_vals, csc, cs)?;
+    //Delimiter stack scope, only carried when the grammar has one
+    if csc.has_stack {
+        let (stk_kind_in, stk_kind_out) = FpVar::new_input_output_pair(
+            cs.clone(),
+            || old_wires.stk_kind.value(),
+            || new_wires.stk_kind.value(),
+        )?;
+        l_vals.push(old_wires.stk_kind.clone());
+        r_vals.push(stk_kind_in.clone());
+        l_vals.push(new_wires.stk_kind.clone());
+        r_vals.push(stk_kind_out.clone());
+
+        let (stk_mult_in, stk_mult_out) = FpVar::new_input_output_pair(
+            cs.clone(),
+            || old_wires.stk_mult.value(),
+            || new_wires.stk_mult.value(),
+        )?;
+        old_wires.stk_mult.enforce_equal(&stk_mult_in)?;
+        new_wires.stk_mult.enforce_equal(&stk_mult_out)?;
+
+        let (stk_limb_in, stk_limb_out) = FpVar::new_input_output_pair(
+            cs.clone(),
+            || old_wires.stk_limb.value(),
+            || new_wires.stk_limb.value(),
+        )?;
+        l_vals.push(old_wires.stk_limb.clone());
+        r_vals.push(stk_limb_in.clone());
+        l_vals.push(new_wires.stk_limb.clone());
+        r_vals.push(stk_limb_out.clone());
+
+        //Span limbs use the full field, so they are not packed
+        for (old_limb, new_limb) in old_wires.stk_span.iter().zip(new_wires.stk_span.iter()) {
+            let (limb_in, limb_out) =
+                FpVar::new_input_output_pair(cs.clone(), || old_limb.value(), || new_limb.value())?;
+            old_limb.enforce_equal(&limb_in)?;
+            new_limb.enforce_equal(&limb_out)?;
+        }
+    }
+
+    chunk_cee(&Boolean::TRUE, &l_vals, &r_vals, csc, cs.clone())?;
+
+    //Grammar digest is fixed by the circuit and carried through every step
+    let grammar_digest = FpVar::new_constant(cs.clone(), csc.grammar_digest)?;
+    let (digest_in, digest_out) = FpVar::new_input_output_pair(
+        cs.clone(),
+        || Ok(csc.grammar_digest),
+        || Ok(csc.grammar_digest),
+    )?;
+    digest_in.enforce_equal(&grammar_digest)?;
+    digest_out.enforce_equal(&grammar_digest)?;
 
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::frontend::read_rules;
     use crate::parser::*;
     use crate::prover::{run_doc_committer, setup};
-    use crate::{circuit::*, solver::InterRoundWires, util::*};
+    use crate::{
+        circuit::*,
+        solver::{InterRoundWires, TreeSizeClass, doc_challenge},
+        util::*,
+    };
     use ark_bn254::Fr as F;
     use ark_relations::gr1cs::{
         ConstraintSystem,
@@ -899,13 +1189,47 @@ mod tests {
     use std::fs;
     use tracing_subscriber::{Registry, layer::SubscriberExt};
 
+    // The tests run against the ceremony file when it is there, and against a generated SRS with
+    // the test-srs feature or without the file
+    pub fn test_srs() -> Srs {
+        if !cfg!(feature = "test-srs") && std::path::Path::new(PTAU_PATH).exists() {
+            //Without a shipped or .sha256 hash the tests only warn about it
+            return Srs::open(PTAU_PATH, None, true).unwrap();
+        }
+
+        use std::sync::OnceLock;
+        static PATH: OnceLock<String> = OnceLock::new();
+        let path = PATH.get_or_init(|| {
+            let path = std::env::temp_dir().join(format!("coral_test_{}.ptau", std::process::id()));
+            let tmp = path.with_extension("ptau.tmp");
+            write_test_ptau(tmp.to_str().unwrap(), 18, 0).unwrap();
+            fs::rename(&tmp, &path).unwrap();
+            path.to_str().unwrap().to_string()
+        });
+        //Generated file, there is no published hash to check it against
+        Srs::open(path, None, true).unwrap()
+    }
+
     pub fn full_test_function_multi(pest_file: String, input: String) {
-        let grammar = fs::read_to_string(pest_file).expect("Failed to read grammar file");
+        full_test_function_padded(pest_file, input, TreeSizeClass::Exact, None, None, false);
+    }
+
+    pub fn full_test_function_padded(
+        pest_file: String,
+        input: String,
+        tree_size_class: TreeSizeClass,
+        max_doc_len: Option<usize>,
+        max_arity: Option<usize>,
+        collapse_chains: bool,
+    ) {
+        let rules = read_rules(&pest_file).expect("Failed to read grammar file");
         let input_text = fs::read_to_string(input).expect("Failed to read input file");
 
         let mut grammar_graph = GrammarGraph::new();
+        grammar_graph.max_arity = max_arity;
+        grammar_graph.collapse_chains = collapse_chains;
         grammar_graph
-            .parse_text_and_build_graph(&grammar, &input_text)
+            .parse_rules_and_build_graph(rules, &input_text)
             .expect("Failed to parse input");
 
         // Convert the petgraph tree to a left-child right-sibling tree
@@ -915,19 +1239,30 @@ mod tests {
 
         let nodes_per_step = 1;
 
-        let (ark_ck, _) = gen_ark_pp(input_text.len());
-
-        let doc_commit = run_doc_committer(&input_text.chars().collect(), &ark_ck);
-
-        let (_, mut base, _, _) =
-            setup::<AF>(&grammar_graph, nodes_per_step, doc_commit.blind).unwrap();
+        let srs = test_srs();
+        let (ark_ck, _) = gen_ark_pp(max_doc_len.unwrap_or(input_text.len()), &srs);
+
+        let doc_commit =
+            run_doc_committer(&input_text.chars().collect(), &ark_ck, max_doc_len).unwrap();
+
+        let (_, mut base, _, _) = setup::<AF>(
+            &grammar_graph,
+            nodes_per_step,
+            doc_commit.blind_roots,
+            tree_size_class,
+            None,
+            &srs,
+        )
+        .unwrap();
+        base.doc_chal = doc_challenge(
+            &doc_commit.doc_commit,
+            base.grammar_digest,
+            &base.mem.as_ref().unwrap().perm_chal,
+        );
 
         let mut irw = InterRoundWires::new();
 
-        let n_rounds = u32::div_ceil(
-            grammar_graph.lcrs_tree.node_count() as u32,
-            nodes_per_step as u32,
-        ) as usize;
+        let n_rounds = base.num_steps();
 
         let constraint_layer = ConstraintLayer::new(TracingMode::OnlyConstraints);
         let subscriber = Registry::default()
@@ -988,6 +1323,96 @@ mod tests {
         }
     }
 
+    fn digest_of(grammar: &str) -> F {
+        let mut grammar_graph = GrammarGraph::new();
+        grammar_graph.compile_grammar(grammar).unwrap();
+        crate::solver::grammar_digest(&grammar_graph)
+    }
+
+    #[test]
+    fn grammar_digest_binds_table() {
+        let json = fs::read_to_string("grammars/json.pest").unwrap();
+        let toml = fs::read_to_string("grammars/toml.pest").unwrap();
+
+        assert_eq!(digest_of(&json), digest_of(&json));
+        assert_ne!(digest_of(&json), digest_of(&toml));
+
+        // Same table dimensions, different terminal
+        assert_ne!(
+            digest_of("root = { \"a\" ~ EOI }"),
+            digest_of("root = { \"b\" ~ EOI }")
+        );
+    }
+
+    #[test]
+    fn public_io_layout_matches_step_zero() {
+        let (z0, expected, _) = step_zero_state();
+        for (i, value) in expected {
+            let claimed: AF = segmented_circuit_memory::bellpepper::nova_to_ark_field(&z0[i]);
+            assert_eq!(claimed, value, "slot {}", i);
+        }
+    }
+
+    #[test]
+    fn tampered_memory_accumulator_rejected() {
+        let (mut z0, expected, layout) = step_zero_state();
+        assert_eq!(crate::verifier::initial_mismatch(&expected, &z0), None);
+
+        let slot = layout.mem_accs.start;
+        z0[slot] += N1::from(1);
+        assert_eq!(
+            crate::verifier::initial_mismatch(&expected, &z0),
+            Some(slot)
+        );
+    }
+
+    // Prover's z_0 and the initial state the verifier expects, with the memory accumulators
+    // taken from the public circuit
+    fn step_zero_state() -> (Vec<N1>, Vec<(usize, AF)>, crate::solver::PublicIoLayout) {
+        let (grammar_graph, doc) = read_graph(
+            "grammars/test_simple.pest".to_string(),
+            "tests/test_docs/test_simple.txt".to_string(),
+            None,
+            false,
+        );
+        let srs = test_srs();
+        let (ark_ck, _) = gen_ark_pp(doc.len(), &srs);
+        let doc_commit = run_doc_committer(&doc, &ark_ck, None).unwrap();
+
+        let (p_i, mut base, _, _) = setup::<AF>(
+            &grammar_graph,
+            1,
+            doc_commit.blind_roots,
+            TreeSizeClass::Exact,
+            None,
+            &srs,
+        )
+        .unwrap();
+        base.doc_chal = doc_challenge(
+            &doc_commit.doc_commit,
+            base.grammar_digest,
+            &base.mem.as_ref().unwrap().perm_chal,
+        );
+
+        let mut irw = InterRoundWires::new();
+        let circuit_primary = crate::prover::make_coral_circuit(&mut base, &mut irw, 0, None);
+        let z0 = circuit_primary.get_zi()[p_i.ic_key_length..].to_vec();
+
+        let public =
+            CoralStepCircuit::<AF>::public_circuit(&grammar_graph, 1, base.tree_null_val, &srs);
+        let initial_mem = crate::verifier::initial_mem_accs(&public);
+
+        let layout = crate::solver::PublicIoLayout::new(z0.len(), base.has_stack);
+        assert!(!layout.mem_accs.is_empty());
+        let expected = layout.initial_state(
+            base.tree_null_val,
+            base.grammar_digest,
+            base.doc_chal,
+            &initial_mem,
+        );
+        (z0, expected, layout)
+    }
+
     #[test]
     fn full_test_multi_atomic() {
         full_test_function_multi(
@@ -1004,6 +1429,86 @@ mod tests {
         );
     }
 
+    #[test]
+    fn full_test_multi_pp() {
+        full_test_function_multi(
+            "grammars/test_pp.pest".to_string(),
+            "tests/test_docs/test_pp.txt".to_string(),
+        );
+    }
+
+    #[test]
+    fn full_test_multi_stack() {
+        full_test_function_multi(
+            "grammars/test_stack.pest".to_string(),
+            "tests/test_docs/test_stack.txt".to_string(),
+        );
+    }
+
+    #[test]
+    fn full_test_multi_comment() {
+        full_test_function_multi(
+            "grammars/test_comment.pest".to_string(),
+            "tests/test_docs/test_comment.txt".to_string(),
+        );
+    }
+
+    #[test]
+    fn full_test_multi_unicode() {
+        full_test_function_multi(
+            "grammars/test_unicode.pest".to_string(),
+            "tests/test_docs/test_unicode.txt".to_string(),
+        );
+    }
+
+    #[test]
+    fn full_test_multi_np_multi() {
+        full_test_function_multi(
+            "grammars/test_np_multi.pest".to_string(),
+            "tests/test_docs/test_np_multi.txt".to_string(),
+        );
+    }
+
+    #[test]
+    fn full_test_multi_abnf() {
+        full_test_function_multi(
+            "grammars/test_abnf.abnf".to_string(),
+            "tests/test_docs/test_abnf.txt".to_string(),
+        );
+    }
+
+    #[test]
+    fn full_test_multi_antlr() {
+        full_test_function_multi(
+            "grammars/test_antlr.g4".to_string(),
+            "tests/test_docs/test_antlr.txt".to_string(),
+        );
+    }
+
+    #[test]
+    fn full_test_multi_ebnf() {
+        full_test_function_multi(
+            "grammars/test_ebnf.ebnf".to_string(),
+            "tests/test_docs/test_ebnf.txt".to_string(),
+        );
+    }
+
+    #[test]
+    fn full_test_multi_schema() {
+        full_test_function_multi(
+            "grammars/test_schema.json".to_string(),
+            "tests/test_docs/test_schema.txt".to_string(),
+        );
+    }
+
+    #[test]
+    fn full_test_multi_regex() {
+        full_test_function_multi(
+            "grammars/test_regex.regex".to_string(),
+            "tests/test_docs/test_regex.txt".to_string(),
+        );
+    }
+
     #[test]
     fn full_test_multi_any() {
         full_test_function_multi(
@@ -1028,6 +1533,30 @@ mod tests {
         );
     }
 
+    #[test]
+    fn full_test_multi_simple_padded() {
+        full_test_function_padded(
+            "grammars/test_simple.pest".to_string(),
+            "tests/test_docs/test_simple.txt".to_string(),
+            TreeSizeClass::Max(16),
+            Some(8),
+            None,
+            false,
+        );
+    }
+
+    #[test]
+    fn full_test_multi_json_padded() {
+        full_test_function_padded(
+            "grammars/json.pest".to_string(),
+            "./tests/test_docs/json/test_json_128.txt".to_string(),
+            TreeSizeClass::NextPow2,
+            Some(256),
+            None,
+            false,
+        );
+    }
+
     #[test]
     fn full_test_multi_json() {
         full_test_function_multi(
@@ -1044,6 +1573,30 @@ mod tests {
         );
     }
 
+    #[test]
+    fn full_test_multi_c_split() {
+        full_test_function_padded(
+            "grammars/c_simple.pest".to_string(),
+            "./tests/test_docs/c/c1.txt".to_string(),
+            TreeSizeClass::Exact,
+            None,
+            Some(2),
+            false,
+        );
+    }
+
+    #[test]
+    fn full_test_multi_c_collapsed() {
+        full_test_function_padded(
+            "grammars/c_simple.pest".to_string(),
+            "./tests/test_docs/c/c1.txt".to_string(),
+            TreeSizeClass::Exact,
+            None,
+            None,
+            true,
+        );
+    }
+
     #[test]
     fn full_test_multi_toml() {
         full_test_function_multi(
@@ -1,3 +1,4 @@
+use crate::{solver::TreeSizeClass, util::PTAU_PATH};
 use clap::{ArgGroup, Parser};
 use std::path::PathBuf;
 
@@ -6,9 +7,11 @@ use std::path::PathBuf;
 #[clap(group(
             ArgGroup::new("mode")
                 .required(true)
-                .args(&["commit", "prove", "verify", "e2e"]),
+                .args(&["setup", "commit", "prove", "verify", "e2e", "lint"]),
         ))]
 pub struct Options {
+    #[arg(long, default_value_t = false)]
+    pub setup: bool,
     #[arg(long, default_value_t = false)]
     pub commit: bool,
     #[arg(long, default_value_t = false)]
@@ -17,10 +20,40 @@ pub struct Options {
     pub verify: bool,
     #[arg(long, default_value_t = false)]
     pub e2e: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Report grammar constructs the circuit can't prove, then exit"
+    )]
+    pub lint: bool,
     #[arg(long, value_name = "FILE", help = "Optional name for .cmt file")]
     pub cmt_name: Option<String>,
     #[arg(long, value_name = "FILE", help = "Optional name for .proof file")]
     pub proof_name: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Setup bundle written by --setup and loaded by --prove/--verify"
+    )]
+    pub params: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Powers of Tau file",
+        default_value = PTAU_PATH
+    )]
+    pub srs: String,
+    #[arg(
+        long = "srs-sha256",
+        value_name = "HEX",
+        help = "Expected SHA-256 of the SRS (defaults to the contents of <srs>.sha256)"
+    )]
+    pub srs_sha256: Option<String>,
+    #[arg(
+        long = "allow-unverified-srs",
+        help = "Use the SRS even if its SHA-256 is unknown or does not match"
+    )]
+    pub allow_unverified_srs: bool,
     #[arg(short = 'd', long, value_name = "FILE")]
     pub doc: Option<String>,
     #[arg(short = 'g', long, value_name = "FILE")]
@@ -40,4 +73,29 @@ pub struct Options {
         default_value_t = 1, // auto select
     )]
     pub batch_size: usize,
+    #[arg(
+        long = "tree-bucket",
+        value_name = "exact|pow2|USIZE",
+        help = "Pad the parse tree to a public size so only the bucket is revealed",
+        default_value = "exact"
+    )]
+    pub tree_bucket: TreeSizeClass,
+    #[arg(
+        long = "max-arity",
+        value_name = "USIZE",
+        help = "Split rules so no row has more children than this (setup, prove and verify must agree)"
+    )]
+    pub max_arity: Option<usize>,
+    #[arg(
+        long = "collapse-chains",
+        default_value_t = false,
+        help = "Fold single-child derivation chains into one node (setup, prove and verify must agree)"
+    )]
+    pub collapse_chains: bool,
+    #[arg(
+        long = "max-doc-len",
+        value_name = "USIZE",
+        help = "Pad the document commitment to this length so only the maximum is revealed"
+    )]
+    pub max_doc_len: Option<usize>,
 }
@@ -0,0 +1,472 @@
+use crate::{
+    prover::{CoralDocCommitment, ProverOutput},
+    util::*,
+    verifier::VerifierDocCommit,
+};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+
+pub const CONTAINER_MAGIC: [u8; 4] = *b"CORL";
+pub const CONTAINER_VERSION: u32 = 1;
+pub const CURVE: &str = "bn254/grumpkin";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContainerKind {
+    Proof,
+    ProverCommitment,
+    VerifierCommitment,
+}
+
+impl ContainerKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            ContainerKind::Proof => 1,
+            ContainerKind::ProverCommitment => 2,
+            ContainerKind::VerifierCommitment => 3,
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            1 => Some(ContainerKind::Proof),
+            2 => Some(ContainerKind::ProverCommitment),
+            3 => Some(ContainerKind::VerifierCommitment),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ContainerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContainerKind::Proof => write!(f, "proof"),
+            ContainerKind::ProverCommitment => write!(f, "prover commitment"),
+            ContainerKind::VerifierCommitment => write!(f, "verifier commitment"),
+        }
+    }
+}
+
+// Section tags. Readers skip tags they do not know, so new sections can be added without a
+// version bump.
+pub const SECTION_SNARK: u32 = 1;
+pub const SECTION_DOC_OPENING: u32 = 2;
+pub const SECTION_Z0: u32 = 3;
+pub const SECTION_PERM_CHAL: u32 = 4;
+pub const SECTION_DOC_COMMIT: u32 = 5;
+pub const SECTION_PROVER_DOC_COMMIT: u32 = 6;
+
+#[derive(Debug)]
+pub enum ContainerError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion {
+        found: u32,
+    },
+    WrongKind {
+        expected: ContainerKind,
+        found: ContainerKind,
+    },
+    WrongCurve {
+        found: String,
+    },
+    MissingSection(u32),
+    Malformed(String),
+    GrammarMismatch,
+    SrsMismatch,
+}
+
+impl ContainerError {
+    // Verification exit codes for mismatches, 11 for anything that is not a readable container
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ContainerError::GrammarMismatch => 9,
+            ContainerError::SrsMismatch => 10,
+            _ => 11,
+        }
+    }
+}
+
+impl fmt::Display for ContainerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContainerError::Io(e) => write!(f, "{}", e),
+            ContainerError::BadMagic => write!(f, "not a Coral file"),
+            ContainerError::UnsupportedVersion { found } => write!(
+                f,
+                "file format version {} is not supported (this build reads up to {})",
+                found, CONTAINER_VERSION
+            ),
+            ContainerError::WrongKind { expected, found } => {
+                write!(f, "expected a {} file, found a {} file", expected, found)
+            }
+            ContainerError::WrongCurve { found } => {
+                write!(f, "file is for curve {}, expected {}", found, CURVE)
+            }
+            ContainerError::MissingSection(tag) => write!(f, "missing section {}", tag),
+            ContainerError::Malformed(msg) => write!(f, "malformed file: {}", msg),
+            ContainerError::GrammarMismatch => write!(f, "file was made for a different grammar"),
+            ContainerError::SrsMismatch => write!(f, "file was made with a different SRS"),
+        }
+    }
+}
+
+impl std::error::Error for ContainerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ContainerError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ContainerError {
+    fn from(e: io::Error) -> Self {
+        ContainerError::Io(e)
+    }
+}
+
+fn malformed<E: fmt::Display>(e: E) -> ContainerError {
+    ContainerError::Malformed(e.to_string())
+}
+
+// What produced the file. Commitments are made before the circuit shape is chosen, so they
+// leave batch_size and tree_bucket unset.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContainerHeader {
+    pub kind: ContainerKind,
+    pub coral_version: String,
+    pub curve: String,
+    pub grammar_digest: AF,
+    pub batch_size: Option<usize>,
+    pub tree_bucket: Option<usize>,
+    pub srs_sha256: [u8; 32],
+}
+
+impl ContainerHeader {
+    pub fn new(kind: ContainerKind, grammar_digest: AF, srs: &Srs) -> Self {
+        ContainerHeader {
+            kind,
+            coral_version: env!("CARGO_PKG_VERSION").to_string(),
+            curve: CURVE.to_string(),
+            grammar_digest,
+            batch_size: None,
+            tree_bucket: None,
+            srs_sha256: srs.sha256,
+        }
+    }
+
+    pub fn check(&self, grammar_digest: AF, srs: &Srs) -> Result<(), ContainerError> {
+        if self.grammar_digest != grammar_digest {
+            return Err(ContainerError::GrammarMismatch);
+        }
+        if self.srs_sha256 != srs.sha256 {
+            return Err(ContainerError::SrsMismatch);
+        }
+        Ok(())
+    }
+}
+
+pub struct Container {
+    pub header: ContainerHeader,
+    pub sections: Vec<(u32, Vec<u8>)>,
+}
+
+fn write_str<W: Write>(writer: &mut W, s: &str) -> io::Result<()> {
+    writer.write_all(&(s.len() as u32).to_le_bytes())?;
+    writer.write_all(s.as_bytes())
+}
+
+// The length is checked against what is left before anything is allocated for it
+fn read_str(reader: &mut &[u8]) -> Result<String, ContainerError> {
+    let len = read_u32(reader)? as usize;
+    if len > reader.len() {
+        return Err(ContainerError::Malformed("string is truncated".to_string()));
+    }
+    let (data, rest) = reader.split_at(len);
+    *reader = rest;
+    String::from_utf8(data.to_vec()).map_err(malformed)
+}
+
+// 0 is never a valid batch size or bucket, so it stands for unset
+fn write_opt<W: Write>(writer: &mut W, v: Option<usize>) -> io::Result<()> {
+    writer.write_all(&(v.unwrap_or(0) as u64).to_le_bytes())
+}
+
+fn read_opt<R: Read>(reader: &mut R) -> Result<Option<usize>, ContainerError> {
+    let v = read_u64(reader)? as usize;
+    Ok(if v == 0 { None } else { Some(v) })
+}
+
+impl Container {
+    pub fn new(header: ContainerHeader) -> Self {
+        Container {
+            header,
+            sections: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, tag: u32, data: Vec<u8>) {
+        self.sections.push((tag, data));
+    }
+
+    pub fn section(&self, tag: u32) -> Result<&[u8], ContainerError> {
+        self.sections
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, d)| d.as_slice())
+            .ok_or(ContainerError::MissingSection(tag))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&CONTAINER_MAGIC);
+        out.extend_from_slice(&CONTAINER_VERSION.to_le_bytes());
+
+        let h = &self.header;
+        out.push(h.kind.to_byte());
+        write_str(&mut out, &h.coral_version).unwrap();
+        write_str(&mut out, &h.curve).unwrap();
+        h.grammar_digest.serialize_compressed(&mut out).unwrap();
+        write_opt(&mut out, h.batch_size).unwrap();
+        write_opt(&mut out, h.tree_bucket).unwrap();
+        out.extend_from_slice(&h.srs_sha256);
+
+        out.extend_from_slice(&(self.sections.len() as u32).to_le_bytes());
+        for (tag, data) in self.sections.iter() {
+            out.extend_from_slice(&tag.to_le_bytes());
+            out.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            out.extend_from_slice(data);
+        }
+        out
+    }
+
+    // Reads any supported version and checks the file is of the expected kind
+    pub fn from_bytes(mut bytes: &[u8], expected: ContainerKind) -> Result<Self, ContainerError> {
+        let reader = &mut bytes;
+        let mut magic = [0u8; 4];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|_| ContainerError::BadMagic)?;
+        if magic != CONTAINER_MAGIC {
+            return Err(ContainerError::BadMagic);
+        }
+        let container = match read_u32(reader)? {
+            1 => Self::read_v1(reader)?,
+            found => return Err(ContainerError::UnsupportedVersion { found }),
+        };
+        if container.header.kind != expected {
+            return Err(ContainerError::WrongKind {
+                expected,
+                found: container.header.kind,
+            });
+        }
+        Ok(container)
+    }
+
+    fn read_v1(reader: &mut &[u8]) -> Result<Self, ContainerError> {
+        let mut kind = [0u8; 1];
+        reader.read_exact(&mut kind)?;
+        let kind = ContainerKind::from_byte(kind[0])
+            .ok_or_else(|| ContainerError::Malformed(format!("unknown file kind {}", kind[0])))?;
+        let coral_version = read_str(reader)?;
+        let curve = read_str(reader)?;
+        if curve != CURVE {
+            return Err(ContainerError::WrongCurve { found: curve });
+        }
+        let grammar_digest = AF::deserialize_compressed(&mut *reader).map_err(malformed)?;
+        let batch_size = read_opt(reader)?;
+        let tree_bucket = read_opt(reader)?;
+        let mut srs_sha256 = [0u8; 32];
+        reader.read_exact(&mut srs_sha256)?;
+
+        let n_sections = read_u32(reader)?;
+        let mut sections = Vec::new();
+        for _ in 0..n_sections {
+            let tag = read_u32(reader)?;
+            let len = read_u64(reader)? as usize;
+            if len > reader.len() {
+                return Err(ContainerError::Malformed(format!(
+                    "section {} is truncated",
+                    tag
+                )));
+            }
+            let (data, rest) = reader.split_at(len);
+            sections.push((tag, data.to_vec()));
+            *reader = rest;
+        }
+
+        Ok(Container {
+            header: ContainerHeader {
+                kind,
+                coral_version,
+                curve,
+                grammar_digest,
+                batch_size,
+                tree_bucket,
+                srs_sha256,
+            },
+            sections,
+        })
+    }
+
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn read(path: &str, expected: ContainerKind) -> Result<Self, ContainerError> {
+        Self::from_bytes(&fs::read(path)?, expected)
+    }
+}
+
+fn ark_bytes<T: CanonicalSerialize>(t: &T) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    t.serialize_compressed(&mut bytes).unwrap();
+    bytes
+}
+
+pub fn proof_container(output: &ProverOutput, grammar_digest: AF, srs: &Srs) -> Container {
+    let mut header = ContainerHeader::new(ContainerKind::Proof, grammar_digest, srs);
+    header.batch_size = Some(output.batch_size);
+    header.tree_bucket = Some(output.tree_bucket);
+
+    let mut c = Container::new(header);
+    c.push(
+        SECTION_SNARK,
+        bincode::serialize(&output.compressed_snark).unwrap(),
+    );
+    c.push(SECTION_DOC_OPENING, ark_bytes(&output.doc_commit_proof));
+    c.push(SECTION_Z0, bincode::serialize(&output.z_0).unwrap());
+    c.push(SECTION_PERM_CHAL, ark_bytes(&output.perm_chal));
+    c
+}
+
+pub fn proof_from_container(c: &Container) -> Result<ProverOutput, ContainerError> {
+    let shape = |v: Option<usize>, name: &str| {
+        v.ok_or_else(|| ContainerError::Malformed(format!("proof has no {}", name)))
+    };
+    Ok(ProverOutput {
+        compressed_snark: bincode::deserialize(c.section(SECTION_SNARK)?).map_err(malformed)?,
+        doc_commit_proof: CanonicalDeserialize::deserialize_compressed(
+            c.section(SECTION_DOC_OPENING)?,
+        )
+        .map_err(malformed)?,
+        z_0: bincode::deserialize(c.section(SECTION_Z0)?).map_err(malformed)?,
+        batch_size: shape(c.header.batch_size, "batch size")?,
+        tree_bucket: shape(c.header.tree_bucket, "tree bucket")?,
+        perm_chal: CanonicalDeserialize::deserialize_compressed(c.section(SECTION_PERM_CHAL)?)
+            .map_err(malformed)?,
+    })
+}
+
+pub fn prover_commitment_container(
+    doc_commit: &CoralDocCommitment,
+    grammar_digest: AF,
+    srs: &Srs,
+) -> Container {
+    let mut c = Container::new(ContainerHeader::new(
+        ContainerKind::ProverCommitment,
+        grammar_digest,
+        srs,
+    ));
+    c.push(SECTION_PROVER_DOC_COMMIT, ark_bytes(doc_commit));
+    c
+}
+
+pub fn prover_commitment_from_container(
+    c: &Container,
+) -> Result<CoralDocCommitment<'static>, ContainerError> {
+    CoralDocCommitment::deserialize_compressed_unchecked(c.section(SECTION_PROVER_DOC_COMMIT)?)
+        .map_err(malformed)
+}
+
+pub fn verifier_commitment_container(
+    doc_commit: &VerifierDocCommit,
+    grammar_digest: AF,
+    srs: &Srs,
+) -> Container {
+    let mut c = Container::new(ContainerHeader::new(
+        ContainerKind::VerifierCommitment,
+        grammar_digest,
+        srs,
+    ));
+    c.push(SECTION_DOC_COMMIT, ark_bytes(doc_commit));
+    c
+}
+
+pub fn verifier_commitment_from_container(
+    c: &Container,
+) -> Result<VerifierDocCommit, ContainerError> {
+    VerifierDocCommit::deserialize_compressed_unchecked(c.section(SECTION_DOC_COMMIT)?)
+        .map_err(malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> ContainerHeader {
+        ContainerHeader {
+            kind: ContainerKind::Proof,
+            coral_version: "0.1.0".to_string(),
+            curve: CURVE.to_string(),
+            grammar_digest: AF::from(7u64),
+            batch_size: Some(4),
+            tree_bucket: Some(64),
+            srs_sha256: [3u8; 32],
+        }
+    }
+
+    #[test]
+    fn container_roundtrip() {
+        let mut c = Container::new(header());
+        c.push(SECTION_Z0, vec![1, 2, 3]);
+        c.push(99, vec![]);
+
+        let read = Container::from_bytes(&c.to_bytes(), ContainerKind::Proof).unwrap();
+        assert_eq!(read.header, header());
+        assert_eq!(read.section(SECTION_Z0).unwrap(), &[1, 2, 3]);
+        assert!(matches!(
+            read.section(SECTION_SNARK),
+            Err(ContainerError::MissingSection(SECTION_SNARK))
+        ));
+    }
+
+    #[test]
+    fn container_rejects() {
+        let bytes = Container::new(header()).to_bytes();
+
+        assert!(matches!(
+            Container::from_bytes(&bytes, ContainerKind::VerifierCommitment),
+            Err(ContainerError::WrongKind { .. })
+        ));
+
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert!(matches!(
+            Container::from_bytes(&bad, ContainerKind::Proof),
+            Err(ContainerError::BadMagic)
+        ));
+
+        let mut newer = bytes.clone();
+        newer[4..8].copy_from_slice(&(CONTAINER_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            Container::from_bytes(&newer, ContainerKind::Proof),
+            Err(ContainerError::UnsupportedVersion { .. })
+        ));
+
+        assert!(matches!(
+            Container::from_bytes(&bytes[..bytes.len() - 2], ContainerKind::Proof),
+            Err(ContainerError::Io(_))
+        ));
+
+        //Version string claiming 4 GiB
+        let mut long = bytes.clone();
+        long[9..13].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            Container::from_bytes(&long, ContainerKind::Proof),
+            Err(ContainerError::Malformed(_))
+        ));
+    }
+}
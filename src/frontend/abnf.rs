@@ -0,0 +1,588 @@
+use crate::parser::GrammarGraph;
+use pest_meta::ast::{Expr, Rule, RuleType};
+use std::collections::{HashMap, HashSet};
+
+// RFC 5234 Appendix B, added when a grammar uses one of them without defining it
+const CORE_RULES: &str = r#"
+ALPHA  = %x41-5A / %x61-7A
+BIT    = "0" / "1"
+CHAR   = %x01-7F
+CR     = %x0D
+CRLF   = CR LF
+CTL    = %x00-1F / %x7F
+DIGIT  = %x30-39
+DQUOTE = %x22
+HEXDIG = DIGIT / "A" / "B" / "C" / "D" / "E" / "F"
+HTAB   = %x09
+LF     = %x0A
+LWSP   = *(WSP / CRLF WSP)
+OCTET  = %x00-FF
+SP     = %x20
+VCHAR  = %x21-7E
+WSP    = SP / HTAB
+"#;
+
+// Lowers an ABNF grammar (RFC 5234, with the RFC 7405 %s and %i string prefixes) into pest
+// rules. The first rule is the start rule, root matches it against the whole document.
+// Alternatives are tried in order and repetitions take as much as they can, as in pest, and
+// the obvious places where that changes what the grammar accepts are reported
+pub fn to_rules(abnf: &str) -> Result<(Vec<Rule>, Vec<String>), String> {
+    let mut rules = parse_rules(abnf)?;
+    let start = rules
+        .first()
+        .map(|(name, _)| name.clone())
+        .ok_or("The ABNF grammar has no rules")?;
+
+    // Core rules are only added when used, and can use other core rules
+    let core = parse_rules(CORE_RULES)?;
+    loop {
+        let defined: HashSet<String> = rules
+            .iter()
+            .map(|(name, _)| name.to_ascii_lowercase())
+            .collect();
+        let missing: Vec<String> = rules
+            .iter()
+            .flat_map(|(_, expr)| expr.iter_top_down())
+            .filter_map(|expr| match expr {
+                Expr::Ident(name) if !defined.contains(&name.to_ascii_lowercase()) => Some(name),
+                _ => None,
+            })
+            .collect();
+        let Some(name) = missing.first() else {
+            break;
+        };
+        match core.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            Some(rule) => rules.push(rule.clone()),
+            None => return Err(format!("Rule {} is not defined", name)),
+        }
+    }
+
+    // Rule names are case insensitive, references use the spelling of the definition
+    let spelling: HashMap<String, String> = rules
+        .iter()
+        .map(|(name, _)| (name.to_ascii_lowercase(), name.clone()))
+        .collect();
+    for (_, expr) in rules.iter_mut() {
+        *expr = expr.clone().map_bottom_up(|expr| match expr {
+            Expr::Ident(name) => Expr::Ident(spelling[&name.to_ascii_lowercase()].clone()),
+            expr => expr,
+        });
+    }
+
+    for (name, _) in &rules {
+        if name.eq_ignore_ascii_case("root")
+            || matches!(name.as_str(), "EOI" | "WHITESPACE" | "COMMENT")
+            || GrammarGraph::is_terminal(name)
+        {
+            return Err(format!("ABNF rule {} clashes with a pest builtin", name));
+        }
+    }
+
+    let bodies: HashMap<String, Expr> = rules.iter().cloned().collect();
+    let mut report = Vec::new();
+    for (name, expr) in &rules {
+        check_peg(name, expr, &bodies, &mut report);
+    }
+
+    let root = Expr::Seq(
+        Box::new(Expr::Seq(
+            Box::new(Expr::Ident("SOI".to_string())),
+            Box::new(Expr::Ident(start)),
+        )),
+        Box::new(Expr::Ident("EOI".to_string())),
+    );
+    let rules = std::iter::once(("root".to_string(), root))
+        .chain(rules)
+        .map(|(name, expr)| Rule {
+            name,
+            ty: RuleType::Normal,
+            expr,
+        })
+        .collect();
+    Ok((rules, report))
+}
+
+// ABNF backtracks into alternatives and repetitions, pest doesn't. An alternative that starts
+// with a whole earlier one is never reached, and a repetition followed by something it can
+// also start with may leave nothing for it, as in 1*DIGIT DIGIT
+fn check_peg(rule: &str, expr: &Expr, rules: &HashMap<String, Expr>, report: &mut Vec<String>) {
+    match expr {
+        Expr::Choice(..) => {
+            let alternatives = flatten(expr, &|e| match e {
+                Expr::Choice(lhs, rhs) => Some((&**lhs, &**rhs)),
+                _ => None,
+            });
+            for (i, earlier) in alternatives.iter().enumerate() {
+                let prefix = atoms(earlier);
+                for later in &alternatives[i + 1..] {
+                    if atoms(later).starts_with(&prefix) {
+                        report.push(format!(
+                            "Alternative {} in rule {} starts with the earlier alternative {}, which is tried first, so it never matches",
+                            later, rule, earlier
+                        ));
+                    }
+                }
+            }
+            for alternative in alternatives {
+                check_peg(rule, alternative, rules, report);
+            }
+        }
+        Expr::Seq(..) => {
+            let items = flatten(expr, &|e| match e {
+                Expr::Seq(lhs, rhs) => Some((&**lhs, &**rhs)),
+                _ => None,
+            });
+            for pair in items.windows(2) {
+                let repeated = match pair[0] {
+                    Expr::Opt(inner)
+                    | Expr::Rep(inner)
+                    | Expr::RepOnce(inner)
+                    | Expr::RepMin(inner, _)
+                    | Expr::RepMax(inner, _)
+                    | Expr::RepMinMax(inner, ..) => inner,
+                    _ => continue,
+                };
+                let first = |e: &Expr| super::first_chars(e, rules, &mut HashSet::new());
+                if let (Some(lhs), Some(rhs)) = (first(repeated), first(pair[1]))
+                    && super::overlap(&lhs, &rhs)
+                {
+                    report.push(format!(
+                        "Repetition {} in rule {} takes as much as it can and may leave nothing for the {} after it",
+                        pair[0], rule, pair[1]
+                    ));
+                }
+            }
+            for item in items {
+                check_peg(rule, item, rules, report);
+            }
+        }
+        Expr::Opt(inner)
+        | Expr::Rep(inner)
+        | Expr::RepOnce(inner)
+        | Expr::RepExact(inner, _)
+        | Expr::RepMin(inner, _)
+        | Expr::RepMax(inner, _)
+        | Expr::RepMinMax(inner, ..) => check_peg(rule, inner, rules, report),
+        _ => {}
+    }
+}
+
+// Operands of a chain of the same binary operator, left to right
+fn flatten<'a>(
+    expr: &'a Expr,
+    split: &impl Fn(&'a Expr) -> Option<(&'a Expr, &'a Expr)>,
+) -> Vec<&'a Expr> {
+    match split(expr) {
+        Some((lhs, rhs)) => {
+            let mut operands = flatten(lhs, split);
+            operands.extend(flatten(rhs, split));
+            operands
+        }
+        None => vec![expr],
+    }
+}
+
+// A sequence one character of a string at a time, so "a" is a prefix of "ab"
+fn atoms(expr: &Expr) -> Vec<Expr> {
+    match expr {
+        Expr::Seq(lhs, rhs) => {
+            let mut list = atoms(lhs);
+            list.extend(atoms(rhs));
+            list
+        }
+        Expr::Str(s) => s.chars().map(|c| Expr::Str(c.to_string())).collect(),
+        expr => vec![expr.clone()],
+    }
+}
+
+// Rules in definition order, =/ alternatives folded into their rule
+fn parse_rules(abnf: &str) -> Result<Vec<(String, Expr)>, String> {
+    let mut rules: Vec<(String, Expr)> = Vec::new();
+    for text in rule_texts(abnf) {
+        let mut p = AbnfParser {
+            chars: text.chars().collect(),
+            pos: 0,
+        };
+        let name = p.rulename()?;
+        p.skip_ws();
+        let incremental = if p.eat("=/") {
+            true
+        } else if p.eat("=") {
+            false
+        } else {
+            return Err(format!("Expected = after rule name {}", name));
+        };
+        let expr = p
+            .alternation()
+            .map_err(|e| format!("{} in rule {}", e, name))?;
+        p.skip_ws();
+        if p.pos < p.chars.len() {
+            return Err(format!(
+                "Unexpected {:?} in rule {}",
+                p.chars[p.pos..].iter().collect::<String>(),
+                name
+            ));
+        }
+
+        let existing = rules
+            .iter_mut()
+            .find(|(n, _)| n.eq_ignore_ascii_case(&name));
+        match (existing, incremental) {
+            (Some((_, alternatives)), true) => {
+                *alternatives = Expr::Choice(Box::new(alternatives.clone()), Box::new(expr));
+            }
+            (None, false) => rules.push((name, expr)),
+            (Some(_), false) => return Err(format!("Rule {} is defined twice", name)),
+            (None, true) => return Err(format!("Rule {} is extended before it is defined", name)),
+        }
+    }
+    Ok(rules)
+}
+
+// One string per rule: comments dropped and indented continuation lines joined
+fn rule_texts(abnf: &str) -> Vec<String> {
+    let mut texts: Vec<String> = Vec::new();
+    for line in abnf.lines() {
+        let mut text = String::new();
+        let mut quote = None;
+        for c in line.chars() {
+            match (quote, c) {
+                (None, ';') => break,
+                (None, '"') => quote = Some('"'),
+                (None, '<') => quote = Some('>'),
+                (Some(end), c) if c == end => quote = None,
+                _ => {}
+            }
+            text.push(c);
+        }
+        let text = text.trim_end();
+        if text.trim().is_empty() {
+            continue;
+        }
+        match texts.last_mut() {
+            Some(last) if line.starts_with([' ', '\t']) => {
+                last.push(' ');
+                last.push_str(text.trim_start());
+            }
+            _ => texts.push(text.to_string()),
+        }
+    }
+    texts
+}
+
+struct AbnfParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl AbnfParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        let end = self.pos + s.chars().count();
+        if end <= self.chars.len() && self.chars[self.pos..end].iter().copied().eq(s.chars()) {
+            self.pos = end;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_ws(&mut self) {
+        while self.peek().is_some_and(|c| c == ' ' || c == '\t') {
+            self.pos += 1;
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(&f) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn rulename(&mut self) -> Result<String, String> {
+        if !self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+            return Err(format!(
+                "Expected a rule name at {:?}",
+                self.chars[self.pos..].iter().collect::<String>()
+            ));
+        }
+        Ok(self.take_while(|c| c.is_ascii_alphanumeric() || c == '-'))
+    }
+
+    // alternation = concatenation *("/" concatenation)
+    fn alternation(&mut self) -> Result<Expr, String> {
+        let mut expr = self.concatenation()?;
+        loop {
+            self.skip_ws();
+            if !self.eat("/") {
+                return Ok(expr);
+            }
+            expr = Expr::Choice(Box::new(expr), Box::new(self.concatenation()?));
+        }
+    }
+
+    // concatenation = repetition *(1*c-wsp repetition)
+    fn concatenation(&mut self) -> Result<Expr, String> {
+        self.skip_ws();
+        let mut expr = self.repetition()?;
+        loop {
+            self.skip_ws();
+            match self.peek() {
+                None | Some('/' | ')' | ']') => return Ok(expr),
+                _ => expr = Expr::Seq(Box::new(expr), Box::new(self.repetition()?)),
+            }
+        }
+    }
+
+    // repetition = [1*DIGIT / (*DIGIT "*" *DIGIT)] element
+    fn repetition(&mut self) -> Result<Expr, String> {
+        let min = self.take_while(|c| c.is_ascii_digit());
+        let star = self.eat("*");
+        let max = if star {
+            self.take_while(|c| c.is_ascii_digit())
+        } else {
+            String::new()
+        };
+        let element = self.element()?;
+
+        let number = |digits: &str| digits.parse::<u32>().map_err(|e| e.to_string());
+        let element = Box::new(element);
+        Ok(match (star, min.is_empty(), max.is_empty()) {
+            (false, true, _) => *element,
+            (false, false, _) => match number(&min)? {
+                1 => *element,
+                n => Expr::RepExact(element, n),
+            },
+            (true, true, true) => Expr::Rep(element),
+            (true, false, true) => match number(&min)? {
+                0 => Expr::Rep(element),
+                1 => Expr::RepOnce(element),
+                n => Expr::RepMin(element, n),
+            },
+            (true, true, false) => match number(&max)? {
+                1 => Expr::Opt(element),
+                m => Expr::RepMax(element, m),
+            },
+            (true, false, false) => match (number(&min)?, number(&max)?) {
+                (0, 1) => Expr::Opt(element),
+                (n, m) => Expr::RepMinMax(element, n, m),
+            },
+        })
+    }
+
+    // element = rulename / group / option / char-val / num-val / prose-val
+    fn element(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some('(') | Some('[') => {
+                let optional = self.peek() == Some('[');
+                self.pos += 1;
+                let expr = self.alternation()?;
+                self.skip_ws();
+                if !self.eat(if optional { "]" } else { ")" }) {
+                    return Err("Unclosed group".to_string());
+                }
+                Ok(if optional {
+                    Expr::Opt(Box::new(expr))
+                } else {
+                    expr
+                })
+            }
+            Some('"') => self.char_val(false),
+            Some('%') => {
+                if self.eat("%s") || self.eat("%S") {
+                    self.char_val(true)
+                } else if self.eat("%i") || self.eat("%I") {
+                    self.char_val(false)
+                } else {
+                    self.num_val()
+                }
+            }
+            Some('<') => Err(format!(
+                "Prose value {} can't be lowered",
+                self.take_while(|c| c != '>')
+            )),
+            Some(c) if c.is_ascii_alphabetic() => Ok(Expr::Ident(self.rulename()?)),
+            _ => Err(format!(
+                "Unexpected {:?}",
+                self.chars[self.pos..].iter().collect::<String>()
+            )),
+        }
+    }
+
+    // char-val = DQUOTE *(%x20-21 / %x23-7E) DQUOTE, case insensitive unless marked %s
+    fn char_val(&mut self, sensitive: bool) -> Result<Expr, String> {
+        if !self.eat("\"") {
+            return Err("Expected a quoted string".to_string());
+        }
+        let s = self.take_while(|c| c != '"');
+        if !self.eat("\"") {
+            return Err(format!("Unterminated string \"{}", s));
+        }
+        if sensitive || !s.chars().any(|c| c.is_ascii_alphabetic()) {
+            return Ok(Expr::Str(s));
+        }
+        // Each letter becomes its own choice, so every leaf stays a single character
+        Ok(s.chars()
+            .map(|c| {
+                let (lower, upper) = (c.to_ascii_lowercase(), c.to_ascii_uppercase());
+                if lower == upper {
+                    Expr::Str(c.to_string())
+                } else {
+                    Expr::Choice(
+                        Box::new(Expr::Str(lower.to_string())),
+                        Box::new(Expr::Str(upper.to_string())),
+                    )
+                }
+            })
+            .reduce(|lhs, rhs| Expr::Seq(Box::new(lhs), Box::new(rhs)))
+            .unwrap())
+    }
+
+    // num-val = "%" (bin-val / dec-val / hex-val), either a range or a dotted string
+    fn num_val(&mut self) -> Result<Expr, String> {
+        self.pos += 1;
+        let radix = match self.peek().map(|c| c.to_ascii_lowercase()) {
+            Some('b') => 2,
+            Some('d') => 10,
+            Some('x') => 16,
+            _ => return Err("Expected b, d or x after %".to_string()),
+        };
+        self.pos += 1;
+
+        let first = self.num_char(radix)?;
+        if self.eat("-") {
+            let last = self.num_char(radix)?;
+            return Ok(Expr::Range(first.to_string(), last.to_string()));
+        }
+        let mut s = first.to_string();
+        while self.eat(".") {
+            s.push(self.num_char(radix)?);
+        }
+        Ok(Expr::Str(s))
+    }
+
+    fn num_char(&mut self, radix: u32) -> Result<char, String> {
+        let digits = self.take_while(|c| c.is_digit(radix));
+        u32::from_str_radix(&digits, radix)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or(format!("Invalid character value {:?}", digits))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::GrammarGraph;
+
+    // RFC 7230 request line, with the core rules it needs left implicit
+    const REQUEST_LINE: &str = r##"
+request-line   = method SP request-target SP HTTP-version CRLF
+method         = token
+request-target = "/" *( pchar / "/" ) [ "?" query ]
+query          = *( pchar / "/" / "?" )
+pchar          = ALPHA / DIGIT / "-" / "." / "%" HEXDIG HEXDIG
+HTTP-version   = HTTP-name "/" DIGIT "." DIGIT
+HTTP-name      = %s"HTTP"  ; case sensitive
+token          = 1*tchar
+tchar          = "!" / "#" / "$" / "%" / "&" / "'" / "*"
+               / "+" / "-" / "." / "^" / "_" / "`" / "|" / "~"
+tchar          =/ DIGIT / ALPHA
+"##;
+
+    #[test]
+    fn test_request_line() {
+        let (rules, report) = to_rules(REQUEST_LINE).unwrap();
+        assert!(report.is_empty(), "{:?}", report);
+        assert_eq!(rules[0].name, "root");
+        assert_eq!(rules[1].name, "request-line");
+        for core in ["SP", "CRLF", "CR", "LF", "ALPHA", "DIGIT", "HEXDIG"] {
+            assert!(rules.iter().any(|rule| rule.name == core), "{}", core);
+        }
+        assert_eq!(
+            rules
+                .iter()
+                .find(|rule| rule.name == "HTTP-name")
+                .unwrap()
+                .expr,
+            Expr::Str("HTTP".to_string())
+        );
+
+        let mut grammar_graph = GrammarGraph::new();
+        grammar_graph
+            .parse_rules_and_build_graph(rules.clone(), "GET /a%2Fb?x.y HTTP/1.1\r\n")
+            .unwrap_or_else(|e| panic!("{}", e));
+
+        // %s"HTTP" is case sensitive
+        assert!(
+            GrammarGraph::new()
+                .parse_rules_and_build_graph(rules, "GET / http/1.1\r\n")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_repeat_and_values() {
+        let rules = parse_rules("a = 2*3%x41-43 %d13.10 [b] 1b\nb = %b110000 / \"aB\"\n").unwrap();
+        let range = Box::new(Expr::Range("A".to_string(), "C".to_string()));
+        let b = Box::new(Expr::Ident("b".to_string()));
+        assert_eq!(
+            rules[0].1,
+            Expr::Seq(
+                Box::new(Expr::Seq(
+                    Box::new(Expr::Seq(
+                        Box::new(Expr::RepMinMax(range, 2, 3)),
+                        Box::new(Expr::Str("\r\n".to_string()))
+                    )),
+                    Box::new(Expr::Opt(b.clone()))
+                )),
+                b
+            )
+        );
+        let insens = |lower: &str, upper: &str| {
+            Box::new(Expr::Choice(
+                Box::new(Expr::Str(lower.to_string())),
+                Box::new(Expr::Str(upper.to_string())),
+            ))
+        };
+        assert_eq!(
+            rules[1].1,
+            Expr::Choice(
+                Box::new(Expr::Str("0".to_string())),
+                Box::new(Expr::Seq(insens("a", "A"), insens("b", "B")))
+            )
+        );
+
+        // RFC 7405 prefixes are case insensitive
+        let rules = parse_rules("a = %S\"aB\" %I\"c\"\n").unwrap();
+        assert_eq!(
+            rules[0].1,
+            Expr::Seq(Box::new(Expr::Str("aB".to_string())), insens("c", "C"))
+        );
+
+        assert!(to_rules("a = <prose>\n").is_err());
+        assert!(to_rules("a = b\n").is_err());
+        assert!(to_rules("a = \"x\"\nANY = \"y\"\n").is_err());
+    }
+
+    #[test]
+    fn test_peg_differences() {
+        let (_, report) = to_rules("a = 1*DIGIT DIGIT\n").unwrap();
+        assert_eq!(report.len(), 1);
+        assert!(report[0].contains("Repetition"));
+
+        let (_, report) =
+            to_rules("a = %s\"x\" / b / %s\"xy\"\nb = \"1\" / \"1\" \"2\"\n").unwrap();
+        assert_eq!(report.len(), 2);
+        assert!(report.iter().all(|line| line.contains("never matches")));
+
+        // Alternatives that only share a first character backtrack in pest as well
+        let (_, report) = to_rules("a = *ALPHA \"1\" / \"ab\" / \"ac\"\n").unwrap();
+        assert!(report.is_empty(), "{:?}", report);
+    }
+}
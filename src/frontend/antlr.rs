@@ -0,0 +1,860 @@
+use crate::parser::GrammarGraph;
+use pest_meta::ast::{Expr, Rule, RuleType};
+use std::collections::{HashMap, HashSet};
+
+// Negated sets are checked by listing their characters, larger ones are rejected
+const MAX_NEGATED_SET: u32 = 256;
+
+// Lowers the context-free subset of an ANTLR4 .g4 grammar into pest rules, and reports what
+// was dropped on the way. Parser rules become normal rules and lexer rules atomic ones, with
+// lexer rules sent to skip or the hidden channel folded into WHITESPACE and COMMENT. The first
+// parser rule is the start rule, a lexer grammar matches a stream of its tokens. Tokens are
+// matched in place rather than by longest match, keywords that can run on into a word are reported
+pub fn to_rules(g4: &str) -> Result<(Vec<Rule>, Vec<String>), String> {
+    let tokens = lex(g4)?;
+    let mut p = G4Parser {
+        tokens,
+        pos: 0,
+        report: Vec::new(),
+    };
+    let grammar = p.grammar()?;
+    let mut report = p.report;
+
+    let mut rules: Vec<(String, RuleType, Expr)> = Vec::new();
+    let mut whitespace = Vec::new();
+    let mut comment = Vec::new();
+    for rule in &grammar {
+        let trivia = trivia(&rule.commands);
+        for command in rule.commands.iter().filter(|_| !trivia) {
+            report.push(format!(
+                "Lexer command {} in rule {} is ignored",
+                command, rule.name
+            ));
+        }
+        if trivia {
+            // pest already repeats trivia, so WS : [ \t]+ keeps just its character set
+            let expr = match &rule.expr {
+                Expr::Rep(inner) | Expr::RepOnce(inner) => *inner.clone(),
+                expr => expr.clone(),
+            };
+            if rule.name.to_ascii_uppercase().contains("COMMENT") {
+                comment.push(expr);
+            } else {
+                whitespace.push(expr);
+            }
+            continue;
+        }
+        let ty = if rule.lexer && !rule.fragment {
+            RuleType::Atomic
+        } else {
+            RuleType::Normal
+        };
+        rules.push((rule.name.clone(), ty, rule.expr.clone()));
+    }
+
+    let mut start = match grammar.iter().find(|rule| !rule.lexer) {
+        Some(rule) => Expr::Ident(rule.name.clone()),
+        None => Expr::Rep(Box::new(choice(
+            grammar
+                .iter()
+                .filter(|rule| !rule.fragment && rules.iter().any(|(n, ..)| *n == rule.name))
+                .map(|rule| Expr::Ident(rule.name.clone()))
+                .collect(),
+        )?)),
+    };
+
+    // Direct left recursion becomes a loop, the language stays the same
+    for (name, _, expr) in rules.iter_mut() {
+        if let Some(rewritten) = remove_left_recursion(name, expr)? {
+            report.push(format!(
+                "Left recursion in rule {} is rewritten into a loop, its tree is flat",
+                name
+            ));
+            *expr = rewritten;
+        }
+    }
+
+    // Skipped tokens can't be referenced
+    let skipped: HashSet<&str> = grammar
+        .iter()
+        .filter(|rule| !rules.iter().any(|(n, ..)| *n == rule.name))
+        .map(|rule| rule.name.as_str())
+        .collect();
+    let defined: HashSet<String> = rules.iter().map(|(n, ..)| n.clone()).collect();
+    let mut undefined = None;
+    let mut rename = |expr: Expr| match expr {
+        Expr::Ident(name) if name == "EOF" => Expr::Ident("EOI".to_string()),
+        Expr::Ident(name) if name != "ANY" && !defined.contains(&name) => {
+            undefined.get_or_insert(name.clone());
+            Expr::Ident(name)
+        }
+        expr => expr,
+    };
+    start = start.map_bottom_up(&mut rename);
+    let mut lowered = Vec::new();
+    for (name, ty, expr) in rules {
+        lowered.push(Rule {
+            name,
+            ty,
+            expr: expr.map_bottom_up(&mut rename),
+        });
+    }
+    for (name, exprs) in [("WHITESPACE", whitespace), ("COMMENT", comment)] {
+        if !exprs.is_empty() {
+            lowered.push(Rule {
+                name: name.to_string(),
+                ty: RuleType::Normal,
+                expr: choice(exprs)?.map_bottom_up(&mut rename),
+            });
+        }
+    }
+    if let Some(name) = undefined {
+        return Err(if skipped.contains(name.as_str()) {
+            format!("Token {} is skipped and can't be referenced", name)
+        } else {
+            format!("Rule {} is not defined", name)
+        });
+    }
+
+    // ANTLR lexes the longest token, pest matches a keyword even when a word goes on after it
+    let bodies: HashMap<String, Expr> = lowered
+        .iter()
+        .map(|rule| (rule.name.clone(), rule.expr.clone()))
+        .collect();
+    let mut run_on = |rule: &str, keyword: &Expr, next: &Expr| {
+        let Some(word) = keyword_text(keyword, &bodies) else {
+            return;
+        };
+        let continues = super::first_chars(next, &bodies, &mut HashSet::new())
+            .is_some_and(|first| super::overlap(&first, &WORD_CHARS));
+        let line = format!(
+            "{} in rule {} can be followed by {} without a break: ANTLR would lex a longer token, as for {}x, pest matches {:?} and goes on",
+            keyword, rule, next, word, word
+        );
+        if continues && !report.contains(&line) {
+            report.push(line);
+        }
+    };
+    for rule in lowered.iter().filter(|rule| rule.ty == RuleType::Normal) {
+        for expr in rule.expr.iter_top_down() {
+            if let Expr::Seq(..) = expr {
+                for pair in sequence(&expr).windows(2) {
+                    run_on(&rule.name, &pair[0], &pair[1]);
+                }
+            }
+        }
+    }
+    if let Expr::Rep(stream) = &start {
+        let tokens = alternatives(stream);
+        // Only a token that isn't a keyword itself can lex a longer word
+        for keyword in &tokens {
+            for next in tokens.iter().filter(|t| keyword_text(t, &bodies).is_none()) {
+                run_on("root", keyword, next);
+            }
+        }
+    }
+
+    let root = Expr::Seq(
+        Box::new(Expr::Seq(
+            Box::new(Expr::Ident("SOI".to_string())),
+            Box::new(start),
+        )),
+        Box::new(Expr::Ident("EOI".to_string())),
+    );
+    lowered.insert(
+        0,
+        Rule {
+            name: "root".to_string(),
+            ty: RuleType::Normal,
+            expr: root,
+        },
+    );
+    Ok((lowered, report))
+}
+
+const WORD_CHARS: [(char, char); 4] = [('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+
+// Text of a literal, or of a token that is just one, when it ends in a word character
+fn keyword_text(expr: &Expr, rules: &HashMap<String, Expr>) -> Option<String> {
+    let text = match expr {
+        Expr::Str(s) => s,
+        Expr::Ident(name) => match rules.get(name) {
+            Some(Expr::Str(s)) => s,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let last = text.chars().last()?;
+    (last.is_ascii_alphanumeric() || last == '_').then(|| text.clone())
+}
+
+fn choice(exprs: Vec<Expr>) -> Result<Expr, String> {
+    exprs
+        .into_iter()
+        .reduce(|lhs, rhs| Expr::Choice(Box::new(lhs), Box::new(rhs)))
+        .ok_or("Empty alternative list".to_string())
+}
+
+fn seq(exprs: Vec<Expr>) -> Expr {
+    exprs
+        .into_iter()
+        .reduce(|lhs, rhs| Expr::Seq(Box::new(lhs), Box::new(rhs)))
+        .unwrap_or(Expr::Str(String::new()))
+}
+
+fn alternatives(expr: &Expr) -> Vec<Expr> {
+    match expr {
+        Expr::Choice(lhs, rhs) => {
+            let mut alts = alternatives(lhs);
+            alts.extend(alternatives(rhs));
+            alts
+        }
+        expr => vec![expr.clone()],
+    }
+}
+
+fn sequence(expr: &Expr) -> Vec<Expr> {
+    match expr {
+        Expr::Seq(lhs, rhs) => {
+            let mut parts = sequence(lhs);
+            parts.extend(sequence(rhs));
+            parts
+        }
+        expr => vec![expr.clone()],
+    }
+}
+
+// A : A x | A y | b | c  becomes  A : (b | c) (x | y)*
+fn remove_left_recursion(name: &str, expr: &Expr) -> Result<Option<Expr>, String> {
+    let (mut tails, mut bases) = (Vec::new(), Vec::new());
+    for alt in alternatives(expr) {
+        let mut parts = sequence(&alt);
+        if parts[0] == Expr::Ident(name.to_string()) {
+            parts.remove(0);
+            if parts.is_empty() {
+                return Err(format!("Rule {} only refers to itself", name));
+            }
+            tails.push(seq(parts));
+        } else {
+            bases.push(alt);
+        }
+    }
+    if tails.is_empty() {
+        return Ok(None);
+    }
+    if bases.is_empty() {
+        return Err(format!("Left recursive rule {} has no base case", name));
+    }
+    Ok(Some(Expr::Seq(
+        Box::new(choice(bases)?),
+        Box::new(Expr::Rep(Box::new(choice(tails)?))),
+    )))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Literal(String),
+    // Contents of [...], a character set in lexer rules and arguments after a rule name
+    Bracket(String),
+    // {...}, an action or, followed by ?, a semantic predicate
+    Action,
+    Punct(&'static str),
+}
+
+const PUNCTS: [&str; 21] = [
+    "->", "..", "+=", "::", "??", "*?", "+?", ":", ";", "|", "(", ")", "?", "*", "+", "~", ".",
+    "#", "=", ",", "@",
+];
+
+fn lex(g4: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = g4.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    let at = |i: usize, s: &str| chars[i..].iter().copied().take(s.len()).eq(s.chars());
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if at(i, "//") {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if at(i, "/*") {
+            i += 2;
+            while !at(i, "*/") {
+                if i >= chars.len() {
+                    return Err("Unterminated comment".to_string());
+                }
+                i += 1;
+            }
+            i += 2;
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c == '\'' || c == '[' {
+            // Escapes are kept, the parser decodes them
+            let end = if c == '\'' { '\'' } else { ']' };
+            let start = i + 1;
+            i += 1;
+            while i < chars.len() && chars[i] != end {
+                i += if chars[i] == '\\' { 2 } else { 1 };
+            }
+            if i >= chars.len() {
+                return Err(format!("Unterminated {}", c));
+            }
+            let body: String = chars[start..i].iter().collect();
+            tokens.push(if c == '\'' {
+                Token::Literal(body)
+            } else {
+                Token::Bracket(body)
+            });
+            i += 1;
+        } else if c == '{' || c == '<' {
+            // Element options like <assoc=right> don't change the language, actions are
+            // reported by the parser
+            let (open, close) = if c == '{' { ('{', '}') } else { ('<', '>') };
+            let mut depth = 0;
+            loop {
+                match chars.get(i) {
+                    None => return Err(format!("Unterminated {}", open)),
+                    Some(&ch) if ch == open => depth += 1,
+                    Some(&ch) if ch == close => depth -= 1,
+                    _ => {}
+                }
+                i += 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            if c == '{' {
+                tokens.push(Token::Action);
+            }
+        } else if let Some(punct) = PUNCTS.iter().find(|p| at(i, p)) {
+            tokens.push(Token::Punct(punct));
+            i += punct.len();
+        } else {
+            return Err(format!("Unexpected {:?}", c));
+        }
+    }
+    Ok(tokens)
+}
+
+struct G4Rule {
+    name: String,
+    lexer: bool,
+    fragment: bool,
+    expr: Expr,
+    commands: Vec<String>,
+}
+
+struct G4Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    report: Vec<String>,
+}
+
+impl G4Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        if self.peek() == Some(&Token::Punct(Self::intern(punct))) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn intern(punct: &str) -> &'static str {
+        PUNCTS.iter().find(|p| **p == punct).unwrap()
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), String> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            Err(format!("Expected {} before {:?}", punct, self.peek()))
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Ident(name)) => Ok(name),
+            token => Err(format!("Expected a name, found {:?}", token)),
+        }
+    }
+
+    // Skips up to and including the next ;
+    fn statement(&mut self) {
+        while let Some(token) = self.next() {
+            if token == Token::Punct(";") {
+                return;
+            }
+        }
+    }
+
+    fn grammar(&mut self) -> Result<Vec<G4Rule>, String> {
+        let mut rules = Vec::new();
+        while let Some(token) = self.peek().cloned() {
+            match token {
+                Token::Ident(word) if word == "lexer" || word == "parser" || word == "grammar" => {
+                    self.statement();
+                }
+                Token::Ident(word) if word == "import" => {
+                    return Err("Imports are not supported, merge the grammars first".to_string());
+                }
+                Token::Ident(word) if word == "mode" => {
+                    self.report
+                        .push("Lexer modes are ignored, all lexer rules are imported".to_string());
+                    self.statement();
+                }
+                Token::Ident(word)
+                    if matches!(word.as_str(), "options" | "tokens" | "channels")
+                        && matches!(self.tokens.get(self.pos + 1), Some(Token::Action)) =>
+                {
+                    self.report.push(format!("The {} block is ignored", word));
+                    self.pos += 2;
+                }
+                // Named actions, @header {...} or @lexer::members {...}
+                Token::Punct("@") => {
+                    self.pos += 1;
+                    let mut name = self.ident()?;
+                    if self.eat("::") {
+                        name = self.ident()?;
+                    }
+                    self.report.push(format!("The @{} action is ignored", name));
+                    self.pos += 1;
+                }
+                Token::Ident(_) => rules.push(self.rule()?),
+                token => return Err(format!("Unexpected {:?} at the top level", token)),
+            }
+        }
+        Ok(rules)
+    }
+
+    fn rule(&mut self) -> Result<G4Rule, String> {
+        let mut name = self.ident()?;
+        let fragment = name == "fragment";
+        if fragment {
+            name = self.ident()?;
+        }
+        let lexer = name.starts_with(|c: char| c.is_uppercase());
+
+        // Arguments, return values, locals, options and @init/@after actions
+        let mut actions = false;
+        while !self.eat(":") {
+            match self.next() {
+                Some(Token::Bracket(_) | Token::Ident(_) | Token::Punct("@")) => {}
+                Some(Token::Action) => actions = true,
+                None => return Err(format!("Rule {} has no body", name)),
+                token => return Err(format!("Unexpected {:?} in the header of {}", token, name)),
+            }
+        }
+        if actions {
+            self.report
+                .push(format!("Actions in the header of {} are ignored", name));
+        }
+
+        let mut commands = Vec::new();
+        let expr = self.alternatives(&name, &mut commands)?;
+        self.expect(";")?;
+        // Skipped tokens are folded into WHITESPACE and COMMENT and keep their name
+        if !trivia(&commands) && pest_name(&name) != name {
+            self.report
+                .push(format!("Rule {} is renamed to {}", name, pest_name(&name)));
+            name = pest_name(&name);
+        }
+        // Exception handlers
+        while matches!(self.peek(), Some(Token::Ident(w)) if w == "catch" || w == "finally") {
+            self.report
+                .push(format!("Exception handlers of {} are ignored", name));
+            self.pos += 1;
+            while matches!(self.peek(), Some(Token::Bracket(_)) | Some(Token::Action)) {
+                self.pos += 1;
+            }
+        }
+        Ok(G4Rule {
+            name,
+            lexer,
+            fragment,
+            expr,
+            commands,
+        })
+    }
+
+    fn alternatives(&mut self, rule: &str, commands: &mut Vec<String>) -> Result<Expr, String> {
+        let mut alts = vec![self.alternative(rule, commands)?];
+        while self.eat("|") {
+            alts.push(self.alternative(rule, commands)?);
+        }
+        choice(alts)
+    }
+
+    fn alternative(&mut self, rule: &str, commands: &mut Vec<String>) -> Result<Expr, String> {
+        // Elements with their suffix, non-greedy loops are lowered once the next element is known
+        let mut elements: Vec<(Expr, Option<&'static str>)> = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some(Token::Punct(";" | "|" | ")")) => break,
+                Some(Token::Punct("#")) => {
+                    self.pos += 1;
+                    self.ident()?;
+                }
+                Some(Token::Punct("->")) => {
+                    self.pos += 1;
+                    let mut command = String::new();
+                    while !matches!(self.peek(), None | Some(Token::Punct(";" | "|"))) {
+                        match self.next() {
+                            Some(Token::Ident(word)) => command.push_str(&word),
+                            Some(Token::Punct(p)) => command.push_str(p),
+                            Some(token) => command.push_str(&format!("{:?}", token)),
+                            None => {}
+                        }
+                    }
+                    commands.extend(command.split(',').map(str::to_string));
+                }
+                Some(Token::Action) => {
+                    self.pos += 1;
+                    if self.eat("?") {
+                        self.report
+                            .push(format!("Semantic predicate in rule {} is ignored", rule));
+                    } else {
+                        self.report
+                            .push(format!("Action in rule {} is ignored", rule));
+                    }
+                }
+                _ => {
+                    // Labels, x=e and x+=e
+                    if matches!(self.peek(), Some(Token::Ident(_)))
+                        && matches!(
+                            self.tokens.get(self.pos + 1),
+                            Some(Token::Punct("=" | "+="))
+                        )
+                    {
+                        self.pos += 2;
+                    }
+                    let atom = self.atom(rule, commands)?;
+                    let suffix = match self.peek() {
+                        Some(Token::Punct(p @ ("?" | "*" | "+" | "??" | "*?" | "+?"))) => {
+                            let p = *p;
+                            self.pos += 1;
+                            Some(p)
+                        }
+                        _ => None,
+                    };
+                    elements.push((atom, suffix));
+                }
+            }
+        }
+
+        let mut parts = Vec::new();
+        for i in 0..elements.len() {
+            let (atom, suffix) = elements[i].clone();
+            let atom = Box::new(atom);
+            parts.push(match suffix {
+                None => *atom,
+                Some("?") => Expr::Opt(atom),
+                Some("*") => Expr::Rep(atom),
+                Some("+") => Expr::RepOnce(atom),
+                Some(lazy) => {
+                    // e*? x  matches e until x can match:  (!x ~ e)* x
+                    let Some((stop, _)) = elements.get(i + 1) else {
+                        return Err(format!(
+                            "Non-greedy {} at the end of an alternative in rule {} has nothing to stop at",
+                            lazy, rule
+                        ));
+                    };
+                    let guarded = Box::new(Expr::Seq(
+                        Box::new(Expr::NegPred(Box::new(stop.clone()))),
+                        atom.clone(),
+                    ));
+                    match lazy {
+                        "??" => Expr::Opt(guarded),
+                        "*?" => Expr::Rep(guarded),
+                        _ => Expr::Seq(atom, Box::new(Expr::Rep(guarded))),
+                    }
+                }
+            });
+        }
+        Ok(seq(parts))
+    }
+
+    fn atom(&mut self, rule: &str, commands: &mut Vec<String>) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Ident(name)) => Ok(Expr::Ident(pest_name(&name))),
+            Some(Token::Literal(s)) => {
+                let s = unescape(&s)?;
+                if self.eat("..") {
+                    let end = match self.next() {
+                        Some(Token::Literal(end)) => unescape(&end)?,
+                        token => return Err(format!("Expected a range end, found {:?}", token)),
+                    };
+                    let (mut lo, mut hi) = (s.chars(), end.chars());
+                    match (lo.next(), lo.next(), hi.next(), hi.next()) {
+                        (Some(lo), None, Some(hi), None) if lo <= hi => Ok(Expr::Range(s, end)),
+                        _ => Err(format!("Invalid range {:?}..{:?} in rule {}", s, end, rule)),
+                    }
+                } else {
+                    Ok(Expr::Str(s))
+                }
+            }
+            Some(Token::Bracket(set)) => choice(
+                char_set(&set)?
+                    .into_iter()
+                    .map(|(lo, hi)| {
+                        if lo == hi {
+                            Expr::Str(lo.to_string())
+                        } else {
+                            Expr::Range(lo.to_string(), hi.to_string())
+                        }
+                    })
+                    .collect(),
+            ),
+            Some(Token::Punct(".")) => Ok(Expr::Ident("ANY".to_string())),
+            Some(Token::Punct("(")) => {
+                let expr = self.alternatives(rule, commands)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(Token::Punct("~")) => {
+                let negated = self.atom(rule, commands)?;
+                let mut chars = Vec::new();
+                for alt in alternatives(&negated) {
+                    match alt {
+                        Expr::Str(s) if s.chars().count() == 1 => chars.push(Expr::Str(s)),
+                        Expr::Range(lo, hi) => {
+                            let (Some(lo), Some(hi)) = (lo.chars().next(), hi.chars().next())
+                            else {
+                                return Err(format!("Empty range in rule {}", rule));
+                            };
+                            if (hi as u32).saturating_sub(lo as u32) >= MAX_NEGATED_SET {
+                                return Err(format!(
+                                    "Negated range {:?}..{:?} in rule {} is too large",
+                                    lo, hi, rule
+                                ));
+                            }
+                            chars.extend((lo..=hi).map(|c| Expr::Str(c.to_string())));
+                        }
+                        alt => {
+                            return Err(format!(
+                                "~ in rule {} only applies to characters, not {}",
+                                rule, alt
+                            ));
+                        }
+                    }
+                }
+                Ok(Expr::Seq(
+                    Box::new(Expr::NegPred(Box::new(choice(chars)?))),
+                    Box::new(Expr::Ident("ANY".to_string())),
+                ))
+            }
+            token => Err(format!("Unexpected {:?} in rule {}", token, rule)),
+        }
+    }
+}
+
+fn trivia(commands: &[String]) -> bool {
+    commands
+        .iter()
+        .any(|c| c == "skip" || c.replace(' ', "") == "channel(HIDDEN)")
+}
+
+// Names pest reserves get a trailing _
+fn pest_name(name: &str) -> String {
+    if matches!(name, "root" | "EOI" | "WHITESPACE" | "COMMENT") || GrammarGraph::is_terminal(name)
+    {
+        format!("{}_", name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn unescape(s: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some('b') => out.push('\u{8}'),
+            Some('f') => out.push('\u{c}'),
+            Some('u') => {
+                let hex: String = if chars.peek() == Some(&'{') {
+                    chars.next();
+                    chars.by_ref().take_while(|c| *c != '}').collect()
+                } else {
+                    chars.by_ref().take(4).collect()
+                };
+                let c = u32::from_str_radix(&hex, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or(format!("Invalid escape \\u{}", hex))?;
+                out.push(c);
+            }
+            Some('p') | Some('P') => {
+                return Err(format!(
+                    "Unicode property escapes in {:?} are not supported",
+                    s
+                ));
+            }
+            Some(c) => out.push(c),
+            None => return Err(format!("Dangling \\ in {:?}", s)),
+        }
+    }
+    Ok(out)
+}
+
+// [a-z_\-] as inclusive intervals
+fn char_set(set: &str) -> Result<Vec<(char, char)>, String> {
+    let mut chars = Vec::new();
+    let mut it = set.chars();
+    while let Some(c) = it.next() {
+        // Decode one possibly escaped character, \u{...} spans several
+        if c == '\\' {
+            let mut escape = String::from("\\");
+            match it.next() {
+                Some('u') => {
+                    escape.push('u');
+                    let rest = it.as_str();
+                    let len = if rest.starts_with('{') {
+                        rest.find('}').map_or(rest.len(), |i| i + 1)
+                    } else {
+                        rest.char_indices().nth(4).map_or(rest.len(), |(i, _)| i)
+                    };
+                    escape.push_str(&rest[..len]);
+                    it = rest[len..].chars();
+                }
+                Some(e) => escape.push(e),
+                None => return Err(format!("Dangling \\ in [{}]", set)),
+            }
+            chars.push((unescape(&escape)?.chars().next().unwrap(), true));
+        } else {
+            chars.push((c, false));
+        }
+    }
+
+    let mut intervals = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (lo, _) = chars[i];
+        if i + 2 < chars.len() && chars[i + 1] == ('-', false) {
+            let hi = chars[i + 2].0;
+            if hi < lo {
+                return Err(format!("Reversed range {:?}-{:?} in [{}]", lo, hi, set));
+            }
+            intervals.push((lo, hi));
+            i += 3;
+        } else {
+            intervals.push((lo, lo));
+            i += 1;
+        }
+    }
+    if intervals.is_empty() {
+        return Err("Empty character set []".to_string());
+    }
+    Ok(intervals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn rule<'a>(rules: &'a [Rule], name: &str) -> &'a Rule {
+        rules.iter().find(|rule| rule.name == name).unwrap()
+    }
+
+    #[test]
+    fn test_calc() {
+        let g4 = fs::read_to_string("grammars/test_antlr.g4").unwrap();
+        let (rules, report) = to_rules(&g4).unwrap();
+        assert_eq!(rules[0].name, "root");
+        assert_eq!(rule(&rules, "ID").ty, RuleType::Atomic);
+        assert_eq!(rule(&rules, "DIGIT").ty, RuleType::Normal);
+        assert_eq!(rule(&rules, "expr").ty, RuleType::Normal);
+        assert!(rules.iter().all(|rule| rule.name != "WS"));
+        assert!(rule(&rules, "WHITESPACE").expr.to_string().contains("\\t"));
+        assert!(rule(&rules, "COMMENT").expr.to_string().contains("!\"*/\""));
+        assert_eq!(
+            report,
+            vec!["Left recursion in rule expr is rewritten into a loop, its tree is flat"]
+        );
+
+        let doc = fs::read_to_string("tests/test_docs/test_antlr.txt").unwrap();
+        GrammarGraph::new()
+            .parse_rules_and_build_graph(rules.clone(), &doc)
+            .unwrap_or_else(|e| panic!("{}", e));
+        for bad in ["x = 4 *;", "print \"a\nb\";", "x = 1; /* open"] {
+            assert!(
+                GrammarGraph::new()
+                    .parse_rules_and_build_graph(rules.clone(), bad)
+                    .is_err(),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_dropped_and_rejected() {
+        let (rules, report) = to_rules(
+            "lexer grammar L;\noptions { caseInsensitive = false; }\n\
+             A : 'a' {count++;} ;\nB : ~[a-c] -> type(A) ;\nANY : '.' ;\n",
+        )
+        .unwrap();
+        assert_eq!(rules[0].name, "root");
+        assert!(rules.iter().any(|rule| rule.name == "ANY_"));
+        assert_eq!(report.len(), 4);
+        assert_eq!(
+            rule(&rules, "B").expr,
+            Expr::Seq(
+                Box::new(Expr::NegPred(Box::new(
+                    choice(["a", "b", "c"].map(|c| Expr::Str(c.to_string())).to_vec()).unwrap()
+                ))),
+                Box::new(Expr::Ident("ANY".to_string()))
+            )
+        );
+
+        assert!(to_rules("a : 'x' .*? ;").is_err());
+        assert!(to_rules("a : 'x' WS ; WS : ' ' -> skip ;").is_err());
+        assert!(to_rules("a : a 'x' ;").is_err());
+        assert!(to_rules("A : ~[\\u0000-\\uFFFF] ;").is_err());
+        assert!(to_rules("grammar G; import H; a : 'x' ;").is_err());
+
+        // Malformed ranges and escapes are errors, not panics
+        assert!(to_rules("A : ~('' .. 'b') ;").is_err());
+        assert!(to_rules("A : ~('z' .. 'a') ;").is_err());
+        assert!(to_rules("A : [z-a] ;").is_err());
+        assert!(to_rules("A : [\\u000é] ;").is_err());
+    }
+
+    #[test]
+    fn test_longest_match() {
+        let (_, report) =
+            to_rules("a : 'print' ID | PRINT '(' ID ')' ;\nPRINT : 'print' ;\nID : [a-z]+ ;\n")
+                .unwrap();
+        assert_eq!(report.len(), 1);
+        assert!(report[0].contains("printx"));
+
+        let (_, report) = to_rules("lexer grammar L;\nIF : 'if' ;\nID : [a-z]+ ;\n").unwrap();
+        assert_eq!(report.len(), 1);
+        assert!(report[0].contains("IF"));
+    }
+}
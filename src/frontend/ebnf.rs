@@ -0,0 +1,598 @@
+use crate::parser::GrammarGraph;
+use pest_meta::ast::{Expr, Rule, RuleType};
+use std::collections::{HashMap, HashSet};
+
+// Character classes are listed in full when negated or excluded, larger ones are rejected
+const MAX_EXPANDED: usize = 256;
+
+// Lowers a W3C EBNF grammar (the notation of the XML specification) into pest rules, and
+// reports what was approximated on the way. Rules may carry [n] numbers and [ WFC: ... ]
+// constraint notes, the first rule is the start rule. There is no implicit whitespace, as in
+// the specifications S is always written out
+pub fn to_rules(ebnf: &str) -> Result<(Vec<Rule>, Vec<String>), String> {
+    let mut p = EbnfParser {
+        tokens: lex(ebnf)?,
+        pos: 0,
+        report: Vec::new(),
+    };
+    let parsed = p.grammar()?;
+    let mut report = p.report;
+    let start = parsed
+        .first()
+        .map(|(name, _)| name.clone())
+        .ok_or("The EBNF grammar has no rules")?;
+
+    let mut defined = HashMap::new();
+    for (name, expr) in &parsed {
+        if name == "root"
+            || matches!(name.as_str(), "EOI" | "WHITESPACE" | "COMMENT")
+            || GrammarGraph::is_terminal(name)
+        {
+            return Err(format!("EBNF rule {} clashes with a pest builtin", name));
+        }
+        if defined.insert(name.clone(), expr.clone()).is_some() {
+            return Err(format!("Rule {} is defined twice", name));
+        }
+    }
+    for (_, expr) in &parsed {
+        if let Some(name) = expr.iter_top_down().find_map(|expr| match expr {
+            Expr::Ident(name) if name != "ANY" && !defined.contains_key(&name) => Some(name),
+            _ => None,
+        }) {
+            return Err(format!("Rule {} is not defined", name));
+        }
+    }
+
+    let mut rules = Vec::new();
+    for (name, expr) in parsed {
+        let expr = lower(&name, expr, &defined, &mut report)?;
+        rules.push((name, expr));
+    }
+
+    let root = Expr::Seq(
+        Box::new(Expr::Seq(
+            Box::new(Expr::Ident("SOI".to_string())),
+            Box::new(Expr::Ident(start)),
+        )),
+        Box::new(Expr::Ident("EOI".to_string())),
+    );
+    let rules = std::iter::once(("root".to_string(), root))
+        .chain(rules)
+        .map(|(name, expr)| Rule {
+            name,
+            ty: RuleType::Normal,
+            expr,
+        })
+        .collect();
+    Ok((rules, report))
+}
+
+// The parser keeps A - B as a Seq of A and a NegPred of B, this turns it into something the
+// negative predicates can check
+fn lower(
+    rule: &str,
+    expr: Expr,
+    rules: &HashMap<String, Expr>,
+    report: &mut Vec<String>,
+) -> Result<Expr, String> {
+    let lower_box = |expr: Box<Expr>, report: &mut Vec<String>| -> Result<Box<Expr>, String> {
+        Ok(Box::new(lower(rule, *expr, rules, report)?))
+    };
+    Ok(match expr {
+        Expr::Seq(lhs, rhs) => match *rhs {
+            Expr::NegPred(excluded) => exclusion(rule, *lhs, *excluded, rules, report)?,
+            rhs => Expr::Seq(lower_box(lhs, report)?, lower_box(Box::new(rhs), report)?),
+        },
+        Expr::Choice(lhs, rhs) => Expr::Choice(lower_box(lhs, report)?, lower_box(rhs, report)?),
+        Expr::Opt(inner) => Expr::Opt(lower_box(inner, report)?),
+        Expr::Rep(inner) => Expr::Rep(lower_box(inner, report)?),
+        Expr::RepOnce(inner) => Expr::RepOnce(lower_box(inner, report)?),
+        expr => expr,
+    })
+}
+
+// A - B, for the three shapes the specifications use
+fn exclusion(
+    rule: &str,
+    lhs: Expr,
+    excluded: Expr,
+    rules: &HashMap<String, Expr>,
+    report: &mut Vec<String>,
+) -> Result<Expr, String> {
+    let lhs = lower(rule, lhs, rules, report)?;
+    let unsupported = || {
+        format!(
+            "Exclusion {} - {} in rule {} is not supported, the excluded part has to be a few short strings",
+            lhs, excluded, rule
+        )
+    };
+    // !a ~ (!b ~ ANY) is checked as a single predicate !(a | b) ~ ANY
+    let not = |strings: Vec<String>, e: Expr| match e {
+        Expr::Seq(lhs, rest) if matches!(*lhs, Expr::NegPred(_)) => {
+            let Expr::NegPred(inner) = *lhs else {
+                unreachable!()
+            };
+            Expr::Seq(
+                Box::new(Expr::NegPred(Box::new(Expr::Choice(
+                    Box::new(choice(strings)),
+                    inner,
+                )))),
+                rest,
+            )
+        }
+        e => Expr::Seq(
+            Box::new(Expr::NegPred(Box::new(choice(strings)))),
+            Box::new(e),
+        ),
+    };
+
+    // Char* - (Char* '?>' Char*)  is Char* not containing ?>:  (!"?>" ~ Char)*
+    let parts = sequence(&excluded);
+    if let Expr::Rep(item) = &lhs
+        && parts.len() >= 3
+        && parts[0] == Expr::Rep(item.clone())
+        && parts[parts.len() - 1] == Expr::Rep(item.clone())
+    {
+        let middle = seq(parts[1..parts.len() - 1].to_vec());
+        let strings = strings(&middle, rules, &mut HashSet::new()).ok_or_else(unsupported)?;
+        return Ok(Expr::Rep(Box::new(not(strings, *item.clone()))));
+    }
+
+    let strings = strings(&excluded, rules, &mut HashSet::new()).ok_or_else(unsupported)?;
+    // Char - '-' is exact, one character is matched either way
+    if !(single_char(&lhs, rules, &mut HashSet::new())
+        && strings.iter().all(|s| s.chars().count() == 1))
+    {
+        report.push(format!(
+            "Exclusion {} - {} in rule {} also rejects matches that only start with the excluded strings",
+            lhs, excluded, rule
+        ));
+    }
+    Ok(not(strings, lhs))
+}
+
+// Every string the expression matches, if there are few and none is empty
+fn strings(
+    expr: &Expr,
+    rules: &HashMap<String, Expr>,
+    visited: &mut HashSet<String>,
+) -> Option<Vec<String>> {
+    let strings = match expr {
+        Expr::Str(s) => vec![s.clone()],
+        Expr::Range(lo, hi) => {
+            let (lo, hi) = (lo.chars().next()?, hi.chars().next()?);
+            // A reversed range lists nothing, the caller reports it
+            if (hi as usize).checked_sub(lo as usize)? >= MAX_EXPANDED {
+                return None;
+            }
+            (lo..=hi).map(|c| c.to_string()).collect()
+        }
+        Expr::Choice(lhs, rhs) => {
+            let mut all = strings(lhs, rules, visited)?;
+            all.extend(strings(rhs, rules, visited)?);
+            all
+        }
+        Expr::Seq(lhs, rhs) => {
+            let rhs = strings(rhs, rules, visited)?;
+            strings(lhs, rules, visited)?
+                .iter()
+                .flat_map(|l| rhs.iter().map(move |r| format!("{}{}", l, r)))
+                .collect()
+        }
+        Expr::Ident(name) if rules.contains_key(name) && visited.insert(name.clone()) => {
+            let strings = strings(&rules[name], rules, visited);
+            visited.remove(name);
+            strings?
+        }
+        _ => return None,
+    };
+    (strings.len() <= MAX_EXPANDED && strings.iter().all(|s| !s.is_empty())).then_some(strings)
+}
+
+// Whether every match is a single character
+fn single_char(expr: &Expr, rules: &HashMap<String, Expr>, visited: &mut HashSet<String>) -> bool {
+    match expr {
+        Expr::Str(s) => s.chars().count() == 1,
+        Expr::Range(..) => true,
+        Expr::Ident(name) if name == "ANY" => true,
+        Expr::Choice(lhs, rhs) => {
+            single_char(lhs, rules, visited) && single_char(rhs, rules, visited)
+        }
+        Expr::Seq(lhs, rhs) => {
+            matches!(**lhs, Expr::NegPred(_)) && single_char(rhs, rules, visited)
+        }
+        Expr::Ident(name) if rules.contains_key(name) && visited.insert(name.clone()) => {
+            let single = single_char(&rules[name], rules, visited);
+            visited.remove(name);
+            single
+        }
+        _ => false,
+    }
+}
+
+fn choice(strings: Vec<String>) -> Expr {
+    strings
+        .into_iter()
+        .map(Expr::Str)
+        .reduce(|lhs, rhs| Expr::Choice(Box::new(lhs), Box::new(rhs)))
+        .unwrap()
+}
+
+fn seq(exprs: Vec<Expr>) -> Expr {
+    exprs
+        .into_iter()
+        .reduce(|lhs, rhs| Expr::Seq(Box::new(lhs), Box::new(rhs)))
+        .unwrap()
+}
+
+fn sequence(expr: &Expr) -> Vec<Expr> {
+    match expr {
+        Expr::Seq(lhs, rhs) => {
+            let mut parts = sequence(lhs);
+            parts.extend(sequence(rhs));
+            parts
+        }
+        expr => vec![expr.clone()],
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Literal(String),
+    // #xN
+    Char(char),
+    // Contents of [...], a character class, a rule number or a constraint note
+    Bracket(String),
+    Punct(&'static str),
+}
+
+const PUNCTS: [&str; 8] = ["::=", "|", "-", "?", "*", "+", "(", ")"];
+
+fn lex(ebnf: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = ebnf.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    let at = |i: usize, s: &str| chars[i..].iter().copied().take(s.len()).eq(s.chars());
+    let until = |i: usize, end: char| {
+        (i..chars.len())
+            .find(|j| chars[*j] == end)
+            .ok_or(format!("Unterminated {}", chars[i - 1]))
+    };
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if at(i, "/*") {
+            i = (i + 2..chars.len())
+                .find(|j| at(*j, "*/"))
+                .ok_or("Unterminated comment")?
+                + 2;
+        } else if c.is_alphanumeric() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c == '\'' || c == '"' || c == '[' {
+            // No escapes, a quote is written with the other kind of quote
+            let end = until(i + 1, if c == '[' { ']' } else { c })?;
+            let body: String = chars[i + 1..end].iter().collect();
+            tokens.push(if c == '[' {
+                Token::Bracket(body)
+            } else {
+                Token::Literal(body)
+            });
+            i = end + 1;
+        } else if at(i, "#x") {
+            let start = i + 2;
+            i = start;
+            while i < chars.len() && chars[i].is_ascii_hexdigit() {
+                i += 1;
+            }
+            tokens.push(Token::Char(hex_char(
+                &chars[start..i].iter().collect::<String>(),
+            )?));
+        } else if let Some(punct) = PUNCTS.iter().find(|p| at(i, p)) {
+            tokens.push(Token::Punct(punct));
+            i += punct.len();
+        } else {
+            return Err(format!("Unexpected {:?}", c));
+        }
+    }
+    Ok(tokens)
+}
+
+fn hex_char(hex: &str) -> Result<char, String> {
+    u32::from_str_radix(hex, 16)
+        .ok()
+        .and_then(char::from_u32)
+        .ok_or(format!("Invalid character #x{}", hex))
+}
+
+struct EbnfParser {
+    tokens: Vec<Token>,
+    pos: usize,
+    report: Vec<String>,
+}
+
+impl EbnfParser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Punct(p)) if *p == punct) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    // [1], [5a]
+    fn is_number(token: Option<&Token>) -> bool {
+        let Some(Token::Bracket(b)) = token else {
+            return false;
+        };
+        let b = b.trim();
+        b.starts_with(|c: char| c.is_ascii_digit()) && b.chars().all(|c| c.is_ascii_alphanumeric())
+    }
+
+    // [ WFC: Element Type Match ]
+    fn constraint(token: Option<&Token>) -> Option<String> {
+        match token {
+            Some(Token::Bracket(b)) => {
+                let (kind, name) = b.split_once(':')?;
+                let kind = kind.trim();
+                (!kind.is_empty() && kind.chars().all(|c| c.is_ascii_alphabetic()))
+                    .then(|| format!("{}: {}", kind, name.trim()))
+            }
+            _ => None,
+        }
+    }
+
+    // The next tokens start a rule, name ::= with an optional number before it
+    fn at_rule(&self) -> bool {
+        let i = self.pos + usize::from(Self::is_number(self.peek()));
+        matches!(self.tokens.get(i), Some(Token::Ident(_)))
+            && self.tokens.get(i + 1) == Some(&Token::Punct("::="))
+    }
+
+    fn grammar(&mut self) -> Result<Vec<(String, Expr)>, String> {
+        let mut rules = Vec::new();
+        while self.peek().is_some() {
+            if Self::is_number(self.peek()) {
+                self.pos += 1;
+            }
+            let name = match self.tokens.get(self.pos).cloned() {
+                Some(Token::Ident(name))
+                    if self.tokens.get(self.pos + 1) == Some(&Token::Punct("::=")) =>
+                {
+                    name
+                }
+                token => return Err(format!("Expected a rule, found {:?}", token)),
+            };
+            self.pos += 2;
+            let expr = self
+                .alternatives()
+                .map_err(|e| format!("{} in rule {}", e, name))?;
+            while let Some(note) = Self::constraint(self.peek()) {
+                self.report.push(format!(
+                    "Constraint [{}] on rule {} is not checked",
+                    note, name
+                ));
+                self.pos += 1;
+            }
+            if self.peek().is_some() && !self.at_rule() {
+                return Err(format!(
+                    "Unexpected {:?} after rule {}",
+                    self.peek().unwrap(),
+                    name
+                ));
+            }
+            rules.push((name, expr));
+        }
+        Ok(rules)
+    }
+
+    fn alternatives(&mut self) -> Result<Expr, String> {
+        let mut expr = self.sequence()?;
+        while self.eat("|") {
+            expr = Expr::Choice(Box::new(expr), Box::new(self.sequence()?));
+        }
+        Ok(expr)
+    }
+
+    fn sequence(&mut self) -> Result<Expr, String> {
+        let mut parts = Vec::new();
+        while !self.at_rule()
+            && Self::constraint(self.peek()).is_none()
+            && !matches!(self.peek(), None | Some(Token::Punct("|" | ")")))
+        {
+            parts.push(self.difference()?);
+        }
+        if parts.is_empty() {
+            return Err(format!("Empty alternative before {:?}", self.peek()));
+        }
+        Ok(seq(parts))
+    }
+
+    // A - B is kept as A ~ !B until the rules are known
+    fn difference(&mut self) -> Result<Expr, String> {
+        let expr = self.postfix()?;
+        if self.eat("-") {
+            let excluded = self.postfix()?;
+            return Ok(Expr::Seq(
+                Box::new(expr),
+                Box::new(Expr::NegPred(Box::new(excluded))),
+            ));
+        }
+        Ok(expr)
+    }
+
+    fn postfix(&mut self) -> Result<Expr, String> {
+        let mut expr = self.atom()?;
+        loop {
+            expr = if self.eat("?") {
+                Expr::Opt(Box::new(expr))
+            } else if self.eat("*") {
+                Expr::Rep(Box::new(expr))
+            } else if self.eat("+") {
+                Expr::RepOnce(Box::new(expr))
+            } else {
+                return Ok(expr);
+            };
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expr, String> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Ident(name)) => Ok(Expr::Ident(name)),
+            Some(Token::Literal(s)) if !s.is_empty() => Ok(Expr::Str(s)),
+            Some(Token::Char(c)) => Ok(Expr::Str(c.to_string())),
+            Some(Token::Bracket(class)) => char_class(&class),
+            Some(Token::Punct("(")) => {
+                let expr = self.alternatives()?;
+                if !self.eat(")") {
+                    return Err(format!("Expected ) before {:?}", self.peek()));
+                }
+                Ok(expr)
+            }
+            token => Err(format!("Unexpected {:?}", token)),
+        }
+    }
+}
+
+// [a-zA-Z], [#x20-#xD7FF] or [^<&], a negated class is any character not listed
+fn char_class(class: &str) -> Result<Expr, String> {
+    let (negated, body) = match class.strip_prefix('^') {
+        Some(body) => (true, body),
+        None => (false, class),
+    };
+    let mut chars = Vec::new();
+    let mut rest = body;
+    while let Some(c) = rest.chars().next() {
+        if let Some(hex) = rest.strip_prefix("#x") {
+            let len = hex
+                .find(|c: char| !c.is_ascii_hexdigit())
+                .unwrap_or(hex.len());
+            chars.push((hex_char(&hex[..len])?, true));
+            rest = &hex[len..];
+        } else {
+            chars.push((c, false));
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    let mut items = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (lo, _) = chars[i];
+        if i + 2 < chars.len() && chars[i + 1] == ('-', false) {
+            let hi = chars[i + 2].0;
+            if hi < lo {
+                return Err(format!("Empty range in [{}]", class));
+            }
+            items.push(Expr::Range(lo.to_string(), hi.to_string()));
+            i += 3;
+        } else {
+            items.push(Expr::Str(lo.to_string()));
+            i += 1;
+        }
+    }
+    let set = items
+        .into_iter()
+        .reduce(|lhs, rhs| Expr::Choice(Box::new(lhs), Box::new(rhs)))
+        .ok_or(format!("Empty character class [{}]", class))?;
+    if !negated {
+        return Ok(set);
+    }
+    let listed = strings(&set, &HashMap::new(), &mut HashSet::new())
+        .ok_or(format!("Negated class [{}] is too large", class))?;
+    Ok(Expr::Seq(
+        Box::new(Expr::NegPred(Box::new(choice(listed)))),
+        Box::new(Expr::Ident("ANY".to_string())),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn lowered(ebnf: &str) -> Expr {
+        to_rules(ebnf).unwrap().0.remove(1).expr
+    }
+
+    fn not(strings: &[&str], expr: Expr) -> Expr {
+        Expr::Seq(
+            Box::new(Expr::NegPred(Box::new(choice(
+                strings.iter().map(|s| s.to_string()).collect(),
+            )))),
+            Box::new(expr),
+        )
+    }
+
+    #[test]
+    fn test_xml() {
+        let ebnf = fs::read_to_string("grammars/test_ebnf.ebnf").unwrap();
+        let (rules, report) = to_rules(&ebnf).unwrap();
+        assert_eq!(rules[1].name, "document");
+        assert_eq!(report.len(), 2);
+        assert!(report[0].contains("WFC: Element Type Match"));
+        assert!(report[1].contains("rule PITarget"));
+
+        let doc = fs::read_to_string("tests/test_docs/test_ebnf.txt").unwrap();
+        GrammarGraph::new()
+            .parse_rules_and_build_graph(rules.clone(), &doc)
+            .unwrap_or_else(|e| panic!("{}", e));
+        for bad in [
+            "<a><!-- x -- y --></a>",
+            "<a>]]></a>",
+            "<a x=\"<\"/>",
+            "<?xml-stylesheet href=\"s\"?><a/>",
+        ] {
+            assert!(
+                GrammarGraph::new()
+                    .parse_rules_and_build_graph(rules.clone(), bad)
+                    .is_err(),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_exclusions() {
+        let any = || Expr::Ident("ANY".to_string());
+        let char_ = || Expr::Ident("C".to_string());
+        assert_eq!(
+            lowered("a ::= C - '-'\nC ::= [#x20-#x7E]"),
+            not(&["-"], char_())
+        );
+        assert_eq!(
+            lowered("a ::= C* - (C* '?>' C*)\nC ::= [^<]"),
+            Expr::Rep(Box::new(not(&["?>"], char_())))
+        );
+        assert_eq!(lowered("a ::= [^a-c]"), not(&["a", "b", "c"], any()));
+        let (_, report) = to_rules("a ::= C+ - (('a' | 'b') [0-1])\nC ::= [a-z]").unwrap();
+        assert_eq!(report.len(), 1);
+        assert_eq!(
+            lowered("a ::= C+ - (('a' | 'b') [0-1])\nC ::= [a-z]"),
+            not(&["a0", "a1", "b0", "b1"], Expr::RepOnce(Box::new(char_())))
+        );
+
+        assert!(to_rules("a ::= 'x'* - 'y'*").is_err());
+        assert!(to_rules("a ::= ANY - 'y'\nANY ::= 'x'").is_err());
+        assert!(to_rules("a ::= b").is_err());
+        assert!(to_rules("a ::= [^#x0-#xFFFF]").is_err());
+        assert!(to_rules("a ::= [z-a]").is_err());
+        assert!(to_rules("a ::= [^z-a]").is_err());
+        assert!(to_rules("a ::= C - [#x7A-#x61]\nC ::= [a-z]").is_err());
+        let reversed = Expr::Range("z".to_string(), "a".to_string());
+        assert!(strings(&reversed, &HashMap::new(), &mut HashSet::new()).is_none());
+    }
+}
@@ -0,0 +1,768 @@
+use super::regex;
+use crate::parser::{GrammarGraph, NP_MAX_LOOKAHEAD};
+use pest_meta::ast::{Expr, Rule, RuleType};
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+
+// Strings, numbers and the punctuation come from the generic JSON grammar
+const JSON_GRAMMAR: &str = include_str!("../../grammars/json.pest");
+
+// Required keys of an object that may come in any order, every order is a row of its rule
+const PERMUTED_KEYS: usize = 3;
+
+// Keywords that only describe the schema
+const ANNOTATIONS: [&str; 12] = [
+    "$schema",
+    "$id",
+    "$comment",
+    "$defs",
+    "definitions",
+    "title",
+    "description",
+    "default",
+    "examples",
+    "deprecated",
+    "readOnly",
+    "writeOnly",
+];
+
+// Compiles a JSON Schema into a grammar for the documents it accepts, built on the rules of
+// grammars/json.pest. Keys may come in any order, except that more than PERMUTED_KEYS required
+// keys keep the order of "required". Keywords that can't be expressed are reported and not
+// checked, the grammar then accepts more than the schema does
+pub fn to_rules(schema: &str) -> Result<(Vec<Rule>, Vec<String>), String> {
+    let schema: Value =
+        serde_json::from_str(schema).map_err(|e| format!("Invalid JSON Schema: {}", e))?;
+    let mut rules = GrammarGraph::pest_rules(JSON_GRAMMAR)?;
+    rules.retain(|rule| rule.name != "root");
+
+    let mut c = SchemaCompiler {
+        root: &schema,
+        names: rules.iter().map(|rule| rule.name.clone()).collect(),
+        refs: HashMap::new(),
+        rules: Vec::new(),
+        report: Vec::new(),
+    };
+    let start = c.schema("schema", &schema)?;
+
+    let root = Expr::Seq(
+        Box::new(Expr::Seq(
+            Box::new(Expr::Ident("SOI".to_string())),
+            Box::new(Expr::Ident(start)),
+        )),
+        Box::new(Expr::Ident("EOI".to_string())),
+    );
+    rules.insert(
+        0,
+        Rule {
+            name: "root".to_string(),
+            ty: RuleType::Normal,
+            expr: root,
+        },
+    );
+    rules.extend(c.rules);
+    Ok((rules, c.report))
+}
+
+fn ident(name: &str) -> Expr {
+    Expr::Ident(name.to_string())
+}
+
+fn seq(exprs: Vec<Expr>) -> Expr {
+    exprs
+        .into_iter()
+        .reduce(|lhs, rhs| Expr::Seq(Box::new(lhs), Box::new(rhs)))
+        .unwrap()
+}
+
+fn choice(exprs: Vec<Expr>) -> Expr {
+    exprs
+        .into_iter()
+        .reduce(|lhs, rhs| Expr::Choice(Box::new(lhs), Box::new(rhs)))
+        .unwrap()
+}
+
+// Every order of the items
+fn permutations(items: &[Expr]) -> Vec<Vec<Expr>> {
+    if items.len() <= 1 {
+        return vec![items.to_vec()];
+    }
+    let mut orders = Vec::new();
+    for (i, first) in items.iter().enumerate() {
+        let mut rest = items.to_vec();
+        rest.remove(i);
+        for mut order in permutations(&rest) {
+            order.insert(0, first.clone());
+            orders.push(order);
+        }
+    }
+    orders
+}
+
+// open ~ item ~ (comma ~ item)* ~ close, with the repetition given
+fn list(open: &str, item: Expr, rest: impl Fn(Box<Expr>) -> Option<Expr>, close: &str) -> Expr {
+    let tail = Box::new(seq(vec![ident("comma"), item.clone()]));
+    let mut parts = vec![ident(open), item];
+    parts.extend(rest(tail));
+    parts.push(ident(close));
+    seq(parts)
+}
+
+// The ways a decoded character can be written inside a JSON string, as serde_json writes it
+fn json_chars(lo: char, hi: char) -> Vec<Expr> {
+    let mut exprs = Vec::new();
+    let mut raw = |lo: u32, hi: u32| {
+        if lo <= hi {
+            let c = |code| char::from_u32(code).unwrap().to_string();
+            exprs.push(if lo == hi {
+                Expr::Str(c(lo))
+            } else {
+                Expr::Range(c(lo), c(hi))
+            });
+        }
+    };
+    let (lo, hi) = (lo as u32, hi as u32);
+    let mut next = lo;
+    for special in [0x22, 0x5C] {
+        if (next..=hi).contains(&special) {
+            raw(next.max(0x20), special - 1);
+            next = special + 1;
+        }
+    }
+    raw(next.max(0x20), hi);
+    for code in lo..=hi.min(0x5C) {
+        let escape = match code {
+            0x22 => "\\\"".to_string(),
+            0x5C => "\\\\".to_string(),
+            0x08 => "\\b".to_string(),
+            0x0C => "\\f".to_string(),
+            0x0A => "\\n".to_string(),
+            0x0D => "\\r".to_string(),
+            0x09 => "\\t".to_string(),
+            code if code < 0x20 => format!("\\u{:04x}", code),
+            _ => continue,
+        };
+        exprs.push(Expr::Str(escape));
+    }
+    exprs
+}
+
+struct SchemaCompiler<'a> {
+    root: &'a Value,
+    names: HashSet<String>,
+    // $ref targets already compiled, by JSON pointer
+    refs: HashMap<String, String>,
+    rules: Vec<Rule>,
+    report: Vec<String>,
+}
+
+impl SchemaCompiler<'_> {
+    // A fresh rule name from a path in the schema
+    fn name(&mut self, path: &str) -> String {
+        let base: String = path
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let mut name = base.clone();
+        let mut i = 1;
+        while !self.names.insert(name.clone()) {
+            i += 1;
+            name = format!("{}_{}", base, i);
+        }
+        name
+    }
+
+    // A key or scalar written out, in an atomic rule so no whitespace fits inside it
+    fn token(&mut self, path: &str, text: String) -> Expr {
+        let rule = self.name(path);
+        self.rules.push(Rule {
+            name: rule.clone(),
+            ty: RuleType::Atomic,
+            expr: Expr::Str(text),
+        });
+        ident(&rule)
+    }
+
+    // A JSON value written out, the rule it sits in skips whitespace between the tokens
+    fn literal(&mut self, name: &str, value: &Value) -> Expr {
+        match value {
+            Value::Array(items) if items.is_empty() => {
+                seq(vec![ident("open_bracket"), ident("close_bracket")])
+            }
+            Value::Array(items) => {
+                let mut parts = vec![ident("open_bracket")];
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        parts.push(ident("comma"));
+                    }
+                    parts.push(self.literal(name, item));
+                }
+                parts.push(ident("close_bracket"));
+                seq(parts)
+            }
+            Value::Object(map) if map.is_empty() => {
+                seq(vec![ident("open_brace"), ident("close_brace")])
+            }
+            Value::Object(map) => {
+                let mut parts = vec![ident("open_brace")];
+                for (i, (key, item)) in map.iter().enumerate() {
+                    if i > 0 {
+                        parts.push(ident("comma"));
+                    }
+                    let key = Value::String(key.clone()).to_string();
+                    parts.push(self.token(&format!("{}_key", name), key));
+                    parts.push(ident("colon"));
+                    parts.push(self.literal(name, item));
+                }
+                parts.push(ident("close_brace"));
+                seq(parts)
+            }
+            value => self.token(&format!("{}_literal", name), value.to_string()),
+        }
+    }
+
+    fn unsupported(&mut self, rule: &str, keyword: &str) {
+        self.report.push(format!(
+            "Keyword {} in {} can't be expressed in the grammar and is not checked",
+            keyword, rule
+        ));
+    }
+
+    // Compiles a schema into a rule, returns its name
+    fn schema(&mut self, path: &str, schema: &Value) -> Result<String, String> {
+        let name = self.name(path);
+        let (expr, ty) = self.body(&name, schema)?;
+        self.rules.push(Rule {
+            name: name.clone(),
+            ty,
+            expr,
+        });
+        Ok(name)
+    }
+
+    fn body(&mut self, name: &str, schema: &Value) -> Result<(Expr, RuleType), String> {
+        let map = match schema {
+            Value::Bool(true) => return Ok((ident("value"), RuleType::Normal)),
+            Value::Bool(false) => return Err(format!("Schema {} matches nothing", name)),
+            Value::Object(map) => map,
+            _ => return Err(format!("Schema {} is not an object", name)),
+        };
+
+        let mut handled: Vec<&str> = ANNOTATIONS.to_vec();
+        let result = if let Some(target) = map.get("$ref") {
+            handled.push("$ref");
+            let target = target.as_str().ok_or("$ref is not a string")?;
+            (ident(&self.reference(name, target)?), RuleType::Normal)
+        } else if let Some(values) = map.get("enum").or(map.get("const")) {
+            handled.extend(["enum", "const", "type"]);
+            let mut values = match values {
+                Value::Array(values) if map.contains_key("enum") => values.clone(),
+                Value::Array(_) => return Err(format!("enum of {} is not an array", name)),
+                value => vec![value.clone()],
+            };
+            if values.is_empty() {
+                return Err(format!("Schema {} matches nothing", name));
+            }
+            // 10 before 1, a shorter number would stop a longer one from being tried
+            values.sort_by_key(|value| std::cmp::Reverse(value.to_string().len()));
+            if values
+                .iter()
+                .all(|value| !value.is_array() && !value.is_object())
+            {
+                let exprs = values.iter().map(|value| Expr::Str(value.to_string()));
+                (choice(exprs.collect()), RuleType::Atomic)
+            } else {
+                let exprs = values.iter().map(|value| self.literal(name, value));
+                (choice(exprs.collect()), RuleType::Normal)
+            }
+        } else if let Some(keyword) = ["anyOf", "oneOf"]
+            .into_iter()
+            .find(|k| map.contains_key(*k))
+        {
+            handled.push(keyword);
+            if keyword == "oneOf" {
+                self.report.push(format!(
+                    "oneOf in {} is checked as anyOf, a value matching several is accepted",
+                    name
+                ));
+            }
+            let Some(Value::Array(alternatives)) = map.get(keyword) else {
+                return Err(format!("{} of {} is not an array", keyword, name));
+            };
+            let mut exprs = Vec::new();
+            for (i, alternative) in alternatives.iter().enumerate() {
+                exprs.push(ident(
+                    &self.schema(&format!("{}_{}", name, i + 1), alternative)?,
+                ));
+            }
+            if exprs.is_empty() {
+                return Err(format!("Schema {} matches nothing", name));
+            }
+            (choice(exprs), RuleType::Normal)
+        } else {
+            handled.push("type");
+            self.typed(name, map, &mut handled)?
+        };
+
+        for keyword in map.keys() {
+            if !handled.contains(&keyword.as_str()) {
+                self.unsupported(name, keyword);
+            }
+        }
+        Ok(result)
+    }
+
+    // A local $ref, compiled once so recursive schemas become recursive rules
+    fn reference(&mut self, name: &str, target: &str) -> Result<String, String> {
+        if let Some(rule) = self.refs.get(target) {
+            return Ok(rule.clone());
+        }
+        let root = self.root;
+        let schema = target
+            .strip_prefix('#')
+            .and_then(|pointer| root.pointer(pointer))
+            .ok_or(format!(
+                "$ref {} in {} is not a local reference",
+                target, name
+            ))?;
+        let rule = self.name(&format!(
+            "def_{}",
+            target
+                .rsplit('/')
+                .next()
+                .filter(|s| !s.is_empty() && *s != "#")
+                .unwrap_or("schema")
+        ));
+        self.refs.insert(target.to_string(), rule.clone());
+        let (expr, ty) = self.body(&rule, schema)?;
+        self.rules.push(Rule {
+            name: rule.clone(),
+            ty,
+            expr,
+        });
+        Ok(rule)
+    }
+
+    // The choice over the types the schema allows, a schema without a type accepts any value
+    // its other keywords fit
+    fn typed<'a>(
+        &mut self,
+        name: &str,
+        map: &'a Map<String, Value>,
+        handled: &mut Vec<&'a str>,
+    ) -> Result<(Expr, RuleType), String> {
+        let mut types: Vec<&str> = match map.get("type") {
+            Some(Value::String(ty)) => vec![ty],
+            Some(Value::Array(types)) => types.iter().filter_map(Value::as_str).collect(),
+            Some(_) => return Err(format!("type of {} is not a string or an array", name)),
+            None => {
+                let implied = [
+                    ("object", ["properties", "required", "additionalProperties"]),
+                    ("array", ["items", "minItems", "maxItems"]),
+                    ("string", ["pattern", "minLength", "maxLength"]),
+                ];
+                let types: Vec<&str> = implied
+                    .iter()
+                    .filter(|(_, keywords)| keywords.iter().any(|k| map.contains_key(*k)))
+                    .map(|(ty, _)| *ty)
+                    .collect();
+                if types.is_empty() {
+                    return Ok((ident("value"), RuleType::Normal));
+                }
+                types
+            }
+        };
+        // A number is also an integer, and an integer would stop at the . of a number
+        if types.contains(&"number") {
+            types.retain(|ty| *ty != "integer");
+        }
+
+        let mut alternatives = Vec::new();
+        for ty in &types {
+            let (expr, atomic) = match *ty {
+                "null" | "boolean" | "number" => (ident(ty), false),
+                "integer" => (ident("integer_part"), false),
+                "string" => self.string(name, map, handled)?,
+                "object" => (self.object(name, map, handled)?, false),
+                "array" => (self.array(name, map, handled)?, false),
+                ty => return Err(format!("Unknown type {} in {}", ty, name)),
+            };
+            alternatives.push((expr, atomic));
+        }
+        if alternatives.len() == 1 {
+            let (expr, atomic) = alternatives.pop().unwrap();
+            let ty = if atomic {
+                RuleType::Atomic
+            } else {
+                RuleType::Normal
+            };
+            return Ok((expr, ty));
+        }
+        // A string that has to be atomic gets a rule of its own
+        let mut exprs = Vec::new();
+        for (expr, atomic) in alternatives {
+            exprs.push(if atomic {
+                let rule = self.name(&format!("{}_string", name));
+                self.rules.push(Rule {
+                    name: rule.clone(),
+                    ty: RuleType::Atomic,
+                    expr,
+                });
+                ident(&rule)
+            } else {
+                expr
+            });
+        }
+        Ok((choice(exprs), RuleType::Normal))
+    }
+
+    // A string rule, atomic when it looks inside the string
+    fn string<'a>(
+        &mut self,
+        name: &str,
+        map: &'a Map<String, Value>,
+        handled: &mut Vec<&'a str>,
+    ) -> Result<(Expr, bool), String> {
+        let length = |keyword| map.get(keyword).and_then(Value::as_u64);
+        let (min, max) = (length("minLength"), length("maxLength"));
+        if let Some(Value::String(pattern)) = map.get("pattern") {
+            handled.push("pattern");
+            return Ok((self.pattern(name, pattern)?, true));
+        }
+        handled.extend(["minLength", "maxLength"]);
+        let chars = Box::new(ident("char"));
+        let chars = match (min, max) {
+            (None | Some(0), None) => return Ok((ident("string"), false)),
+            (Some(min), None) => Expr::RepMin(chars, min as u32),
+            (None | Some(0), Some(max)) => Expr::RepMax(chars, max as u32),
+            (Some(min), Some(max)) if min == max => Expr::RepExact(chars, min as u32),
+            (Some(min), Some(max)) => Expr::RepMinMax(chars, min as u32, max as u32),
+        };
+        Ok((seq(vec![ident("quote"), chars, ident("quote")]), true))
+    }
+
+    // The pattern as one rule per state of its automaton, an accepting state can end the
+    // string. No raw quote is a character of the string, so the choice is never ambiguous
+    fn pattern(&mut self, name: &str, pattern: &str) -> Result<Expr, String> {
+        let dfa = regex::compile(pattern, regex::Flavor::Ecma)
+            .map_err(|e| format!("{} in {}", e, name))?;
+        let states: Vec<String> = (0..dfa.states.len())
+            .map(|i| self.name(&format!("{}_q{}", name, i)))
+            .collect();
+        let quote = ident("quote");
+        let chars = |intervals: &[(char, char)]| {
+            intervals
+                .iter()
+                .flat_map(|(lo, hi)| json_chars(*lo, *hi))
+                .collect()
+        };
+        self.rules
+            .extend(dfa.rules(&states, RuleType::Atomic, chars, &quote));
+        Ok(seq(vec![ident("quote"), ident(&states[0])]))
+    }
+
+    fn object<'a>(
+        &mut self,
+        name: &str,
+        map: &'a Map<String, Value>,
+        handled: &mut Vec<&'a str>,
+    ) -> Result<Expr, String> {
+        handled.extend(["properties", "required", "additionalProperties"]);
+        let empty = Map::new();
+        let properties = match map.get("properties") {
+            Some(Value::Object(properties)) => properties,
+            Some(_) => return Err(format!("properties of {} is not an object", name)),
+            None => &empty,
+        };
+        let required: Vec<&str> = match map.get("required") {
+            Some(Value::Array(keys)) => keys.iter().filter_map(Value::as_str).collect(),
+            Some(_) => return Err(format!("required of {} is not an array", name)),
+            None => Vec::new(),
+        };
+
+        let pair = |c: &mut Self, key: &str| -> Result<Option<Expr>, String> {
+            let value = match properties.get(key) {
+                // A property that must not appear
+                Some(Value::Bool(false)) => return Ok(None),
+                Some(schema) => c.schema(&format!("{}_{}", name, key), schema)?,
+                None => "value".to_string(),
+            };
+            // A rule per pair keeps the characters of the key out of the object's rows
+            let key_text = Value::String(key.to_string()).to_string();
+            let key_rule = c.token(&format!("{}_{}_key", name, key), key_text);
+            let rule = c.name(&format!("{}_{}_pair", name, key));
+            c.rules.push(Rule {
+                name: rule.clone(),
+                ty: RuleType::Normal,
+                expr: seq(vec![key_rule, ident("colon"), ident(&value)]),
+            });
+            Ok(Some(ident(&rule)))
+        };
+        let mut fixed = Vec::new();
+        for key in &required {
+            match pair(self, key)? {
+                Some(expr) => fixed.push(expr),
+                None => return Err(format!("Required key {} of {} is forbidden", key, name)),
+            }
+        }
+        let mut members = Vec::new();
+        for key in properties
+            .keys()
+            .filter(|key| !required.contains(&key.as_str()))
+        {
+            members.extend(pair(self, key)?);
+        }
+
+        let additional = match map.get("additionalProperties") {
+            Some(Value::Bool(false)) => None,
+            None | Some(Value::Bool(true)) => Some(ident("pair")),
+            Some(schema) => {
+                let value = self.schema(&format!("{}_additional", name), schema)?;
+                Some(seq(vec![ident("string"), ident("colon"), ident(&value)]))
+            }
+        };
+        if let Some(additional) = additional {
+            // An additional pair can't have a listed key, or it would skip the key's schema
+            let mut listed: Vec<String> = properties
+                .keys()
+                .map(|key| Value::String(key.clone()).to_string())
+                .collect();
+            if listed
+                .iter()
+                .any(|text| text.chars().count() > NP_MAX_LOOKAHEAD)
+            {
+                self.report.push(format!(
+                    "Additional keys of {} that start like a listed key of more than {} characters are rejected",
+                    name,
+                    NP_MAX_LOOKAHEAD - 2
+                ));
+            }
+            listed = listed
+                .iter()
+                .map(|text| text.chars().take(NP_MAX_LOOKAHEAD).collect())
+                .collect();
+            listed.sort();
+            listed.dedup();
+            members.push(if listed.is_empty() {
+                additional
+            } else {
+                let excluded = choice(listed.into_iter().map(Expr::Str).collect());
+                seq(vec![Expr::NegPred(Box::new(excluded)), additional])
+            });
+        }
+        let member = (!members.is_empty()).then(|| choice(members));
+
+        if fixed.is_empty() {
+            let empty = seq(vec![ident("open_brace"), ident("close_brace")]);
+            return Ok(match member {
+                None => empty,
+                Some(member) => Expr::Choice(
+                    Box::new(empty),
+                    Box::new(list(
+                        "open_brace",
+                        member,
+                        |tail| Some(Expr::Rep(tail)),
+                        "close_brace",
+                    )),
+                ),
+            });
+        }
+
+        // Every order of the required keys is a row, other members may sit between them
+        let orders = if fixed.len() <= PERMUTED_KEYS {
+            permutations(&fixed)
+        } else {
+            self.report.push(format!(
+                "Required keys of {} have to come in the order of required, only up to {} can come in any order",
+                name, PERMUTED_KEYS
+            ));
+            vec![fixed]
+        };
+        let rows = orders.into_iter().map(|order| {
+            let mut parts = vec![ident("open_brace")];
+            if let Some(member) = &member {
+                parts.push(Expr::Rep(Box::new(seq(vec![
+                    member.clone(),
+                    ident("comma"),
+                ]))));
+            }
+            for (i, expr) in order.into_iter().enumerate() {
+                if i > 0 {
+                    parts.push(ident("comma"));
+                }
+                parts.push(expr);
+                if let Some(member) = &member {
+                    parts.push(Expr::Rep(Box::new(seq(vec![
+                        ident("comma"),
+                        member.clone(),
+                    ]))));
+                }
+            }
+            parts.push(ident("close_brace"));
+            seq(parts)
+        });
+        Ok(choice(rows.collect()))
+    }
+
+    fn array<'a>(
+        &mut self,
+        name: &str,
+        map: &'a Map<String, Value>,
+        handled: &mut Vec<&'a str>,
+    ) -> Result<Expr, String> {
+        handled.extend(["items", "minItems", "maxItems"]);
+        let item = match map.get("items") {
+            None => ident("value"),
+            Some(Value::Array(_)) => {
+                self.unsupported(name, "items as an array");
+                ident("value")
+            }
+            Some(schema) => ident(&self.schema(&format!("{}_item", name), schema)?),
+        };
+        let count = |keyword| map.get(keyword).and_then(Value::as_u64).map(|n| n as u32);
+        let (min, max) = (count("minItems").unwrap_or(0), count("maxItems"));
+        let empty = seq(vec![ident("open_bracket"), ident("close_bracket")]);
+        if max == Some(0) {
+            return Ok(empty);
+        }
+        // The first item is written out, the repetition counts the others
+        let items = list(
+            "open_bracket",
+            item,
+            |tail| match (min.saturating_sub(1), max.map(|max| max - 1)) {
+                (0, None) => Some(Expr::Rep(tail)),
+                (min, None) => Some(Expr::RepMin(tail, min)),
+                (_, Some(0)) => None,
+                (0, Some(max)) => Some(Expr::RepMax(tail, max)),
+                (min, Some(max)) if min == max => Some(Expr::RepExact(tail, min)),
+                (min, Some(max)) => Some(Expr::RepMinMax(tail, min, max)),
+            },
+            "close_bracket",
+        );
+        Ok(if min == 0 {
+            Expr::Choice(Box::new(empty), Box::new(items))
+        } else {
+            items
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn parses(rules: &[Rule], doc: &str) -> bool {
+        GrammarGraph::new()
+            .parse_rules_and_build_graph(rules.to_vec(), doc)
+            .is_ok()
+    }
+
+    #[test]
+    fn test_payment() {
+        let schema = fs::read_to_string("grammars/test_schema.json").unwrap();
+        let (rules, report) = to_rules(&schema).unwrap();
+        assert_eq!(
+            report,
+            vec![
+                "Keyword minimum in schema_amount can't be expressed in the grammar and is not checked"
+            ]
+        );
+
+        let doc = fs::read_to_string("tests/test_docs/test_schema.txt").unwrap();
+        GrammarGraph::new()
+            .parse_rules_and_build_graph(rules.clone(), &doc)
+            .unwrap_or_else(|e| panic!("{}", e));
+        let payment = |fields: &str| format!("{{\"id\": \"tx-00ff\", {}}}", fields);
+        assert!(parses(
+            &rules,
+            &payment("\"amount\": 1, \"currency\": \"USD\", \"memo\": \"12345678\"")
+        ));
+        // Keys in any order
+        assert!(parses(
+            &rules,
+            "{\"memo\": \"m\", \"currency\": \"USD\", \"id\": \"tx-00ff\", \"amount\": 1}"
+        ));
+        for bad in [
+            // Required keys missing
+            payment("\"currency\": \"USD\""),
+            payment("\"amount\": 1"),
+            payment("\"amount\": 1.5, \"currency\": \"USD\""),
+            payment("\"amount\": 1, \"currency\": \"GBP\""),
+            payment("\"amount\": 1, \"currency\": \"USD\", \"memo\": \"123456789\""),
+            payment("\"amount\": 1, \"currency\": \"USD\", \"tags\": [\"a\", \"b\", \"c\", \"d\"]"),
+            payment("\"amount\": 1, \"currency\": \"USD\", \"note\": 1"),
+            payment("\"amount\": 1, \"currency\": \"USD\", \"payer\": {\"name\": \"ann\"}"),
+            "{\"id\": \"tx-00fg\", \"amount\": 1, \"currency\": \"USD\"}".to_string(),
+            // Keys and enum values are single tokens
+            payment("\"amount\": 1, \"currency\": \"U SD\""),
+            "{\"i d\": \"tx-00ff\", \"amount\": 1, \"currency\": \"USD\"}".to_string(),
+        ] {
+            assert!(!parses(&rules, &bad), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_keywords() {
+        let (rules, report) = to_rules(
+            r#"{"oneOf": [{"enum": [1, 10, [1, "a"]]}, {"type": ["integer", "null"]}], "format": "x"}"#,
+        )
+        .unwrap();
+        assert_eq!(report.len(), 2);
+        for doc in ["10", "[1 , \"a\"]", "null", "7"] {
+            assert!(parses(&rules, doc), "{}", doc);
+        }
+        assert!(!parses(&rules, "[1]"));
+        assert!(!parses(&rules, "1 0"));
+        assert!(!parses(&rules, "[1, \"a \"]"));
+
+        // A recursive schema, arrays of arrays of numbers
+        let (rules, report) = to_rules(
+            r##"{"type": ["number", "array"], "items": {"$ref": "#"}, "minItems": 1, "maxItems": 2}"##,
+        )
+        .unwrap();
+        assert!(report.is_empty());
+        assert!(parses(&rules, "[[1, [2]], 3]"));
+        assert!(!parses(&rules, "[[]]"));
+        assert!(!parses(&rules, "[1, 2, 3]"));
+
+        // An allowed set of keys, in any order and with extra keys
+        let (rules, report) = to_rules(r#"{"properties": {"a": {"type": "boolean"}}}"#).unwrap();
+        assert!(report.is_empty());
+        assert!(parses(&rules, "{\"b\": 1, \"a\": true}"));
+        assert!(parses(&rules, "{}"));
+        assert!(!parses(&rules, "{\"b\": 1, \"a\": 1}"));
+
+        // Required keys in any order, optional and extra keys between them
+        let (rules, report) = to_rules(
+            r#"{"required": ["a", "b"], "properties": {"a": {"type": "boolean"}, "b": {"type": "null"}, "c": {"type": "boolean"}}, "additionalProperties": {"type": "string"}}"#,
+        )
+        .unwrap();
+        assert!(report.is_empty());
+        for doc in [
+            "{\"a\": true, \"b\": null}",
+            "{\"c\": true, \"b\": null, \"x\": \"s\", \"a\": false}",
+        ] {
+            assert!(parses(&rules, doc), "{}", doc);
+        }
+        for doc in [
+            "{\"b\": null}",
+            "{\"b\": null, \"a\": false, \"c\": 1}",
+            "{\"b\": null, \"a\": false, \"x\": 1}",
+        ] {
+            assert!(!parses(&rules, doc), "{}", doc);
+        }
+
+        // Extra keys are told apart from long listed ones by their first characters
+        let (rules, report) =
+            to_rules(r#"{"properties": {"amount": {"type": "integer"}}}"#).unwrap();
+        assert_eq!(report.len(), 1);
+        assert!(parses(&rules, "{\"amount\": 1, \"b\": true}"));
+        assert!(!parses(&rules, "{\"amount\": true}"));
+        assert!(!parses(&rules, "{\"amounts\": true}"));
+
+        assert!(to_rules(r#"{"$ref": "other.json"}"#).is_err());
+        assert!(to_rules(r#"{"enum": []}"#).is_err());
+        assert!(to_rules(r#"{"type": "string", "pattern": "(a"}"#).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+pub mod abnf;
+pub mod antlr;
+pub mod ebnf;
+pub mod json_schema;
+pub mod regex;
+
+use crate::lint::{Diagnostic, Severity};
+use crate::parser::GrammarGraph;
+use pest_meta::ast::{Expr, Rule};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+fn extension(path: &str) -> Option<&str> {
+    Path::new(path).extension().and_then(|ext| ext.to_str())
+}
+
+// Character ranges the expression can start with, None when it can match the empty string
+// or starts with something else
+fn first_chars(
+    expr: &Expr,
+    rules: &HashMap<String, Expr>,
+    visited: &mut HashSet<String>,
+) -> Option<Vec<(char, char)>> {
+    match expr {
+        Expr::Str(s) => s.chars().next().map(|c| vec![(c, c)]),
+        Expr::Insens(s) => s.chars().next().map(|c| {
+            vec![
+                (c.to_ascii_lowercase(), c.to_ascii_lowercase()),
+                (c.to_ascii_uppercase(), c.to_ascii_uppercase()),
+            ]
+        }),
+        Expr::Range(lo, hi) => Some(vec![(lo.chars().next()?, hi.chars().next()?)]),
+        Expr::Choice(lhs, rhs) => {
+            let mut ranges = first_chars(lhs, rules, visited)?;
+            ranges.extend(first_chars(rhs, rules, visited)?);
+            Some(ranges)
+        }
+        Expr::Seq(lhs, _) => first_chars(lhs, rules, visited),
+        Expr::RepOnce(inner) => first_chars(inner, rules, visited),
+        Expr::RepExact(inner, n) | Expr::RepMin(inner, n) | Expr::RepMinMax(inner, n, _)
+            if *n > 0 =>
+        {
+            first_chars(inner, rules, visited)
+        }
+        Expr::Ident(name) if rules.contains_key(name) && visited.insert(name.clone()) => {
+            let ranges = first_chars(&rules[name], rules, visited);
+            visited.remove(name);
+            ranges
+        }
+        _ => None,
+    }
+}
+
+fn overlap(lhs: &[(char, char)], rhs: &[(char, char)]) -> bool {
+    lhs.iter()
+        .any(|&(lo, hi)| rhs.iter().any(|&(l, h)| lo <= h && l <= hi))
+}
+
+// Rules of a grammar file and what the import had to drop, the extension picks the format and
+// anything else is read as .pest
+fn lower(path: &str) -> Result<(Vec<Rule>, Vec<String>), String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    match extension(path) {
+        Some("abnf") => abnf::to_rules(&text),
+        Some("g4") => antlr::to_rules(&text),
+        Some("ebnf") => ebnf::to_rules(&text),
+        Some("json") => json_schema::to_rules(&text),
+        Some("regex") => Ok((regex::to_rules(&text)?, Vec::new())),
+        _ => Ok((GrammarGraph::pest_rules(&text)?, Vec::new())),
+    }
+}
+
+// Reads a grammar file into pest rules, warning about anything the import dropped
+pub fn read_rules(path: &str) -> Result<Vec<Rule>, String> {
+    let (rules, report) = lower(path)?;
+    for line in report {
+        eprintln!("{}: warning: {}", path, line);
+    }
+    Ok(rules)
+}
+
+// A .pest grammar gets the full lint, other formats are lowered and compiled so the first
+// problem is reported along with what the import dropped
+pub fn lint(path: &str) -> Result<Vec<Diagnostic>, String> {
+    if matches!(
+        extension(path),
+        Some("abnf" | "g4" | "ebnf" | "json" | "regex")
+    ) {
+        let note = |severity, message| Diagnostic {
+            severity,
+            rule: String::new(),
+            line: 1,
+            col: 1,
+            span: 0..0,
+            message,
+        };
+        return Ok(match lower(path) {
+            Ok((rules, report)) => {
+                let mut diagnostics: Vec<Diagnostic> = report
+                    .into_iter()
+                    .map(|message| note(Severity::Warning, message))
+                    .collect();
+                if let Err(message) = GrammarGraph::new().compile_rules(rules) {
+                    diagnostics.push(note(Severity::Error, message));
+                }
+                diagnostics
+            }
+            Err(message) => vec![note(Severity::Error, message)],
+        });
+    }
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(GrammarGraph::validate(&text))
+}
@@ -0,0 +1,1124 @@
+use pest_meta::ast::{Expr, Rule, RuleType};
+use std::collections::{BTreeSet, HashMap};
+
+// Patterns that need more states than this are rejected, every state becomes a rule
+pub const MAX_DFA_STATES: usize = 256;
+// {n,m} copies the repeated pattern, so bounds are kept small
+const MAX_REPEAT: u32 = 64;
+
+// Every char, surrogates are not chars
+const UNIVERSE: [(u32, u32); 2] = [(0, 0xD7FF), (0xE000, 0x10FFFF)];
+
+// Unicode classes like \w have hundreds of intervals and long choices are slow to compile, so
+// they go in rules of at most this many alternatives
+const CLASS_CHUNK: usize = 32;
+
+// What can follow (? in the regex crate to set flags
+const FLAGS: &str = "imsxuUR-";
+
+// General category abbreviations, \pL and \p{Lu} name the same sets as pest's LETTER and
+// UPPERCASE_LETTER
+const CATEGORIES: [(&str, &str); 38] = [
+    ("L", "Letter"),
+    ("LC", "Cased_Letter"),
+    ("Lu", "Uppercase_Letter"),
+    ("Ll", "Lowercase_Letter"),
+    ("Lt", "Titlecase_Letter"),
+    ("Lm", "Modifier_Letter"),
+    ("Lo", "Other_Letter"),
+    ("M", "Mark"),
+    ("Mn", "Nonspacing_Mark"),
+    ("Mc", "Spacing_Mark"),
+    ("Me", "Enclosing_Mark"),
+    ("N", "Number"),
+    ("Nd", "Decimal_Number"),
+    ("Nl", "Letter_Number"),
+    ("No", "Other_Number"),
+    ("P", "Punctuation"),
+    ("Pc", "Connector_Punctuation"),
+    ("Pd", "Dash_Punctuation"),
+    ("Ps", "Open_Punctuation"),
+    ("Pe", "Close_Punctuation"),
+    ("Pi", "Initial_Punctuation"),
+    ("Pf", "Final_Punctuation"),
+    ("Po", "Other_Punctuation"),
+    ("S", "Symbol"),
+    ("Sm", "Math_Symbol"),
+    ("Sc", "Currency_Symbol"),
+    ("Sk", "Modifier_Symbol"),
+    ("So", "Other_Symbol"),
+    ("Z", "Separator"),
+    ("Zs", "Space_Separator"),
+    ("Zl", "Line_Separator"),
+    ("Zp", "Paragraph_Separator"),
+    ("C", "Other"),
+    ("Cc", "Control"),
+    ("Cf", "Format"),
+    ("Cs", "Surrogate"),
+    ("Co", "Private_Use"),
+    ("Cn", "Unassigned"),
+];
+
+// [[:alpha:]] and the other ASCII classes of the regex crate
+const ASCII_CLASSES: [(&str, &[(u32, u32)]); 14] = [
+    ("alnum", &[(0x30, 0x39), (0x41, 0x5A), (0x61, 0x7A)]),
+    ("alpha", &[(0x41, 0x5A), (0x61, 0x7A)]),
+    ("ascii", &[(0x00, 0x7F)]),
+    ("blank", &[(0x09, 0x09), (0x20, 0x20)]),
+    ("cntrl", &[(0x00, 0x1F), (0x7F, 0x7F)]),
+    ("digit", &[(0x30, 0x39)]),
+    ("graph", &[(0x21, 0x7E)]),
+    ("lower", &[(0x61, 0x7A)]),
+    ("print", &[(0x20, 0x7E)]),
+    (
+        "punct",
+        &[(0x21, 0x2F), (0x3A, 0x40), (0x5B, 0x60), (0x7B, 0x7E)],
+    ),
+    ("space", &[(0x09, 0x0D), (0x20, 0x20)]),
+    ("upper", &[(0x41, 0x5A)]),
+    (
+        "word",
+        &[(0x30, 0x39), (0x41, 0x5A), (0x5F, 0x5F), (0x61, 0x7A)],
+    ),
+    ("xdigit", &[(0x30, 0x39), (0x41, 0x46), (0x61, 0x66)]),
+];
+
+// A deterministic automaton over inclusive char intervals, state 0 is the start. Every state
+// can reach an accepting one, so a pattern becomes a right-linear grammar with one rule per
+// state that ordered choice can't get wrong
+pub struct Dfa {
+    pub states: Vec<DfaState>,
+}
+
+pub struct DfaState {
+    pub accepting: bool,
+    // Disjoint intervals in increasing order with their target state
+    pub edges: Vec<((char, char), usize)>,
+}
+
+impl Dfa {
+    // One rule per state, every alternative is a char of the edges to a state followed by that
+    // state, or end in an accepting state. No row is wider than two children however long the
+    // pattern is. The end must not start with a char any edge takes
+    pub fn rules(
+        &self,
+        names: &[String],
+        ty: RuleType,
+        mut chars: impl FnMut(&[(char, char)]) -> Vec<Expr>,
+        end: &Expr,
+    ) -> Vec<Rule> {
+        let mut rules = Vec::new();
+        for (state, name) in self.states.iter().zip(names) {
+            let mut targets: Vec<(usize, Vec<(char, char)>)> = Vec::new();
+            for (interval, target) in &state.edges {
+                match targets.iter_mut().find(|(t, _)| t == target) {
+                    Some((_, intervals)) => intervals.push(*interval),
+                    None => targets.push((*target, vec![*interval])),
+                }
+            }
+            let mut exprs: Vec<Expr> = targets
+                .into_iter()
+                .map(|(target, intervals)| {
+                    Expr::Seq(
+                        Box::new(choice(chars(&intervals))),
+                        Box::new(Expr::Ident(names[target].clone())),
+                    )
+                })
+                .collect();
+            if state.accepting {
+                exprs.push(end.clone());
+            }
+            rules.push(Rule {
+                name: name.clone(),
+                ty,
+                expr: choice(exprs),
+            });
+        }
+        rules
+    }
+}
+
+fn choice(exprs: Vec<Expr>) -> Expr {
+    exprs
+        .into_iter()
+        .reduce(|a, b| Expr::Choice(Box::new(a), Box::new(b)))
+        .unwrap()
+}
+
+// The two pattern syntaxes, JSON Schema uses ECMA-262 and .regex files the regex crate's
+#[derive(Clone, Copy, PartialEq)]
+pub enum Flavor {
+    Ecma,
+    Rust,
+}
+
+// Compiles a pattern to a minimal DFA, a match anywhere in the text unless an alternative is
+// anchored with ^ and $, or \A and \z in the regex crate. Backreferences, lookaround, word
+// boundaries and multi-line mode are not regular or not supported and are rejected
+pub fn compile(pattern: &str, flavor: Flavor) -> Result<Dfa, String> {
+    compile_anchored(pattern, flavor).map_err(|e| format!("{} in pattern {:?}", e, pattern))
+}
+
+fn compile_anchored(pattern: &str, flavor: Flavor) -> Result<Dfa, String> {
+    let mut p = RegexParser {
+        chars: pattern.chars().collect(),
+        pos: 0,
+        flavor,
+        case_insensitive: false,
+        dot_all: false,
+    };
+    let rust = flavor == Flavor::Rust;
+    // (?i)^abc, flags in front of the anchor hold for the whole pattern
+    while rust && p.at("(?") && p.chars.get(p.pos + 2).is_some_and(|c| FLAGS.contains(*c)) {
+        let (pos, saved) = (p.pos, (p.case_insensitive, p.dot_all));
+        p.pos += 2;
+        if !p.flags()? {
+            p.pos = pos;
+            (p.case_insensitive, p.dot_all) = saved;
+            break;
+        }
+    }
+    // ^a|b$ is (^a)|(b$), each top level alternative is anchored on its own
+    let anything = || Re::Repeat(Box::new(Re::Class(UNIVERSE.to_vec())), 0, None);
+    let mut alts = Vec::new();
+    loop {
+        let anchored_start = p.eat('^') || (rust && p.eat_str("\\A"));
+        let mut re = p.sequence()?;
+        if !anchored_start {
+            re = Re::Seq(vec![anything(), re]);
+        }
+        if !(p.eat('$') || (rust && p.eat_str("\\z"))) {
+            re = Re::Seq(vec![re, anything()]);
+        }
+        alts.push(re);
+        if !p.eat('|') {
+            break;
+        }
+    }
+    if p.pos < p.chars.len() {
+        return Err(format!(
+            "Unexpected {:?}, anchors are only supported at the ends",
+            p.chars[p.pos]
+        ));
+    }
+    let re = Re::Alt(alts);
+
+    let mut nfa = Nfa::default();
+    let (start, end) = nfa.build(&re);
+    nfa.to_dfa(start, end)
+}
+
+// A right-linear grammar for a .regex file holding one pattern in the regex crate's syntax,
+// the document has to match it as a whole unless the pattern leaves an end open
+pub fn to_rules(text: &str) -> Result<Vec<Rule>, String> {
+    let pattern = text.strip_suffix('\n').unwrap_or(text);
+    let pattern = pattern.strip_suffix('\r').unwrap_or(pattern);
+    let dfa = compile(pattern, Flavor::Rust)?;
+    let names: Vec<String> = (0..dfa.states.len()).map(|i| format!("q{}", i)).collect();
+    let raw = |&(lo, hi): &(char, char)| {
+        if lo == hi {
+            Expr::Str(lo.to_string())
+        } else {
+            Expr::Range(lo.to_string(), hi.to_string())
+        }
+    };
+    let mut rules = vec![Rule {
+        name: "root".to_string(),
+        ty: RuleType::Normal,
+        expr: Expr::Seq(
+            Box::new(Expr::Ident("SOI".to_string())),
+            Box::new(Expr::Ident(names[0].clone())),
+        ),
+    }];
+    // A large set becomes a rule of its own, shared by every state that takes it
+    let mut classes: HashMap<Vec<(char, char)>, String> = HashMap::new();
+    let mut class_rules = Vec::new();
+    let chars = |intervals: &[(char, char)]| {
+        let exprs: Vec<Expr> = intervals.iter().map(raw).collect();
+        if exprs.len() <= CLASS_CHUNK {
+            return exprs;
+        }
+        let len = classes.len();
+        let name = classes.entry(intervals.to_vec()).or_insert_with(|| {
+            let name = format!("class{}", len);
+            chunked(&name, exprs, &mut class_rules);
+            name
+        });
+        vec![Expr::Ident(name.clone())]
+    };
+    let end = Expr::Ident("EOI".to_string());
+    rules.extend(dfa.rules(&names, RuleType::Normal, chars, &end));
+    rules.extend(class_rules);
+    Ok(rules)
+}
+
+// A rule named name for the choice of exprs, through levels of helper rules when there are
+// more than CLASS_CHUNK
+fn chunked(name: &str, mut exprs: Vec<Expr>, rules: &mut Vec<Rule>) {
+    let mut level = 0;
+    while exprs.len() > CLASS_CHUNK {
+        let chunks: Vec<Vec<Expr>> = exprs.chunks(CLASS_CHUNK).map(<[Expr]>::to_vec).collect();
+        exprs = Vec::new();
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            let part = format!("{}_{}_{}", name, level, i);
+            rules.push(Rule {
+                name: part.clone(),
+                ty: RuleType::Normal,
+                expr: choice(chunk),
+            });
+            exprs.push(Expr::Ident(part));
+        }
+        level += 1;
+    }
+    rules.push(Rule {
+        name: name.to_string(),
+        ty: RuleType::Normal,
+        expr: choice(exprs),
+    });
+}
+
+enum Re {
+    // Sorted, disjoint intervals
+    Class(Vec<(u32, u32)>),
+    Seq(Vec<Re>),
+    Alt(Vec<Re>),
+    Repeat(Box<Re>, u32, Option<u32>),
+}
+
+fn normalize(mut intervals: Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+    intervals.sort();
+    let mut merged: Vec<(u32, u32)> = Vec::new();
+    for (lo, hi) in intervals {
+        match merged.last_mut() {
+            Some(last) if lo <= last.1.saturating_add(1) => last.1 = last.1.max(hi),
+            _ => merged.push((lo, hi)),
+        }
+    }
+    merged
+}
+
+fn complement(intervals: &[(u32, u32)]) -> Vec<(u32, u32)> {
+    let mut out = Vec::new();
+    for (ulo, uhi) in UNIVERSE {
+        let mut next = ulo;
+        for &(lo, hi) in intervals {
+            if hi < next || lo > uhi {
+                continue;
+            }
+            if lo > next {
+                out.push((next, lo - 1));
+            }
+            next = next.max(hi + 1);
+        }
+        if next <= uhi {
+            out.push((next, uhi));
+        }
+    }
+    out
+}
+
+fn intersect(a: &[(u32, u32)], b: &[(u32, u32)]) -> Vec<(u32, u32)> {
+    let mut outside = complement(a);
+    outside.extend(complement(b));
+    complement(&normalize(outside))
+}
+
+// Adds the other case of every char for (?i), where it is a single char
+fn fold(intervals: &[(u32, u32)]) -> Vec<(u32, u32)> {
+    let mut out = intervals.to_vec();
+    for &(lo, hi) in intervals {
+        for c in (lo..=hi).filter_map(char::from_u32) {
+            let cases: [Vec<char>; 2] = [c.to_lowercase().collect(), c.to_uppercase().collect()];
+            for other in cases {
+                if other.len() == 1 && other[0] != c {
+                    out.push((other[0] as u32, other[0] as u32));
+                }
+            }
+        }
+    }
+    normalize(out)
+}
+
+// Intervals of the chars in any of the Unicode properties, named loosely like the regex crate
+// does so Lu, Uppercase_Letter and uppercaseletter are the same
+fn unicode(names: &[&str]) -> Option<Vec<(u32, u32)>> {
+    let loose = |name: &str| {
+        name.chars()
+            .filter(|c| !matches!(c, '_' | '-' | ' '))
+            .collect::<String>()
+            .to_lowercase()
+    };
+    let mut properties = Vec::new();
+    for name in names {
+        let name = CATEGORIES
+            .iter()
+            .find(|(short, _)| loose(short) == loose(name))
+            .map_or(*name, |(_, long)| long);
+        let known =
+            pest::unicode::unicode_property_names().find(|known| loose(known) == loose(name))?;
+        properties.push(pest::unicode::by_name(known)?);
+    }
+    let mut intervals: Vec<(u32, u32)> = Vec::new();
+    for c in (0..=char::MAX as u32).filter_map(char::from_u32) {
+        if !properties.iter().any(|property| property(c)) {
+            continue;
+        }
+        match intervals.last_mut() {
+            Some((_, hi)) if *hi + 1 == c as u32 => *hi = c as u32,
+            _ => intervals.push((c as u32, c as u32)),
+        }
+    }
+    Some(intervals)
+}
+
+struct RegexParser {
+    chars: Vec<char>,
+    pos: usize,
+    flavor: Flavor,
+    // (?i) and (?s), they hold to the end of the group they are set in
+    case_insensitive: bool,
+    dot_all: bool,
+}
+
+impl RegexParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn at(&self, s: &str) -> bool {
+        s.chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+    }
+
+    fn eat_str(&mut self, s: &str) -> bool {
+        let at = self.at(s);
+        if at {
+            self.pos += s.chars().count();
+        }
+        at
+    }
+
+    fn alternation(&mut self) -> Result<Re, String> {
+        let mut alts = vec![self.sequence()?];
+        while self.eat('|') {
+            alts.push(self.sequence()?);
+        }
+        Ok(if alts.len() == 1 {
+            alts.pop().unwrap()
+        } else {
+            Re::Alt(alts)
+        })
+    }
+
+    fn sequence(&mut self) -> Result<Re, String> {
+        let mut items = Vec::new();
+        while let Some(c) = self.peek() {
+            let at_end = |len| matches!(self.chars.get(self.pos + len), None | Some('|'));
+            let end_anchor = (c == '$' && at_end(1))
+                || (self.flavor == Flavor::Rust && self.at("\\z") && at_end(2));
+            if c == '|' || c == ')' || end_anchor {
+                break;
+            }
+            let atom = self.atom()?;
+            items.push(self.quantified(atom)?);
+        }
+        Ok(Re::Seq(items))
+    }
+
+    fn quantified(&mut self, mut atom: Re) -> Result<Re, String> {
+        loop {
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') if self.bounds().is_some() => self.bounds().unwrap(),
+                _ => return Ok(atom),
+            };
+            if self.peek() == Some('{') {
+                self.pos = self.chars[self.pos..]
+                    .iter()
+                    .position(|c| *c == '}')
+                    .unwrap()
+                    + self.pos;
+            }
+            self.pos += 1;
+            // Lazy quantifiers match the same strings
+            self.eat('?');
+            if max.is_some_and(|max| max < min) || min.max(max.unwrap_or(0)) > MAX_REPEAT {
+                return Err(format!(
+                    "Repetition {{{},{}}} is not supported, the bound is at most {}",
+                    min,
+                    max.map_or(String::new(), |m| m.to_string()),
+                    MAX_REPEAT
+                ));
+            }
+            atom = Re::Repeat(Box::new(atom), min, max);
+        }
+    }
+
+    // {n}, {n,} or {n,m} at the current position, a { that isn't one is a literal
+    fn bounds(&self) -> Option<(u32, Option<u32>)> {
+        let rest: String = self.chars[self.pos + 1..].iter().collect();
+        let body = &rest[..rest.find('}')?];
+        let (min, max) = match body.split_once(',') {
+            Some((min, "")) => (min.parse().ok()?, None),
+            Some((min, max)) => (min.parse().ok()?, Some(max.parse().ok()?)),
+            None => (body.parse().ok()?, Some(body.parse().ok()?)),
+        };
+        Some((min, max))
+    }
+
+    // The flags of (?is-U) or (?i:, after the (?. True when the group is only flags
+    fn flags(&mut self) -> Result<bool, String> {
+        let mut on = true;
+        loop {
+            let c = self.peek().ok_or("Unclosed group")?;
+            self.pos += 1;
+            match c {
+                'i' => self.case_insensitive = on,
+                's' => self.dot_all = on,
+                // Greediness doesn't change which strings match
+                'U' => {}
+                'u' if on => {}
+                '-' => on = false,
+                ':' => return Ok(false),
+                ')' => return Ok(true),
+                'm' | 'x' | 'R' | 'u' => return Err(format!("Flag {} is not supported", c)),
+                c => return Err(format!("Unknown flag {}", c)),
+            }
+        }
+    }
+
+    fn atom(&mut self) -> Result<Re, String> {
+        let c = self.peek().ok_or("Unexpected end of pattern")?;
+        self.pos += 1;
+        let rust = self.flavor == Flavor::Rust;
+        let class = match c {
+            '(' => {
+                let saved = (self.case_insensitive, self.dot_all);
+                if self.eat('?') {
+                    if self.eat(':') {
+                    } else if rust && self.peek().is_some_and(|c| FLAGS.contains(c)) {
+                        if self.flags()? {
+                            return Ok(Re::Seq(Vec::new()));
+                        }
+                    } else if ((rust && self.eat('P')) || self.peek() == Some('<'))
+                        && !matches!(self.chars.get(self.pos + 1), Some('=' | '!'))
+                    {
+                        // A named group
+                        while self.peek().is_some_and(|c| c != '>') {
+                            self.pos += 1;
+                        }
+                        self.pos += 1;
+                    } else {
+                        return Err("Lookaround is not supported".to_string());
+                    }
+                }
+                let re = self.alternation()?;
+                if !self.eat(')') {
+                    return Err("Unclosed group".to_string());
+                }
+                (self.case_insensitive, self.dot_all) = saved;
+                return Ok(re);
+            }
+            '[' => return self.class().map(Re::Class),
+            '.' if !rust => complement(&[(0x0A, 0x0A), (0x0D, 0x0D), (0x2028, 0x2029)]),
+            '.' if self.dot_all => UNIVERSE.to_vec(),
+            '.' => complement(&[(0x0A, 0x0A)]),
+            '\\' => self.escape(false)?,
+            '*' | '+' | '?' => return Err(format!("Nothing to repeat before {}", c)),
+            '^' | '$' => return Err("Anchors are only supported at the ends".to_string()),
+            c => vec![(c as u32, c as u32)],
+        };
+        Ok(Re::Class(if self.case_insensitive {
+            fold(&class)
+        } else {
+            class
+        }))
+    }
+
+    // After a \, the class it stands for
+    fn escape(&mut self, in_class: bool) -> Result<Vec<(u32, u32)>, String> {
+        let c = self.peek().ok_or("Dangling \\")?;
+        self.pos += 1;
+        let single = |c: char| vec![(c as u32, c as u32)];
+        if self.flavor == Flavor::Rust {
+            // The regex crate's classes are Unicode aware
+            let perl: Option<&[&str]> = match c.to_ascii_lowercase() {
+                'd' => Some(&["Nd"]),
+                's' => Some(&["White_Space"]),
+                'w' => Some(&["Alphabetic", "M", "Nd", "Pc", "Join_Control"]),
+                _ => None,
+            };
+            if let Some(names) = perl {
+                let set = unicode(names).unwrap();
+                return Ok(if c.is_ascii_uppercase() {
+                    complement(&set)
+                } else {
+                    set
+                });
+            }
+            match c {
+                'p' | 'P' => {
+                    let name: String = if self.eat('{') {
+                        let name = self.chars[self.pos..].iter().take_while(|c| **c != '}');
+                        let name: String = name.collect();
+                        self.pos += name.chars().count();
+                        if !self.eat('}') {
+                            return Err(format!("Unclosed \\{}{{", c));
+                        }
+                        name
+                    } else {
+                        let name = self.peek().ok_or(format!("Dangling \\{}", c))?;
+                        self.pos += 1;
+                        name.to_string()
+                    };
+                    // \p{sc=Greek} and \p{gc:Lu} name the value
+                    let value = name.rsplit(['=', ':']).next().unwrap();
+                    let set = unicode(&[value]).ok_or(format!("Unknown property {}", name))?;
+                    return Ok(if c == 'P' { complement(&set) } else { set });
+                }
+                'x' | 'u' | 'U' => return self.code_point(c),
+                'a' => return Ok(single('\u{7}')),
+                'A' | 'z' => return Err("Anchors are only supported at the ends".to_string()),
+                '<' | '>' => return Err("Word boundaries are not supported".to_string()),
+                _ => {}
+            }
+        }
+        let digit = vec![(0x30, 0x39)];
+        let word = vec![(0x30, 0x39), (0x41, 0x5A), (0x5F, 0x5F), (0x61, 0x7A)];
+        let space = normalize(vec![
+            (0x09, 0x0D),
+            (0x20, 0x20),
+            (0xA0, 0xA0),
+            (0x1680, 0x1680),
+            (0x2000, 0x200A),
+            (0x2028, 0x2029),
+            (0x202F, 0x202F),
+            (0x205F, 0x205F),
+            (0x3000, 0x3000),
+            (0xFEFF, 0xFEFF),
+        ]);
+        let ecma = self.flavor == Flavor::Ecma;
+        Ok(match c {
+            'd' => digit,
+            'D' => complement(&digit),
+            'w' => word,
+            'W' => complement(&word),
+            's' => space,
+            'S' => complement(&space),
+            'n' => single('\n'),
+            'r' => single('\r'),
+            't' => single('\t'),
+            'f' => single('\u{c}'),
+            'v' => single('\u{b}'),
+            '0' if ecma => single('\0'),
+            'b' if in_class && ecma => single('\u{8}'),
+            'x' | 'u' => self.code_point(c)?,
+            'b' | 'B' => return Err("Word boundaries are not supported".to_string()),
+            c if c.is_ascii_digit() => {
+                return Err("Backreferences are not supported".to_string());
+            }
+            c if c.is_ascii_alphanumeric() => return Err(format!("Unknown escape \\{}", c)),
+            c => single(c),
+        })
+    }
+
+    // \x7F, \u007F or \U0010FFFF after the letter, the regex crate also takes \x{1F600}
+    fn code_point(&mut self, c: char) -> Result<Vec<(u32, u32)>, String> {
+        let braced = self.flavor == Flavor::Rust && self.eat('{');
+        let len = match c {
+            _ if braced => self.chars[self.pos..]
+                .iter()
+                .take_while(|c| **c != '}')
+                .count(),
+            'x' => 2,
+            'u' => 4,
+            _ => 8,
+        };
+        let hex: String = self.chars[self.pos..].iter().take(len).collect();
+        self.pos += len;
+        if braced && !self.eat('}') {
+            return Err(format!("Unclosed \\{}{{", c));
+        }
+        let code = u32::from_str_radix(&hex, 16)
+            .ok()
+            .filter(|code| hex.len() == len && *code <= char::MAX as u32)
+            .ok_or(format!("Invalid escape \\{}{}", c, hex))?;
+        Ok(vec![(code, code)])
+    }
+
+    // After a [, up to and including the ]. The regex crate also nests classes, has
+    // [:alpha:] and combines classes with && -- and ~~
+    fn class(&mut self) -> Result<Vec<(u32, u32)>, String> {
+        let negated = self.eat('^');
+        let mut intervals = self.class_items()?;
+        while self.flavor == Flavor::Rust {
+            if self.eat_str("&&") {
+                intervals = intersect(&intervals, &self.class_items()?);
+            } else if self.eat_str("--") {
+                intervals = intersect(&intervals, &complement(&self.class_items()?));
+            } else if self.eat_str("~~") {
+                let other = self.class_items()?;
+                let mut either = intersect(&intervals, &complement(&other));
+                either.extend(intersect(&other, &complement(&intervals)));
+                intervals = normalize(either);
+            } else {
+                break;
+            }
+        }
+        if !self.eat(']') {
+            return Err("Unclosed [".to_string());
+        }
+        if self.case_insensitive {
+            intervals = fold(&intervals);
+        }
+        Ok(if negated {
+            complement(&intervals)
+        } else {
+            intervals
+        })
+    }
+
+    fn class_items(&mut self) -> Result<Vec<(u32, u32)>, String> {
+        let rust = self.flavor == Flavor::Rust;
+        let mut intervals = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.peek().ok_or("Unclosed [")?;
+            let operator = rust && (self.at("&&") || self.at("--") || self.at("~~"));
+            if (c == ']' && !first) || operator {
+                break;
+            }
+            first = false;
+            if rust
+                && self.at("[:")
+                && let Some(ascii) = self.ascii_class()
+            {
+                intervals.extend(ascii);
+                continue;
+            }
+            self.pos += 1;
+            if rust && c == '[' {
+                intervals.extend(self.class()?);
+                continue;
+            }
+            let lo = if c == '\\' {
+                self.escape(true)?
+            } else {
+                vec![(c as u32, c as u32)]
+            };
+            // A range needs single characters on both sides, [a-] ends in a literal -
+            if lo.len() == 1
+                && lo[0].0 == lo[0].1
+                && self.peek() == Some('-')
+                && self
+                    .chars
+                    .get(self.pos + 1)
+                    .is_some_and(|c| *c != ']' && !(rust && *c == '-'))
+            {
+                self.pos += 1;
+                let c = self.peek().unwrap();
+                self.pos += 1;
+                let hi = if c == '\\' {
+                    self.escape(true)?
+                } else {
+                    vec![(c as u32, c as u32)]
+                };
+                if hi.len() != 1 || hi[0].0 != hi[0].1 || hi[0].0 < lo[0].0 {
+                    return Err("Invalid range in [...]".to_string());
+                }
+                intervals.push((lo[0].0, hi[0].0));
+            } else {
+                intervals.extend(lo);
+            }
+        }
+        Ok(normalize(intervals))
+    }
+
+    // [:alpha:] or [:^alpha:] at the current position
+    fn ascii_class(&mut self) -> Option<Vec<(u32, u32)>> {
+        let rest: String = self.chars[self.pos + 2..].iter().collect();
+        let name = &rest[..rest.find(":]")?];
+        let (negated, name) = match name.strip_prefix('^') {
+            Some(name) => (true, name),
+            None => (false, name),
+        };
+        let (_, intervals) = ASCII_CLASSES.iter().find(|(known, _)| *known == name)?;
+        self.pos += name.chars().count() + usize::from(negated) + 4;
+        Some(if negated {
+            complement(intervals)
+        } else {
+            intervals.to_vec()
+        })
+    }
+}
+
+#[derive(Default)]
+struct Nfa {
+    // Per state, epsilon moves and moves on an interval
+    epsilon: Vec<Vec<usize>>,
+    moves: Vec<Vec<((u32, u32), usize)>>,
+}
+
+impl Nfa {
+    fn state(&mut self) -> usize {
+        self.epsilon.push(Vec::new());
+        self.moves.push(Vec::new());
+        self.epsilon.len() - 1
+    }
+
+    // Thompson construction, returns the start and end state
+    fn build(&mut self, re: &Re) -> (usize, usize) {
+        let start = self.state();
+        let end = self.state();
+        match re {
+            Re::Class(intervals) => {
+                // Surrogates in a range like [\uD000-\uE000] are not chars
+                for interval in complement(&complement(intervals)) {
+                    self.moves[start].push((interval, end));
+                }
+            }
+            Re::Seq(items) => {
+                let mut at = start;
+                for item in items {
+                    let (s, e) = self.build(item);
+                    self.epsilon[at].push(s);
+                    at = e;
+                }
+                self.epsilon[at].push(end);
+            }
+            Re::Alt(alts) => {
+                for alt in alts {
+                    let (s, e) = self.build(alt);
+                    self.epsilon[start].push(s);
+                    self.epsilon[e].push(end);
+                }
+            }
+            Re::Repeat(inner, min, max) => {
+                let mut at = start;
+                for _ in 0..*min {
+                    let (s, e) = self.build(inner);
+                    self.epsilon[at].push(s);
+                    at = e;
+                }
+                match max {
+                    None => {
+                        let (s, e) = self.build(inner);
+                        self.epsilon[at].push(s);
+                        self.epsilon[e].push(s);
+                        self.epsilon[e].push(end);
+                    }
+                    Some(max) => {
+                        for _ in *min..*max {
+                            let (s, e) = self.build(inner);
+                            self.epsilon[at].push(s);
+                            self.epsilon[at].push(end);
+                            at = e;
+                        }
+                    }
+                }
+                self.epsilon[at].push(end);
+            }
+        }
+        (start, end)
+    }
+
+    fn closure(&self, states: impl IntoIterator<Item = usize>) -> BTreeSet<usize> {
+        let mut set = BTreeSet::new();
+        let mut stack: Vec<usize> = states.into_iter().collect();
+        while let Some(s) = stack.pop() {
+            if set.insert(s) {
+                stack.extend(&self.epsilon[s]);
+            }
+        }
+        set
+    }
+
+    // Subset construction, splitting the intervals leaving a set of states where they overlap
+    fn to_dfa(&self, start: usize, end: usize) -> Result<Dfa, String> {
+        let mut sets = vec![self.closure([start])];
+        let mut index: HashMap<BTreeSet<usize>, usize> = HashMap::from([(sets[0].clone(), 0)]);
+        let mut states = Vec::new();
+        while states.len() < sets.len() {
+            let set = sets[states.len()].clone();
+            let moves: Vec<((u32, u32), usize)> =
+                set.iter().flat_map(|s| self.moves[*s].clone()).collect();
+            let mut bounds: Vec<u32> = moves
+                .iter()
+                .flat_map(|((lo, hi), _)| [*lo, hi + 1])
+                .collect();
+            bounds.sort();
+            bounds.dedup();
+
+            let mut edges: Vec<((u32, u32), usize)> = Vec::new();
+            for pair in bounds.windows(2) {
+                let (lo, hi) = (pair[0], pair[1] - 1);
+                let targets: Vec<usize> = moves
+                    .iter()
+                    .filter(|((mlo, mhi), _)| *mlo <= lo && hi <= *mhi)
+                    .map(|(_, t)| *t)
+                    .collect();
+                if targets.is_empty() {
+                    continue;
+                }
+                let target = self.closure(targets);
+                let id = match index.get(&target) {
+                    Some(id) => *id,
+                    None => {
+                        if sets.len() == MAX_DFA_STATES {
+                            return Err(format!("More than {} states", MAX_DFA_STATES));
+                        }
+                        sets.push(target.clone());
+                        index.insert(target, sets.len() - 1);
+                        sets.len() - 1
+                    }
+                };
+                match edges.last_mut() {
+                    Some(((_, last_hi), last_id)) if *last_hi + 1 == lo && *last_id == id => {
+                        *last_hi = hi
+                    }
+                    _ => edges.push(((lo, hi), id)),
+                }
+            }
+            states.push(DfaState {
+                accepting: set.contains(&end),
+                edges: edges
+                    .into_iter()
+                    .map(|((lo, hi), id)| {
+                        let c = |code| char::from_u32(code).unwrap();
+                        ((c(lo), c(hi)), id)
+                    })
+                    .collect(),
+            });
+        }
+        prune(states).map(minimize)
+    }
+}
+
+// Drops the states no accepting state can be reached from, a[^\s\S] gets stuck after the a
+fn prune(states: Vec<DfaState>) -> Result<Dfa, String> {
+    let mut live: Vec<bool> = states.iter().map(|state| state.accepting).collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, state) in states.iter().enumerate() {
+            if !live[i] && state.edges.iter().any(|(_, t)| live[*t]) {
+                live[i] = true;
+                changed = true;
+            }
+        }
+    }
+    if !live[0] {
+        return Err("Nothing matches".to_string());
+    }
+    let mut ids = Vec::new();
+    let mut next = 0;
+    for alive in &live {
+        ids.push(next);
+        next += usize::from(*alive);
+    }
+    Ok(Dfa {
+        states: states
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| live[*i])
+            .map(|(_, state)| DfaState {
+                accepting: state.accepting,
+                edges: state
+                    .edges
+                    .into_iter()
+                    .filter(|(_, t)| live[*t])
+                    .map(|(interval, t)| (interval, ids[t]))
+                    .collect(),
+            })
+            .collect(),
+    })
+}
+
+// Merges states no text can tell apart, Moore's partition refinement. Every state is a rule,
+// so this keeps the rule table as small as the language allows
+fn minimize(dfa: Dfa) -> Dfa {
+    // Edges by the block of their target, merged where neighbours end up in the same one
+    let by_block = |edges: &[((char, char), usize)], block: &[usize]| {
+        let mut merged: Vec<((char, char), usize)> = Vec::new();
+        for &((lo, hi), target) in edges {
+            match merged.last_mut() {
+                Some(((_, last_hi), last))
+                    if *last_hi as u32 + 1 == lo as u32 && *last == block[target] =>
+                {
+                    *last_hi = hi
+                }
+                _ => merged.push(((lo, hi), block[target])),
+            }
+        }
+        merged
+    };
+    let mut block: Vec<usize> = dfa
+        .states
+        .iter()
+        .map(|state| usize::from(state.accepting))
+        .collect();
+    let mut count = 0;
+    loop {
+        // Blocks are numbered in order of their first state, so the start stays 0
+        let mut ids = HashMap::new();
+        let next: Vec<usize> = dfa
+            .states
+            .iter()
+            .enumerate()
+            .map(|(i, state)| {
+                let len = ids.len();
+                *ids.entry((block[i], by_block(&state.edges, &block)))
+                    .or_insert(len)
+            })
+            .collect();
+        block = next;
+        if ids.len() == count {
+            break;
+        }
+        count = ids.len();
+    }
+    let mut states: Vec<Option<DfaState>> = (0..count).map(|_| None).collect();
+    for (i, state) in dfa.states.iter().enumerate() {
+        states[block[i]].get_or_insert_with(|| DfaState {
+            accepting: state.accepting,
+            edges: by_block(&state.edges, &block),
+        });
+    }
+    Dfa {
+        states: states.into_iter().map(Option::unwrap).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::GrammarGraph;
+    use std::fs;
+
+    fn matches(dfa: &Dfa, text: &str) -> bool {
+        let mut state = 0;
+        for c in text.chars() {
+            match dfa.states[state]
+                .edges
+                .iter()
+                .find(|((lo, hi), _)| *lo <= c && c <= *hi)
+            {
+                Some((_, next)) => state = *next,
+                None => return false,
+            }
+        }
+        dfa.states[state].accepting
+    }
+
+    #[test]
+    fn test_compile() {
+        let cases: [(&str, &[&str], &[&str]); 7] = [
+            ("^a*a$", &["a", "aaa"], &["", "ab"]),
+            ("b", &["b", "abc"], &["", "ac"]),
+            ("^(?:ab|a)c?$", &["a", "ab", "abc", "ac"], &["b", "abcc"]),
+            (
+                "^[^\"\\d]{2,3}$",
+                &["ab", "x y"],
+                &["a", "a1", "a\"b", "abcd"],
+            ),
+            (
+                "^\\w+@\\w+\\.(com|org)$",
+                &["a@b.com", "x_1@y.org"],
+                &["a@b.net", "@b.com"],
+            ),
+            ("^.$", &["é", "\u{10000}"], &["\n", ""]),
+            ("^\\u00e9|x{2}$", &["é", "xx", "éa"], &["x"]),
+        ];
+        for (pattern, good, bad) in cases {
+            let dfa = compile(pattern, Flavor::Ecma).unwrap();
+            for text in good {
+                assert!(matches(&dfa, text), "{} {:?}", pattern, text);
+            }
+            for text in bad {
+                assert!(!matches(&dfa, text), "{} {:?}", pattern, text);
+            }
+            // Deterministic, and every state leads somewhere
+            for state in &dfa.states {
+                assert!(state.edges.windows(2).all(|w| w[0].0.1 < w[1].0.0));
+                assert!(state.accepting || !state.edges.is_empty());
+            }
+        }
+
+        let states = |pattern| compile(pattern, Flavor::Ecma).unwrap().states.len();
+        assert_eq!(states("^(a[^\\s\\S]|b)$"), 2);
+        // The states after a and after c are merged
+        assert_eq!(states("^(ab|cb)$"), 3);
+        for pattern in [
+            "(a)\\1",
+            "a(?=b)",
+            "\\bword",
+            "a{1,1000}",
+            "a^b",
+            "(a",
+            "[^\\s\\S]",
+        ] {
+            assert!(compile(pattern, Flavor::Ecma).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn test_rust_flavor() {
+        let cases: [(&str, &[&str], &[&str]); 9] = [
+            (r"\A\d{2}\z", &["12", "٣4"], &["1", "a1"]),
+            (r"^\w+$", &["é_1", "ü"], &["a b", "-"]),
+            (r"^\p{Greek}\PL$", &["α1", "Ω "], &["αβ", "a1"]),
+            (r"^[\p{Lu}&&[A-M]]$", &["A", "M"], &["N", "a", "Á"]),
+            (r"^[[:alpha:]--[aeiou]]+$", &["xyz", "B"], &["a", "x1"]),
+            (r"^[a-c~~b-d]$", &["a", "d"], &["b", "c"]),
+            (r"(?i)^ab(?-i:c)$", &["ABc", "abc"], &["AbC"]),
+            (r"^(?s:.)(?P<x>.)$", &["\na", "ab"], &["a\n"]),
+            (r"^\x{1F600}\u00e9\x41$", &["😀éA"], &["😀é"]),
+        ];
+        for (pattern, good, bad) in cases {
+            let dfa = compile(pattern, Flavor::Rust).unwrap();
+            for text in good {
+                assert!(matches(&dfa, text), "{} {:?}", pattern, text);
+            }
+            for text in bad {
+                assert!(!matches(&dfa, text), "{} {:?}", pattern, text);
+            }
+        }
+        for pattern in [r"(?m)^a$", r"\ba", r"\p{Klingon}", r"\x{110000}", r"a\Ab"] {
+            assert!(compile(pattern, Flavor::Rust).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn test_to_rules() {
+        let pattern = fs::read_to_string("grammars/test_regex.regex").unwrap();
+        let rules = to_rules(&pattern).unwrap();
+        let doc = fs::read_to_string("tests/test_docs/test_regex.txt").unwrap();
+        let mut graph = GrammarGraph::new();
+        graph
+            .parse_rules_and_build_graph(rules.clone(), &doc)
+            .unwrap_or_else(|e| panic!("{}", e));
+        // A char and the next state, or EOI, and the rule name
+        assert!(graph.max_rule_size <= 3);
+        for bad in ["", "2024-13-01", "2024-02-01x"] {
+            assert!(
+                GrammarGraph::new()
+                    .parse_rules_and_build_graph(rules.clone(), bad)
+                    .is_err(),
+                "{}",
+                bad
+            );
+        }
+    }
+}
@@ -1,5 +1,8 @@
 pub mod circuit;
 pub mod config;
+pub mod container;
+pub mod frontend;
+pub mod lint;
 pub mod parser;
 pub mod prover;
 pub mod solver;
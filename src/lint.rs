@@ -0,0 +1,512 @@
+use crate::parser::{GrammarGraph, MAX_STACK_SPAN, NP_MAX_LOOKAHEAD};
+use pest::Span;
+use pest::error::{Error, InputLocation, LineColLocation};
+use pest::iterators::Pair;
+use pest_meta::ast::Expr;
+use pest_meta::parser::{self, Rule};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::ops::Range;
+
+// Rows wider than this are reported, every step pays for the widest row in the table
+pub const LINT_MAX_RULE_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    // Compiling or proving with the grammar fails
+    Error,
+    // The grammar works but costs more than it needs to
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub rule: String,
+    pub line: usize,
+    pub col: usize,
+    pub span: Range<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(
+            f,
+            "{}:{}: {}: {}",
+            self.line, self.col, severity, self.message
+        )?;
+        if !self.rule.is_empty() {
+            write!(f, " (rule {})", self.rule)?;
+        }
+        Ok(())
+    }
+}
+
+impl Diagnostic {
+    fn at(severity: Severity, rule: &str, span: Span, message: String) -> Self {
+        let (line, col) = span.start_pos().line_col();
+        Diagnostic {
+            severity,
+            rule: rule.to_string(),
+            line,
+            col,
+            span: span.start()..span.end(),
+            message,
+        }
+    }
+
+    fn from_pest(rule: &str, error: Error<Rule>) -> Self {
+        let span = match error.location {
+            InputLocation::Pos(pos) => pos..pos,
+            InputLocation::Span((start, end)) => start..end,
+        };
+        let (LineColLocation::Pos((line, col)) | LineColLocation::Span((line, col), _)) =
+            error.line_col;
+        Diagnostic {
+            severity: Severity::Error,
+            rule: rule.to_string(),
+            line,
+            col,
+            span,
+            message: error.variant.message().to_string(),
+        }
+    }
+}
+
+// A rule as written in the grammar file
+struct SourceRule<'i> {
+    name: String,
+    name_span: Span<'i>,
+    pair: Pair<'i, Rule>,
+    // Span of every predicate in the rule, in the order iter_top_down visits them
+    predicates: Vec<Span<'i>>,
+}
+
+impl GrammarGraph {
+    // Reports everything compile_grammar can't turn into a sound rule table, and the rules that
+    // make the table wider than it needs to be, without stopping at the first problem
+    pub fn validate(grammar: &str) -> Vec<Diagnostic> {
+        let pairs = match parser::parse(Rule::grammar_rules, grammar) {
+            Ok(pairs) => pairs,
+            Err(e) => return vec![Diagnostic::from_pest("", e)],
+        };
+
+        let mut source_rules: Vec<SourceRule> = pairs
+            .clone()
+            .filter(|pair| pair.as_rule() == Rule::grammar_rule)
+            .filter_map(|pair| {
+                let name = pair.clone().into_inner().next()?;
+                (name.as_rule() == Rule::identifier).then(|| SourceRule {
+                    name: name.as_str().to_string(),
+                    name_span: name.as_span(),
+                    predicates: Vec::new(),
+                    pair,
+                })
+            })
+            .collect();
+        let defined: HashSet<String> = source_rules.iter().map(|rule| rule.name.clone()).collect();
+
+        let mut diagnostics = Vec::new();
+        for rule in &mut source_rules {
+            lint_pairs(rule, &defined, &mut diagnostics);
+        }
+
+        // Left recursion and the other errors pest itself reports
+        let rules = match parser::consume_rules(pairs) {
+            Ok(rules) => rules,
+            Err(errors) => {
+                for error in errors {
+                    let pos = match error.location {
+                        InputLocation::Pos(pos) | InputLocation::Span((pos, _)) => pos,
+                    };
+                    let rule = source_rules
+                        .iter()
+                        .rev()
+                        .find(|rule| rule.pair.as_span().start() <= pos)
+                        .map_or("", |rule| rule.name.as_str());
+                    diagnostics.push(Diagnostic::from_pest(rule, error));
+                }
+                return diagnostics;
+            }
+        };
+        let exprs: HashMap<String, Expr> = rules
+            .iter()
+            .map(|rule| (rule.name.clone(), rule.expr.clone()))
+            .collect();
+
+        for rule in &source_rules {
+            // !XID_START ~ ANY is range checked as a class
+            let classes: Vec<Expr> = exprs[&rule.name]
+                .iter_top_down()
+                .filter(|expr| matches!(expr, Expr::Seq(..)))
+                .flat_map(|expr| {
+                    let items = GrammarGraph::sequence(&expr);
+                    items
+                        .windows(2)
+                        .filter(|pair| GrammarGraph::class_predicate(&pair[0], &pair[1]).is_some())
+                        .map(|pair| pair[0].clone())
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            let predicates = exprs[&rule.name]
+                .iter_top_down()
+                .filter(|expr| matches!(expr, Expr::NegPred(_) | Expr::PosPred(_)));
+            for (expr, span) in predicates.zip(rule.predicates.iter()) {
+                if classes.contains(&expr) {
+                    continue;
+                }
+                if let Some(message) = lint_predicate(&expr, &exprs) {
+                    diagnostics.push(Diagnostic::at(Severity::Error, &rule.name, *span, message));
+                }
+            }
+        }
+
+        // Longer spans only fail once a document pushes one
+        for rule in &source_rules {
+            for expr in exprs[&rule.name].iter_top_down() {
+                if let Expr::Push(inner) = &expr
+                    && span_bound(inner, &exprs, &mut HashSet::new())
+                        .is_none_or(|len| len > MAX_STACK_SPAN)
+                {
+                    diagnostics.push(Diagnostic::at(
+                        Severity::Warning,
+                        &rule.name,
+                        rule.name_span,
+                        format!(
+                            "{} can match more than {} characters, longer spans can't be proven",
+                            expr, MAX_STACK_SPAN
+                        ),
+                    ));
+                }
+            }
+        }
+
+        lint_reachable(&source_rules, &exprs, &mut diagnostics);
+
+        // Row widths only mean something once the table can be built
+        if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+            return diagnostics;
+        }
+        let mut grammar_graph = GrammarGraph::new();
+        if let Err(e) = grammar_graph.compile_grammar(grammar) {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                rule: String::new(),
+                line: 1,
+                col: 1,
+                span: 0..0,
+                message: e,
+            });
+            return diagnostics;
+        }
+        for rule in &source_rules {
+            let width = grammar_graph.rules.get(&rule.name).map_or(0, |rows| {
+                rows.iter()
+                    .filter_map(|row| row.iter().rposition(|name| name == &rule.name))
+                    .map(|pos| pos + 1)
+                    .max()
+                    .unwrap_or(0)
+            });
+            if width > LINT_MAX_RULE_SIZE {
+                diagnostics.push(Diagnostic::at(
+                    Severity::Warning,
+                    &rule.name,
+                    rule.name_span,
+                    format!(
+                        "Rule {} has rows of width {} (max_rule_size is {}), splitting it or --max-arity shrinks every step",
+                        rule.name, width, grammar_graph.max_rule_size
+                    ),
+                ));
+            }
+        }
+
+        diagnostics
+    }
+}
+
+// Checks that only need the source text: unsupported syntax and names that are neither rules
+// nor builtins
+fn lint_pairs(rule: &mut SourceRule, defined: &HashSet<String>, diagnostics: &mut Vec<Diagnostic>) {
+    // The first pair is the name of the rule itself
+    for pair in rule.pair.clone().into_inner().flatten().skip(1) {
+        let span = pair.as_span();
+        let unsupported = |what: &str| {
+            Diagnostic::at(
+                Severity::Error,
+                &rule.name,
+                span,
+                format!("{} is not supported", what),
+            )
+        };
+        match pair.as_rule() {
+            Rule::peek_slice => diagnostics.push(unsupported(pair.as_str())),
+            Rule::_push_literal => diagnostics.push(unsupported("PUSH_LITERAL")),
+            Rule::identifier => match pair.as_str() {
+                "PEEK_ALL" | "POP_ALL" => diagnostics.push(unsupported(pair.as_str())),
+                name if defined.contains(name)
+                    || name == "EOI"
+                    || GrammarGraph::is_terminal(name) => {}
+                name => diagnostics.push(Diagnostic::at(
+                    Severity::Error,
+                    &rule.name,
+                    span,
+                    format!("Unknown rule or builtin {}", name),
+                )),
+            },
+            Rule::term => {
+                let prefixes = pair.into_inner().filter(|p| {
+                    matches!(
+                        p.as_rule(),
+                        Rule::positive_predicate_operator | Rule::negative_predicate_operator
+                    )
+                });
+                for _ in prefixes {
+                    rule.predicates.push(span);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// The circuit checks a predicate against a packed window of the document, so it has to match a
+// finite set of short strings
+fn lint_predicate(expr: &Expr, rules: &HashMap<String, Expr>) -> Option<String> {
+    let (inner, positive) = match expr {
+        Expr::NegPred(inner) => (inner, false),
+        Expr::PosPred(inner) => (inner, true),
+        _ => return None,
+    };
+    let lengths = match lookahead_lengths(inner, rules, &mut HashSet::new()) {
+        Ok(lengths) => lengths,
+        Err(what) => return Some(format!("Lookahead over {} is not supported", what)),
+    };
+    let longest = lengths.last().copied().unwrap_or(0);
+    if positive && lengths.iter().any(|&len| len != 1) {
+        Some(format!(
+            "Positive lookahead {} must match a single character",
+            expr
+        ))
+    } else if longest > NP_MAX_LOOKAHEAD {
+        Some(format!(
+            "Negative lookahead {} matches {} characters, at most {} are supported",
+            expr, longest, NP_MAX_LOOKAHEAD
+        ))
+    } else {
+        None
+    }
+}
+
+// Lengths of the strings a lookahead can match, or the part that isn't a finite set of strings
+fn lookahead_lengths(
+    expr: &Expr,
+    rules: &HashMap<String, Expr>,
+    visited: &mut HashSet<String>,
+) -> Result<BTreeSet<usize>, String> {
+    match expr {
+        Expr::Str(s) | Expr::Insens(s) => Ok(BTreeSet::from([s.chars().count()])),
+        Expr::Seq(lhs, rhs) => {
+            let lhs = lookahead_lengths(lhs, rules, visited)?;
+            let rhs = lookahead_lengths(rhs, rules, visited)?;
+            Ok(lhs
+                .iter()
+                .flat_map(|l| rhs.iter().map(move |r| l + r))
+                .collect())
+        }
+        Expr::Choice(lhs, rhs) => {
+            let mut lengths = lookahead_lengths(lhs, rules, visited)?;
+            lengths.extend(lookahead_lengths(rhs, rules, visited)?);
+            Ok(lengths)
+        }
+        Expr::Ident(name) if rules.contains_key(name) => {
+            if !visited.insert(name.clone()) {
+                return Err(format!("recursive rule {}", name));
+            }
+            let lengths = lookahead_lengths(&rules[name], rules, visited);
+            visited.remove(name);
+            lengths
+        }
+        Expr::Ident(name) if name == "NEWLINE" => Ok(BTreeSet::from([1, 2])),
+        Expr::Ident(name) if name.starts_with("ASCII") => Ok(BTreeSet::from([1])),
+        Expr::Ident(name) if pest::unicode::by_name(name).is_some() => {
+            Err(format!("Unicode property {}", name))
+        }
+        _ => Err(expr.to_string()),
+    }
+}
+
+// Most characters an expression can match, None if there is no bound
+fn span_bound(
+    expr: &Expr,
+    rules: &HashMap<String, Expr>,
+    visited: &mut HashSet<String>,
+) -> Option<usize> {
+    match expr {
+        Expr::Str(s) | Expr::Insens(s) => Some(s.chars().count()),
+        Expr::Range(..) => Some(1),
+        Expr::PosPred(_) | Expr::NegPred(_) => Some(0),
+        Expr::Seq(lhs, rhs) => {
+            Some(span_bound(lhs, rules, visited)? + span_bound(rhs, rules, visited)?)
+        }
+        Expr::Choice(lhs, rhs) => {
+            Some(span_bound(lhs, rules, visited)?.max(span_bound(rhs, rules, visited)?))
+        }
+        Expr::Opt(inner) | Expr::Push(inner) => span_bound(inner, rules, visited),
+        Expr::RepExact(inner, n) | Expr::RepMax(inner, n) | Expr::RepMinMax(inner, _, n) => {
+            Some(span_bound(inner, rules, visited)? * *n as usize)
+        }
+        Expr::Ident(name) if rules.contains_key(name) => {
+            if !visited.insert(name.clone()) {
+                return None;
+            }
+            let bound = span_bound(&rules[name], rules, visited);
+            visited.remove(name);
+            bound
+        }
+        Expr::Ident(name) => match name.as_str() {
+            "SOI" | "EOI" | "DROP" => Some(0),
+            "NEWLINE" => Some(2),
+            "PEEK" | "POP" | "PEEK_ALL" | "POP_ALL" => None,
+            _ => Some(1),
+        },
+        _ => None,
+    }
+}
+
+// Rules root can't reach never get a row in the table
+fn lint_reachable(
+    source_rules: &[SourceRule],
+    rules: &HashMap<String, Expr>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    if !rules.contains_key("root") {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            rule: String::new(),
+            line: 1,
+            col: 1,
+            span: 0..0,
+            message: "The grammar has no root rule".to_string(),
+        });
+        return;
+    }
+
+    let mut reached: HashSet<&str> = HashSet::from(["root", "WHITESPACE", "COMMENT"]);
+    let mut queue: VecDeque<&str> = reached.iter().copied().collect();
+    while let Some(name) = queue.pop_front() {
+        let Some(expr) = rules.get(name) else {
+            continue;
+        };
+        for sub in expr.iter_top_down() {
+            if let Expr::Ident(ident) = sub
+                && let Some((key, _)) = rules.get_key_value(&ident)
+                && reached.insert(key)
+            {
+                queue.push_back(key);
+            }
+        }
+    }
+
+    for rule in source_rules {
+        if !reached.contains(rule.name.as_str()) {
+            diagnostics.push(Diagnostic::at(
+                Severity::Warning,
+                &rule.name,
+                rule.name_span,
+                format!("Rule {} is unreachable from root", rule.name),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn errors(grammar: &str) -> Vec<Diagnostic> {
+        GrammarGraph::validate(grammar)
+            .into_iter()
+            .filter(|d| d.severity == Severity::Error)
+            .collect()
+    }
+
+    #[test]
+    fn test_sample_grammars() {
+        for file in fs::read_dir("grammars").unwrap() {
+            let path = file.unwrap().path();
+            if path.extension().is_none_or(|ext| ext != "pest") {
+                continue;
+            }
+            let grammar = fs::read_to_string(&path).unwrap();
+            assert_eq!(errors(&grammar), vec![], "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn test_report_spans() {
+        let grammar = "root = { SOI ~ a ~ ASCII_DIGT ~ PEEK[1..] ~ EOI }\n\
+                       a = { (!(\"x\"+) ~ ANY)* ~ &\"ab\" ~ ANY }\n\
+                       unused = { \"u\" }\n";
+        let diagnostics = GrammarGraph::validate(grammar);
+        let found: Vec<(Severity, &str, usize, usize)> = diagnostics
+            .iter()
+            .map(|d| (d.severity, d.rule.as_str(), d.line, d.col))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (Severity::Error, "root", 1, 20),
+                (Severity::Error, "root", 1, 33),
+                (Severity::Error, "a", 2, 8),
+                (Severity::Error, "a", 2, 26),
+                (Severity::Warning, "unused", 3, 1),
+            ]
+        );
+        assert_eq!(&grammar[diagnostics[0].span.clone()], "ASCII_DIGT");
+        assert!(diagnostics[2].message.contains("not supported"));
+
+        let left = errors("root = { a }\na = { a ~ \"x\" | \"y\" }\n");
+        assert_eq!(left.len(), 1);
+        assert_eq!((left[0].rule.as_str(), left[0].line), ("a", 2));
+
+        assert_eq!(errors("a = { \"x\" }").len(), 1);
+
+        // A Unicode predicate is only checked as a class right before ANY
+        assert!(errors("root = { SOI ~ (!WHITE_SPACE ~ ANY)+ ~ EOI }").is_empty());
+        assert_eq!(
+            errors("root = { SOI ~ !WHITE_SPACE ~ \"x\" ~ EOI }").len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_long_push() {
+        let warnings = |grammar: &str| {
+            GrammarGraph::validate(grammar)
+                .into_iter()
+                .filter(|d| d.message.contains("can't be proven"))
+                .count()
+        };
+        assert_eq!(warnings("root = { PUSH(ASCII_DIGIT{1,14}) ~ POP }"), 0);
+        assert_eq!(warnings("root = { PUSH(ASCII_DIGIT{1,15}) ~ POP }"), 1);
+        assert_eq!(
+            warnings("root = { PUSH(name) ~ POP }\nname = { ASCII_ALPHA+ }"),
+            1
+        );
+    }
+
+    #[test]
+    fn test_wide_rows() {
+        let grammar = format!("root = {{ \"{}\" }}", "x".repeat(LINT_MAX_RULE_SIZE));
+        let diagnostics = GrammarGraph::validate(&grammar);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(diagnostics[0].rule, "root");
+    }
+}
@@ -2,23 +2,54 @@
 
 mod parser;
 use anyhow::Result;
-use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
 use clap::Parser;
+use coral::frontend::lint;
+use coral::lint::Severity;
 use coral::parser::GrammarGraph;
-use coral::verifier::{self, VerifierDocCommit};
+use coral::solver::{TreeSizeClass, grammar_digest};
+use coral::verifier;
 use coral::{
     config::*,
+    container::*,
     prover::{self, *},
     util::*,
     verifier::verify,
 };
-use std::fs;
 
 #[cfg(feature = "metrics")]
 use metrics::metrics::{log, log::Component};
+
+// Reads a container and checks it was made for this grammar and SRS, exits with the
+// container's error code otherwise
+fn read_checked<T>(
+    path: &str,
+    kind: ContainerKind,
+    digest: AF,
+    srs: &Srs,
+    open: impl FnOnce(&Container) -> Result<T, ContainerError>,
+) -> T {
+    Container::read(path, kind)
+        .and_then(|c| c.header.check(digest, srs).and_then(|_| open(&c)))
+        .unwrap_or_else(|e| {
+            eprintln!("Unable to use {}: {}", path, e);
+            std::process::exit(e.exit_code());
+        })
+}
+
 fn main() -> Result<()> {
     let opt = Options::parse();
 
+    if opt.lint {
+        let diagnostics = lint(&opt.grammar).expect("Failed to read grammar file");
+        for diagnostic in &diagnostics {
+            eprintln!("{}:{}", opt.grammar, diagnostic);
+        }
+        if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     let grammar_path = opt.grammar;
     let input_text_path = opt.doc;
     let batch_size = opt.batch_size;
@@ -26,6 +57,66 @@ fn main() -> Result<()> {
     let mut opt_grammar_graph: Option<GrammarGraph> = None;
     let mut opt_doc: Option<Vec<char>> = None;
 
+    let srs = Srs::open(
+        &opt.srs,
+        opt.srs_sha256.as_deref(),
+        opt.allow_unverified_srs,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("Unable to use SRS {}: {}", opt.srs, e);
+        //A hash problem is an SRS mismatch, anything else an unreadable file
+        std::process::exit(if e.kind() == std::io::ErrorKind::InvalidData {
+            10
+        } else {
+            11
+        });
+    });
+
+    if opt.setup {
+        let params_path = opt.params.clone().unwrap_or("coral.params".to_string());
+
+        //Tree bucket is public, either given directly or sized from a document
+        let (grammar_graph, tree_bucket) = match opt.tree_bucket {
+            TreeSizeClass::Max(max) => (
+                read_grammar(grammar_path.clone(), opt.max_arity, opt.collapse_chains),
+                max,
+            ),
+            tree_size_class => {
+                assert!(
+                    input_text_path.is_some(),
+                    "Setup needs --tree-bucket <USIZE> or a document to size the tree"
+                );
+                let (grammar_graph, _) = read_graph(
+                    grammar_path.clone(),
+                    input_text_path.as_ref().unwrap().clone(),
+                    opt.max_arity,
+                    opt.collapse_chains,
+                );
+                let tree_bucket = tree_size_class
+                    .bucket(grammar_graph.lcrs_tree.node_count())
+                    .unwrap_or_else(|e| {
+                        eprintln!("Setup failed: {}", e);
+                        std::process::exit(1);
+                    });
+                (grammar_graph, tree_bucket)
+            }
+        };
+
+        SetupBundle::generate(&grammar_graph, batch_size, tree_bucket, &srs)
+            .write(&params_path)
+            .expect("Unable to write file");
+    }
+
+    let params = || {
+        opt.params
+            .as_ref()
+            .map(|path| {
+                SetupBundle::read(path)
+                    .map_err(|e| format!("unable to read setup bundle {}: {}", path, e))
+            })
+            .transpose()
+    };
+
     if opt.commit || opt.prove || opt.e2e {
         assert!(
             input_text_path.is_some(),
@@ -35,111 +126,149 @@ fn main() -> Result<()> {
         let (grammar_graph, doc) = read_graph(
             grammar_path.clone(),
             input_text_path.as_ref().unwrap().clone(),
+            opt.max_arity,
+            opt.collapse_chains,
         );
 
         opt_grammar_graph = Some(grammar_graph);
         opt_doc = Some(doc);
     }
 
+    if let Some(doc) = &input_text_path
+        && (opt.max_arity.is_some() || opt.collapse_chains)
+    {
+        // The tree being proven is reused, only the unsplit one is parsed again
+        let (before, _) = read_graph(grammar_path.clone(), doc.clone(), None, false);
+        let parsed;
+        let after = match &opt_grammar_graph {
+            Some(grammar_graph) => grammar_graph,
+            None => {
+                parsed = read_graph(
+                    grammar_path.clone(),
+                    doc.clone(),
+                    opt.max_arity,
+                    opt.collapse_chains,
+                )
+                .0;
+                &parsed
+            }
+        };
+        eprintln!("{}", shape_report(&before, after, batch_size, &srs));
+    }
+
     if opt.e2e || opt.commit {
         #[cfg(feature = "metrics")]
         log::tic(Component::Generator, "doc_commit_params");
 
-        let (ark_ck, ark_vk) = gen_ark_pp(opt_doc.as_ref().unwrap().len());
+        let (ark_ck, _) = gen_ark_pp(
+            opt.max_doc_len.unwrap_or(opt_doc.as_ref().unwrap().len()),
+            &srs,
+        );
 
         #[cfg(feature = "metrics")]
         log::stop(Component::Generator, "doc_commit_params");
 
-        let doc_commit = run_doc_committer(opt_doc.as_ref().unwrap(), &ark_ck);
+        let doc_commit = run_doc_committer(opt_doc.as_ref().unwrap(), &ark_ck, opt.max_doc_len)
+            .unwrap_or_else(|e| {
+                eprintln!("Commit failed: {}", e);
+                std::process::exit(1);
+            });
 
-        let mut prover_compressed_bytes = Vec::new();
-        doc_commit
-            .serialize_compressed(&mut prover_compressed_bytes)
-            .unwrap();
-        fs::write(
-            get_name(opt.cmt_name.clone(), true, true),
-            prover_compressed_bytes,
-        )
-        .expect("Unable to write file");
+        let digest = grammar_digest::<AF>(opt_grammar_graph.as_ref().unwrap());
+        prover_commitment_container(&doc_commit, digest, &srs)
+            .write(&get_name(opt.cmt_name.clone(), true, true))
+            .expect("Unable to write file");
 
         let v_doc_commit = verifier::VerifierDocCommit {
             doc_commit: doc_commit.doc_commit,
-            doc_commit_vk: ark_vk,
         };
-        let mut verifier_compressed_bytes = Vec::new();
-        v_doc_commit
-            .serialize_compressed(&mut verifier_compressed_bytes)
-            .unwrap();
-        fs::write(
-            get_name(opt.cmt_name.clone(), true, false),
-            verifier_compressed_bytes,
-        )
-        .expect("Unable to write file");
+        verifier_commitment_container(&v_doc_commit, digest, &srs)
+            .write(&get_name(opt.cmt_name.clone(), true, false))
+            .expect("Unable to write file");
     }
 
     if opt.e2e || opt.prove {
         // read commitment
-        let prover_cmt_data =
-            fs::read(get_name(opt.cmt_name.clone(), true, true)).expect("Unable to read file");
-
-        let prover_doc_commit: CoralDocCommitment =
-            CoralDocCommitment::deserialize_compressed_unchecked(&*prover_cmt_data).unwrap();
+        let digest = grammar_digest::<AF>(opt_grammar_graph.as_ref().unwrap());
+        let prover_doc_commit = read_checked(
+            &get_name(opt.cmt_name.clone(), true, true),
+            ContainerKind::ProverCommitment,
+            digest,
+            &srs,
+            prover_commitment_from_container,
+        );
 
         #[allow(unused_mut)]
-        let (mut p_i, mut base, mut empty, pp) = prover::setup(
+        let (mut p_i, mut base, _, pp) = prover::setup(
             opt_grammar_graph.as_ref().unwrap(),
             batch_size,
-            prover_doc_commit.blind,
+            prover_doc_commit.blind_roots.clone(),
+            opt.tree_bucket,
+            params().unwrap_or_else(|e| {
+                eprintln!("Setup failed: {}", e);
+                std::process::exit(1);
+            }),
+            &srs,
         )
-        .unwrap();
+        .unwrap_or_else(|e| {
+            eprintln!("Setup failed: {}", e);
+            std::process::exit(1);
+        });
 
         #[cfg(feature = "para")]
-        let prover_output_res = run_para_prover::<AF>(
-            opt_grammar_graph.as_ref().unwrap(),
-            base,
-            &mut p_i,
-            prover_doc_commit,
-            &pp,
-        );
+        let prover_output_res = run_para_prover::<AF>(base, &mut p_i, prover_doc_commit, &pp);
 
         #[cfg(not(feature = "para"))]
-        let prover_output_res = run_prover::<AF>(
-            opt_grammar_graph.as_ref().unwrap(),
-            &mut base,
-            &mut p_i,
-            prover_doc_commit,
-            &pp,
-        );
+        let prover_output_res = run_prover::<AF>(&mut base, &mut p_i, prover_doc_commit, &pp);
 
         assert!(prover_output_res.is_ok());
 
-        let mut prover_output = prover_output_res.unwrap();
-        prover_output.empty = Some(empty);
+        let prover_output = prover_output_res.unwrap();
 
-        let prover_output_data = bincode::serialize(&prover_output).unwrap();
-        fs::write(
-            get_name(opt.proof_name.clone(), false, false),
-            prover_output_data,
-        )
-        .expect("Unable to write file");
+        proof_container(&prover_output, digest, &srs)
+            .write(&get_name(opt.proof_name.clone(), false, false))
+            .expect("Unable to write file");
     }
     if opt.e2e || opt.verify {
-        let data_from_prover =
-            fs::read(get_name(opt.proof_name.clone(), false, false)).expect("Unable to read file");
-        let mut prover_output = bincode::deserialize::<ProverOutput>(&data_from_prover).unwrap();
-
-        let mut empty = prover_output.empty.take().unwrap();
+        let grammar_graph = read_grammar(grammar_path.clone(), opt.max_arity, opt.collapse_chains);
+        let digest = grammar_digest::<AF>(&grammar_graph);
 
-        let v_i = verifier::setup(&mut empty);
+        let mut prover_output = read_checked(
+            &get_name(opt.proof_name.clone(), false, false),
+            ContainerKind::Proof,
+            digest,
+            &srs,
+            proof_from_container,
+        );
 
-        let verifer_doc_commit_data =
-            fs::read(get_name(opt.cmt_name.clone(), true, false)).expect("Unable to read file");
-        let verifier_doc_commit: VerifierDocCommit =
-            VerifierDocCommit::deserialize_compressed_unchecked(&*verifer_doc_commit_data).unwrap();
+        let v_i = verifier::setup(
+            &grammar_graph,
+            prover_output.batch_size,
+            prover_output.tree_bucket,
+            //Unreadable like a commitment or proof file
+            params().unwrap_or_else(|e| {
+                eprintln!("Verification failed: {}", e);
+                std::process::exit(11);
+            }),
+            &srs,
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("Verification failed: {}", e);
+            std::process::exit(e.exit_code());
+        });
 
-        let verifier_output = verify(&mut prover_output, v_i, verifier_doc_commit);
+        let verifier_doc_commit = read_checked(
+            &get_name(opt.cmt_name.clone(), true, false),
+            ContainerKind::VerifierCommitment,
+            digest,
+            &srs,
+            verifier_commitment_from_container,
+        );
 
-        assert!(verifier_output.is_ok());
+        if let Err(e) = verify(&mut prover_output, v_i, verifier_doc_commit) {
+            eprintln!("Verification failed: {}", e);
+            std::process::exit(e.exit_code());
+        }
     }
 
     #[cfg(feature = "metrics")]
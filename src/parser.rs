@@ -13,6 +13,20 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::VecDeque;
 
+// Synthetic last child of every PUSH, POP, PEEK and DROP node, the delimiter stack is updated there
+pub const STACK_END: &str = "stack_END";
+// Chain that re-reads the span matched by POP or PEEK, one ANY per character
+pub const STACK_TAIL: &str = "stack_TAIL";
+
+// Copies of the repeated expression per generated rule for e{n}
+const REP_CHUNK: u32 = 4;
+
+// Longest string a negative predicate can exclude, the circuit packs the window into one element
+pub const NP_MAX_LOOKAHEAD: usize = 7;
+
+// Longest span PUSH, POP, PEEK and DROP can cover, the circuit packs seven characters per limb
+pub const MAX_STACK_SPAN: usize = 14;
+
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct GrammarGraphNode {
@@ -55,6 +69,8 @@ pub struct GrammarGraph {
     pub atom: Vec<String>,
     // NegPred rule names
     pub np_rule_names: HashSet<String>,
+    // Predicate rules from a PosPred, these require the next character instead of excluding it
+    pub pp_rule_names: HashSet<String>,
     // Max rule size
     pub max_rule_size: usize,
     // Unique rule count
@@ -63,6 +79,12 @@ pub struct GrammarGraph {
     pub rule_names: HashMap<String, Expr>,
     // Max np rule size
     pub max_np_rule_size: usize,
+    // Inclusive code point intervals of character class rules, one table row each
+    pub ranges: HashMap<String, Vec<(u32, u32)>>,
+    // Most children a row may have, longer sequences are split into helper rules
+    pub max_arity: Option<usize>,
+    // Single-child derivation chains become one node, see collapse_unary_rows
+    pub collapse_chains: bool,
 }
 
 impl Default for GrammarGraph {
@@ -81,10 +103,14 @@ impl GrammarGraph {
             np: HashMap::new(),
             atom: Vec::new(),
             np_rule_names: HashSet::new(),
+            pp_rule_names: HashSet::new(),
             max_rule_size: 0,
             rule_count: 0,
             rule_names: HashMap::new(),
             max_np_rule_size: 0,
+            ranges: HashMap::new(),
+            max_arity: None,
+            collapse_chains: false,
         }
     }
 
@@ -98,9 +124,49 @@ impl GrammarGraph {
         //Generator - setup
         let vm = self.compile_grammar(grammar)?;
 
+        self.build_graph(&vm, input_text)
+    }
+
+    // Same as parse_text_and_build_graph, for rules lowered from another grammar format
+    pub fn parse_rules_and_build_graph(
+        &mut self,
+        rules: Vec<pest_meta::ast::Rule>,
+        input_text: &str,
+    ) -> Result<(), String> {
+        let vm = self.compile_rules(rules)?;
+
+        self.build_graph(&vm, input_text)
+    }
+
+    fn build_graph(&mut self, vm: &Vm, input_text: &str) -> Result<(), String> {
         //Prover setup
         let pairs = vm.parse("root", input_text).map_err(|e| e.to_string())?;
 
+        // The delimiter stack holds spans of a fixed width and only one scope at a time
+        for pair in pairs.clone().flatten() {
+            if Self::stack_kind(pair.as_rule()) == 0 {
+                continue;
+            }
+            let (line, col) = pair.line_col();
+            let len = pair.as_str().chars().count();
+            if len > MAX_STACK_SPAN {
+                return Err(format!(
+                    "{}:{}: stack span of {} characters, at most {} are supported",
+                    line, col, len, MAX_STACK_SPAN
+                ));
+            }
+            let nested = pair.clone().into_inner().flatten();
+            if nested
+                .into_iter()
+                .any(|p| Self::stack_kind(p.as_rule()) != 0)
+            {
+                return Err(format!(
+                    "{}:{}: PUSH, POP, PEEK and DROP cannot be nested inside a PUSH",
+                    line, col
+                ));
+            }
+        }
+
         // Iterate over rules to build the GrammarGraph nodes
         for pair in pairs {
             self.construct_parse_tree_node(pair, None);
@@ -112,15 +178,126 @@ impl GrammarGraph {
     // Parses the grammar, transforms it via transform_rules, and then optimizes it
     #[allow(dead_code)]
     pub fn compile_grammar(&mut self, grammar: &str) -> Result<Vm, String> {
+        let rules_map = Self::pest_rules(grammar)?;
+
+        self.compile_rules(rules_map)
+    }
+
+    // Rule list of a .pest grammar
+    pub fn pest_rules(grammar: &str) -> Result<Vec<pest_meta::ast::Rule>, String> {
         let pairs = parser::parse(Rule::grammar_rules, grammar).map_err(|e| e.to_string())?;
 
-        let mut rules_map = parser::consume_rules(pairs).map_err(|errors| {
+        parser::consume_rules(pairs).map_err(|errors| {
             errors
                 .into_iter()
                 .map(|error| error.to_string())
                 .collect::<Vec<_>>()
                 .join("\n")
-        })?;
+        })
+    }
+
+    // Builds the rule table from a rule list, whichever format it was written in. Parsing
+    // starts at the rule named root
+    pub fn compile_rules(
+        &mut self,
+        mut rules_map: Vec<pest_meta::ast::Rule>,
+    ) -> Result<Vm, String> {
+        // !XID_START ~ ANY is one character outside a class, range checked like the class
+        let mut class_rules: Vec<pest_meta::ast::Rule> = Vec::new();
+        for rule in rules_map.iter_mut() {
+            rule.expr = rule.expr.clone().map_top_down(|expr| {
+                if !matches!(expr, Seq(..)) {
+                    return expr;
+                }
+                let mut items = Self::sequence(&expr);
+                let mut i = 0;
+                while i + 1 < items.len() {
+                    if let Some(ranges) = Self::class_predicate(&items[i], &items[i + 1]) {
+                        let name = format!("class_{}", items[i]);
+                        if !class_rules.iter().any(|rule| rule.name == name) {
+                            self.ranges.insert(name.clone(), ranges);
+                            class_rules.push(pest_meta::ast::Rule {
+                                name: name.clone(),
+                                ty: RuleType::Atomic,
+                                expr: Seq(
+                                    Box::new(items[i].clone()),
+                                    Box::new(items[i + 1].clone()),
+                                ),
+                            });
+                        }
+                        items.splice(i..i + 2, [Ident(name)]);
+                    }
+                    i += 1;
+                }
+                items
+                    .into_iter()
+                    .reduce(|lhs, rhs| Seq(Box::new(lhs), Box::new(rhs)))
+                    .unwrap()
+            });
+        }
+
+        // Only whole-stack PUSH, POP, PEEK and DROP are checked in the circuit
+        for rule in &rules_map {
+            for expr in rule.expr.iter_top_down() {
+                match expr {
+                    Expr::PeekSlice(..) => {
+                        return Err(format!("{} in rule {} is not supported", expr, rule.name));
+                    }
+                    Expr::Ident(name) if name == "PEEK_ALL" || name == "POP_ALL" => {
+                        return Err(format!("{} in rule {} is not supported", name, rule.name));
+                    }
+                    // Elsewhere a predicate would have to list every character of the class
+                    Expr::NegPred(ref inner) | Expr::PosPred(ref inner)
+                        if inner.iter_top_down().any(|e| {
+                            matches!(e, Expr::Ident(name) if pest::unicode::by_name(&name).is_some())
+                        }) =>
+                    {
+                        return Err(format!(
+                            "Predicate {} in rule {} over a Unicode property must be followed by ANY",
+                            expr, rule.name
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // Trivia nodes are skipped on the rule stack, so an explicit WHITESPACE or COMMENT
+        // is proven as an atomic copy of the rule under another name
+        for implicit in ["WHITESPACE", "COMMENT"] {
+            let Some(body) = rules_map
+                .iter()
+                .find(|rule| rule.name == implicit)
+                .map(|rule| rule.expr.clone())
+            else {
+                continue;
+            };
+            let explicit = format!("explicit_{}", implicit);
+            let mut referenced = false;
+            for rule in rules_map.iter_mut() {
+                rule.expr = rule.expr.clone().map_top_down(|expr| match expr {
+                    Ident(name) if name == implicit => {
+                        referenced = true;
+                        Ident(explicit.clone())
+                    }
+                    expr => expr,
+                });
+            }
+            if referenced {
+                if rules_map.iter().any(|rule| rule.name == explicit) {
+                    return Err(format!("Rule name {} is reserved", explicit));
+                }
+                let expr = body.map_top_down(|expr| match expr {
+                    Ident(name) if name == implicit => Ident(explicit.clone()),
+                    expr => expr,
+                });
+                rules_map.push(pest_meta::ast::Rule {
+                    name: explicit,
+                    ty: RuleType::Atomic,
+                    expr,
+                });
+            }
+        }
 
         // Populate rule_names
         for rule in &rules_map {
@@ -129,9 +306,38 @@ impl GrammarGraph {
 
         // Transform the rules map to separate terminal and non-terminal rules
         self.transform_rules(&mut rules_map);
+        rules_map.append(&mut class_rules);
+        if let Some(arity) = self.max_arity {
+            Self::split_rules(&mut rules_map, arity)?;
+        }
 
         // Call function to create rule table
-        self.create_table_vectors(&mut rules_map);
+        self.create_table_vectors(&mut rules_map)?;
+
+        // A negative predicate reads the next characters of the document
+        for (excluded, _) in self.np.values() {
+            if let Some(s) = excluded
+                .iter()
+                .find(|s| s.chars().count() > NP_MAX_LOOKAHEAD)
+            {
+                return Err(format!(
+                    "Negative lookahead {:?} is longer than {} characters",
+                    s, NP_MAX_LOOKAHEAD
+                ));
+            }
+        }
+
+        // Past the end of the document the lookahead is unchecked, so a positive one only
+        // looks at the single committed character
+        for name in self.pp_rule_names.iter() {
+            let (allowed, _) = &self.np[name];
+            if allowed.is_empty() || allowed.iter().any(|s| s.chars().count() != 1) {
+                return Err(format!(
+                    "Positive lookahead {:?} must match exactly one character",
+                    allowed
+                ));
+            }
+        }
 
         let optimized = optimizer::optimize(rules_map);
         Ok(Vm::new(optimized))
@@ -147,6 +353,10 @@ impl GrammarGraph {
         // Create node using rules in pairs
         let mut temp_child: Option<String> = None;
         let node_type = pair.as_rule().to_string();
+
+        if Self::stack_kind(&node_type) != 0 {
+            return self.construct_stack_scope(pair, parent_index);
+        }
         let value = if pair.clone().into_inner().peek().is_none() {
             if *pair.as_str() != node_type {
                 temp_child = Some(pair.as_str().to_string());
@@ -200,6 +410,56 @@ impl GrammarGraph {
         node_index
     }
 
+    // Builds a PUSH, POP, PEEK or DROP node. The characters under it are the span the
+    // circuit pushes or compares, and the closing stack_END child is where it does so
+    fn construct_stack_scope<R: pest::RuleType + ToString>(
+        &mut self,
+        pair: Pair<'_, R>,
+        parent_index: Option<NodeIndex>,
+    ) -> NodeIndex {
+        let node_type = pair.as_rule().to_string();
+        let node_index = self.add_tree_node(parent_index, &node_type, None);
+
+        match Self::stack_kind(&node_type) {
+            1 => {
+                for inner_pair in pair.into_inner() {
+                    self.construct_parse_tree_node(inner_pair, Some(node_index));
+                }
+            }
+            2 | 3 => {
+                let mut tail_parent = node_index;
+                for c in pair.as_str().chars() {
+                    let tail = self.add_tree_node(Some(tail_parent), STACK_TAIL, None);
+                    let any = self.add_tree_node(Some(tail), "terminal_ANY", None);
+                    self.add_tree_node(Some(any), &c.to_string(), Some(c.to_string()));
+                    tail_parent = tail;
+                }
+            }
+            _ => {}
+        }
+
+        let end = self.add_tree_node(Some(node_index), STACK_END, None);
+        self.add_tree_node(Some(end), "", Some(String::new()));
+
+        node_index
+    }
+
+    fn add_tree_node(
+        &mut self,
+        parent_index: Option<NodeIndex>,
+        node_type: &str,
+        value: Option<String>,
+    ) -> NodeIndex {
+        let node_index = self.graph.add_node(GrammarGraphNode {
+            node_type: node_type.to_string(),
+            value,
+        });
+        if let Some(p_index) = parent_index {
+            self.graph.add_edge(p_index, node_index, ());
+        }
+        node_index
+    }
+
     // Transforms the Parse tree rules to separate terminals and non-terminals
     fn transform_rules(&mut self, rules: &mut Vec<pest_meta::ast::Rule>) {
         let mut new_rules = HashMap::new();
@@ -207,6 +467,15 @@ impl GrammarGraph {
         // Transform each rule directly within the rules vector
         for rule in rules.iter_mut() {
             rule.expr = Self::transform_expr(&rule.expr, &mut new_rules);
+
+            // A silent rule only leaves the tree when all of its text sits in child nodes,
+            // the root and the implicit rules are always kept
+            if matches!(rule.ty, RuleType::Silent)
+                && (matches!(rule.name.as_str(), "root" | "WHITESPACE" | "COMMENT")
+                    || rule.expr.iter_top_down().any(|e| matches!(e, Str(_))))
+            {
+                rule.ty = RuleType::Normal;
+            }
         }
 
         // Append new rules generated during transformation
@@ -217,10 +486,182 @@ impl GrammarGraph {
                 expr,
             });
         }
+
+        let mut literal_rules = HashMap::new();
+        for rule in rules.iter_mut() {
+            rule.expr = Self::wrap_bare_literals(&rule.expr, false, &mut literal_rules);
+        }
+        for (name, expr) in literal_rules {
+            if !rules.iter().any(|rule| rule.name == name) {
+                rules.push(pest_meta::ast::Rule {
+                    ty: pest_meta::ast::RuleType::Normal,
+                    name,
+                    expr,
+                });
+            }
+        }
+    }
+
+    // Whitespace literals stay bare so a WHITESPACE alternative is a single leaf, but
+    // inside a sequence their text would be lost next to the sibling nodes, also when they
+    // are an alternative of a choice in the sequence
+    fn wrap_bare_literals(
+        expr: &pest_meta::ast::Expr,
+        in_seq: bool,
+        literal_rules: &mut HashMap<String, pest_meta::ast::Expr>,
+    ) -> pest_meta::ast::Expr {
+        match expr {
+            Str(s) if in_seq && !s.is_empty() => {
+                literal_rules
+                    .entry(s.clone())
+                    .or_insert_with(|| Str(s.clone()));
+                Ident(s.clone())
+            }
+            Seq(lhs, rhs) => Seq(
+                Box::new(Self::wrap_bare_literals(lhs, true, literal_rules)),
+                Box::new(Self::wrap_bare_literals(rhs, true, literal_rules)),
+            ),
+            Choice(lhs, rhs) => Choice(
+                Box::new(Self::wrap_bare_literals(lhs, in_seq, literal_rules)),
+                Box::new(Self::wrap_bare_literals(rhs, in_seq, literal_rules)),
+            ),
+            Push(inner) => Push(Box::new(Self::wrap_bare_literals(
+                inner,
+                false,
+                literal_rules,
+            ))),
+            _ => expr.clone(),
+        }
+    }
+
+    // Every row is padded to the widest one, so a sequence with more than arity children is
+    // cut into a chain of helper rules: a ~ b ~ c ~ d becomes a ~ b ~ x with x = { c ~ d }
+    // for arity 3. The helpers are real rules, so the parse tree gets their nodes too and
+    // is deeper in exchange for narrower rows
+    fn split_rules(rules: &mut Vec<pest_meta::ast::Rule>, arity: usize) -> Result<(), String> {
+        if arity < 2 {
+            return Err(format!("A row needs room for 2 children, not {}", arity));
+        }
+        let mut names: HashSet<String> = rules.iter().map(|rule| rule.name.clone()).collect();
+        let mut i = 0;
+        while i < rules.len() {
+            let rule = &rules[i];
+            i += 1;
+            // Silent rules are inlined into their parents' rows and the stack rules are
+            // checked by name in the circuit
+            if matches!(rule.ty, RuleType::Silent)
+                || rule.name.starts_with("terminal_")
+                || Self::stack_kind(&rule.name) != 0
+            {
+                continue;
+            }
+            // Same type, so an atomic rule doesn't get whitespace between its parts. pest runs
+            // the trivia atomically whatever their type
+            let ty = match rule.name.as_str() {
+                "WHITESPACE" | "COMMENT" => RuleType::Atomic,
+                _ => rule.ty,
+            };
+            let name = rule.name.clone();
+            let mut helpers = Vec::new();
+            let mut helper = |expr: Expr| {
+                let mut n = 0;
+                while names.contains(&format!("{}_split{}", name, n)) {
+                    n += 1;
+                }
+                let helper_name = format!("{}_split{}", name, n);
+                names.insert(helper_name.clone());
+                helpers.push(pest_meta::ast::Rule {
+                    name: helper_name.clone(),
+                    ty,
+                    expr,
+                });
+                Ident(helper_name)
+            };
+            let expr = Self::split_expr(&rules[i - 1].expr, arity, &mut helper);
+            rules[i - 1].expr = expr;
+            // Helpers are split in turn once the loop gets to them
+            rules.append(&mut helpers);
+        }
+        Ok(())
+    }
+
+    fn split_expr(expr: &Expr, arity: usize, helper: &mut impl FnMut(Expr) -> Expr) -> Expr {
+        match expr {
+            Choice(lhs, rhs) => Choice(
+                Box::new(Self::split_expr(lhs, arity, helper)),
+                Box::new(Self::split_expr(rhs, arity, helper)),
+            ),
+            Seq(..) if Self::row_width(expr) > arity => {
+                let mut units = Vec::new();
+                Self::seq_units(expr, &mut units);
+                // A choice of sequences adds its widest alternative to the row, as a helper
+                // it is a single child
+                let mut units: Vec<Expr> = units
+                    .into_iter()
+                    .map(|unit| match unit {
+                        Choice(..) if Self::row_width(&unit) > 1 => helper(unit),
+                        unit => unit,
+                    })
+                    .collect();
+                // Keep what fits next to one more child, the rest goes to a helper
+                let mut split = 0;
+                let mut width = 0;
+                while split < units.len()
+                    && (split == 0 || width + Self::row_width(&units[split]) < arity)
+                {
+                    width += Self::row_width(&units[split]);
+                    split += 1;
+                }
+                let rest = units.split_off(split);
+                if rest.len() == 1 && Self::row_width(&rest[0]) == 1 {
+                    units.extend(rest);
+                } else if !rest.is_empty() {
+                    units.push(helper(Self::seq_of(rest)));
+                }
+                Self::seq_of(units)
+            }
+            _ => expr.clone(),
+        }
+    }
+
+    // Children an expression adds to each of its rows, at most. Literals in a sequence are
+    // wrapped into rules by now, a bare one has a child per character
+    fn row_width(expr: &Expr) -> usize {
+        match expr {
+            // Replaced by a single special rule
+            Seq(lhs, _) if matches!(**lhs, NegPred(_) | PosPred(_)) => 1,
+            Seq(lhs, rhs) => Self::row_width(lhs) + Self::row_width(rhs),
+            Choice(lhs, rhs) => max(Self::row_width(lhs), Self::row_width(rhs)),
+            Str(s) | Insens(s) => max(s.chars().count(), 1),
+            _ => 1,
+        }
+    }
+
+    // The elements of a sequence, a predicate stays together with everything after it
+    fn seq_units(expr: &Expr, units: &mut Vec<Expr>) {
+        match expr {
+            Seq(lhs, _) if matches!(**lhs, NegPred(_) | PosPred(_)) => units.push(expr.clone()),
+            Seq(lhs, rhs) => {
+                Self::seq_units(lhs, units);
+                Self::seq_units(rhs, units);
+            }
+            _ => units.push(expr.clone()),
+        }
+    }
+
+    fn seq_of(units: Vec<Expr>) -> Expr {
+        units
+            .into_iter()
+            .rev()
+            .reduce(|rhs, lhs| Seq(Box::new(lhs), Box::new(rhs)))
+            .unwrap()
     }
 
     // Modifying create_table_vectors to use the struct's rules
-    fn create_table_vectors(&mut self, transformed_rules: &mut Vec<pest_meta::ast::Rule>) {
+    fn create_table_vectors(
+        &mut self,
+        transformed_rules: &mut Vec<pest_meta::ast::Rule>,
+    ) -> Result<(), String> {
         self.rules.clear(); // Clear existing data if any
         let mut negpred_count = 0;
         let mut special_rules = Vec::new();
@@ -230,23 +671,38 @@ impl GrammarGraph {
             let mut completed_rule = Vec::new();
             let mut rule_deques: Vec<VecDeque<String>> = Vec::new();
 
-            if matches!(rule.ty, RuleType::Atomic) {
+            // pest runs WHITESPACE and COMMENT atomically, so no trivia is skipped inside them
+            if matches!(rule.ty, RuleType::Atomic)
+                || matches!(rule.name.as_str(), "WHITESPACE" | "COMMENT")
+            {
                 // Process the atomic rule
                 rule.ty = RuleType::CompoundAtomic;
                 self.atom.push(rule.name.clone());
             }
 
-            // Passing mutable reference to rule.expr, along with special_rules and negpred_count
-            self.process_expr(
-                &rule.name,
-                &mut rule.expr,
-                VecDeque::new(),
-                &mut rule_deques,
-                &mut special_rules,
-                &mut negpred_count,
-            );
+            // Classes never reach process_expr, their predicate is the range check
+            let class =
+                Self::char_class(&rule.expr).or_else(|| self.ranges.get(&rule.name).cloned());
+            if class.is_none() {
+                // Passing mutable reference to rule.expr, along with special_rules and negpred_count
+                self.process_expr(
+                    &rule.name,
+                    &mut rule.expr,
+                    VecDeque::new(),
+                    &mut rule_deques,
+                    &mut special_rules,
+                    &mut negpred_count,
+                );
+            }
 
-            if rule.name.starts_with("terminal_") {
+            // One row per interval, the bounds live in ranges
+            if let Some(ranges) = class {
+                let variant = vec![String::new(), rule.name.clone()];
+                self.max_rule_size = max(self.max_rule_size, variant.len());
+                self.rules
+                    .insert(rule.name.clone(), vec![variant; ranges.len()]);
+                self.ranges.insert(rule.name.clone(), ranges);
+            } else if rule.name.starts_with("terminal_") {
                 completed_rule.append(&mut Self::expand_terminals(&rule.name));
 
                 for variant in completed_rule.clone() {
@@ -261,6 +717,9 @@ impl GrammarGraph {
             } else {
                 for deque in rule_deques {
                     let mut variant = deque.into_iter().collect::<Vec<String>>();
+                    if Self::stack_kind(&rule.name) != 0 {
+                        variant.insert(0, STACK_END.to_string());
+                    }
                     variant.push(rule.name.clone());
                     self.max_rule_size = max(self.max_rule_size, variant.len());
 
@@ -276,6 +735,32 @@ impl GrammarGraph {
         transformed_rules.append(&mut atomic_rules);
         transformed_rules.append(&mut special_rules);
 
+        let silent: HashSet<String> = transformed_rules
+            .iter()
+            .filter(|rule| matches!(rule.ty, RuleType::Silent))
+            .map(|rule| rule.name.clone())
+            .collect();
+        self.inline_silent_rules(&silent)?;
+
+        // Tree-only rules added under PUSH, POP, PEEK and DROP nodes
+        if self.has_stack() {
+            let stack_rules = [
+                vec![STACK_TAIL, "terminal_ANY", STACK_TAIL],
+                vec!["terminal_ANY", STACK_TAIL],
+                vec!["", STACK_END],
+            ];
+            for variant in stack_rules {
+                let variant: Vec<String> = variant.into_iter().map(String::from).collect();
+                self.max_rule_size = max(self.max_rule_size, variant.len());
+                let name = variant.last().unwrap().clone();
+                self.rules.entry(name).or_default().push(variant);
+            }
+        }
+
+        if self.collapse_chains {
+            self.collapse_unary_rows();
+        }
+
         // Padding to ensure all vectors in rules are of equal length
         for rule_variants in self.rules.values_mut() {
             for variant in rule_variants.iter_mut() {
@@ -285,6 +770,178 @@ impl GrammarGraph {
                 self.rule_count += 1;
             }
         }
+        Ok(())
+    }
+
+    // Silent rules have no node in the tree, so every row that names one is replaced by one
+    // row per expansion of it, keeping each row a derivation the circuit can check
+    fn inline_silent_rules(&mut self, silent: &HashSet<String>) -> Result<(), String> {
+        if silent.is_empty() {
+            return Ok(());
+        }
+
+        let mut expansions: HashMap<String, Vec<Vec<String>>> = HashMap::new();
+        for name in silent {
+            self.expand_silent_rule(name, silent, &mut expansions, &mut Vec::new())?;
+        }
+
+        let mut rules = HashMap::new();
+        for (name, variants) in self.rules.iter() {
+            if silent.contains(name) {
+                continue;
+            }
+            let mut inlined: Vec<Vec<String>> = Vec::new();
+            for variant in variants {
+                let (children, rule_name) = variant.split_at(variant.len() - 1);
+                for mut row in Self::inline_children(children, &expansions) {
+                    if row.is_empty() {
+                        row.push(String::new());
+                    }
+                    row.extend_from_slice(rule_name);
+                    if !inlined.contains(&row) {
+                        inlined.push(row);
+                    }
+                }
+            }
+            rules.insert(name.clone(), inlined);
+        }
+        self.rules = rules;
+
+        self.max_rule_size = self
+            .rules
+            .values()
+            .flatten()
+            .map(|variant| variant.len())
+            .max()
+            .unwrap_or(0);
+        Ok(())
+    }
+
+    // Children sequences a silent rule stands for, with nested silent rules inlined
+    fn expand_silent_rule(
+        &self,
+        name: &str,
+        silent: &HashSet<String>,
+        expansions: &mut HashMap<String, Vec<Vec<String>>>,
+        visiting: &mut Vec<String>,
+    ) -> Result<(), String> {
+        if expansions.contains_key(name) {
+            return Ok(());
+        }
+        if visiting.iter().any(|v| v == name) {
+            return Err(format!(
+                "Silent rule {} derives itself without a node in between",
+                name
+            ));
+        }
+        visiting.push(name.to_string());
+
+        let variants = self.rules.get(name).cloned().unwrap_or_default();
+        for variant in variants.iter() {
+            for child in variant[..variant.len() - 1].iter() {
+                if silent.contains(child) {
+                    self.expand_silent_rule(child, silent, expansions, visiting)?;
+                }
+            }
+        }
+
+        let mut expanded: Vec<Vec<String>> = Vec::new();
+        for variant in variants.iter() {
+            for mut row in Self::inline_children(&variant[..variant.len() - 1], expansions) {
+                row.retain(|child| !child.is_empty());
+                if !expanded.contains(&row) {
+                    expanded.push(row);
+                }
+            }
+        }
+
+        visiting.pop();
+        expansions.insert(name.to_string(), expanded);
+        Ok(())
+    }
+
+    fn inline_children(
+        children: &[String],
+        expansions: &HashMap<String, Vec<Vec<String>>>,
+    ) -> Vec<Vec<String>> {
+        let mut rows: Vec<Vec<String>> = vec![Vec::new()];
+        for child in children {
+            match expansions.get(child) {
+                Some(options) => {
+                    rows = rows
+                        .iter()
+                        .flat_map(|row| {
+                            options.iter().map(move |option| {
+                                let mut row = row.clone();
+                                row.extend_from_slice(option);
+                                row
+                            })
+                        })
+                        .collect();
+                }
+                None => {
+                    for row in rows.iter_mut() {
+                        row.push(child.clone());
+                    }
+                }
+            }
+        }
+        rows
+    }
+
+    // A row with a single child costs a whole step for a node that only names its child, as in
+    // value ⇒ string ⇒ inner ⇒ char ⇒ "x". Such a rule gets every row of the child under its
+    // own name, so the chain is checked as the composite row value ⇒ "x". Each of these is a
+    // derivation of the original grammar, and parse_and_convert_lcrs drops the child nodes
+    fn collapse_unary_rows(&mut self) {
+        loop {
+            let mut composite: HashSet<(String, Vec<String>)> = HashSet::new();
+            for (name, variants) in self.rules.iter() {
+                for variant in variants {
+                    let [child, _] = variant.as_slice() else {
+                        continue;
+                    };
+                    if !self.collapsible(name, child) {
+                        continue;
+                    }
+                    for row in &self.rules[child] {
+                        let mut row = row.clone();
+                        *row.last_mut().unwrap() = name.clone();
+                        if !variants.contains(&row) {
+                            composite.insert((name.clone(), row));
+                        }
+                    }
+                }
+            }
+            // Chains are folded one link per pass until every rule has its whole closure
+            if composite.is_empty() {
+                return;
+            }
+            for (name, row) in composite {
+                self.rules.get_mut(&name).unwrap().push(row);
+            }
+        }
+    }
+
+    // Whether a node whose only child is the given one may take over the child's children.
+    // Rows the circuit reads more than the children of, trivia, predicates, stack operations
+    // and classes, keep their nodes, and single characters may be leaves rather than rules
+    fn collapsible(&self, parent: &str, child: &str) -> bool {
+        let plain = |name: &str| {
+            self.rules.contains_key(name)
+                && name.chars().count() > 1
+                && !matches!(
+                    name,
+                    "WHITESPACE" | "COMMENT" | "terminal_ANY" | STACK_TAIL | STACK_END
+                )
+                && Self::stack_kind(name) == 0
+                && !self.np_rule_names.contains(name)
+                && !self.pp_rule_names.contains(name)
+                && !self.ranges.contains_key(name)
+        };
+        // An atomic child under a non-atomic parent would let trivia in between its children
+        let atomic = |name: &str| self.atom.iter().any(|a| a == name);
+        plain(parent) && plain(child) && (atomic(parent) || !atomic(child))
     }
 
     fn modify_negpred_rule(
@@ -297,7 +954,7 @@ impl GrammarGraph {
         let mut special_rule_name = String::new();
 
         if let Expr::Seq(a, _) = expr
-            && let Expr::NegPred(_) = &**a
+            && let Expr::NegPred(_) | Expr::PosPred(_) = &**a
         {
             special_rule_name = format!("special{}", negpred_count);
             *negpred_count += 1;
@@ -310,8 +967,11 @@ impl GrammarGraph {
 
             // Loop through each rule deque
             for rule in rule_deques.iter_mut() {
-                // Find all occurrences of "NegPred" from left to right
-                while let Some(pos) = rule.iter().position(|r| r.starts_with("NegPred")) {
+                // Find all occurrences of "NegPred" or "PosPred" from left to right
+                while let Some(pos) = rule
+                    .iter()
+                    .position(|r| r.starts_with("NegPred") || r.starts_with("PosPred"))
+                {
                     // Check if there's a previous element to remove
                     if pos > 0 {
                         rule.remove(pos - 1); // Remove the previous element
@@ -326,6 +986,120 @@ impl GrammarGraph {
         special_rule_name
     }
 
+    // Code point intervals of a Unicode property or non-ASCII range rule, these are range
+    // checked in the circuit instead of listing a row per character
+    fn char_class(expr: &Expr) -> Option<Vec<(u32, u32)>> {
+        match expr {
+            Range(start, end) => {
+                let (start, end) = (start.chars().next()?, end.chars().next()?);
+                Some(vec![(start as u32, end as u32)])
+            }
+            // ASCII builtins keep one row per character
+            Ident(name) if !Self::expand_terminals(&format!("terminal_{}", name)).is_empty() => {
+                None
+            }
+            Ident(name) => {
+                let property = pest::unicode::by_name(name)?;
+                let mut ranges: Vec<(u32, u32)> = Vec::new();
+                for c in (0..=char::MAX as u32).filter_map(char::from_u32) {
+                    if !property(c) {
+                        continue;
+                    }
+                    match ranges.last_mut() {
+                        Some((_, hi)) if *hi + 1 == c as u32 => *hi = c as u32,
+                        _ => ranges.push((c as u32, c as u32)),
+                    }
+                }
+                Some(ranges)
+            }
+            _ => None,
+        }
+    }
+
+    // A predicate over a Unicode property followed by ANY, as in !XID_START ~ ANY or
+    // &LETTER ~ ANY, with the intervals of the one character it matches. Predicates over
+    // ASCII only keep their listed lookahead
+    pub fn class_predicate(pred: &Expr, next: &Expr) -> Option<Vec<(u32, u32)>> {
+        let (NegPred(inner) | PosPred(inner)) = pred else {
+            return None;
+        };
+        if !matches!(next, Ident(name) if name == "ANY")
+            || !inner
+                .iter_top_down()
+                .any(|e| matches!(e, Ident(name) if pest::unicode::by_name(&name).is_some()))
+        {
+            return None;
+        }
+
+        let mut intervals = Self::class_intervals(inner)?;
+        intervals.sort_unstable();
+        let mut merged: Vec<(u32, u32)> = Vec::new();
+        for (lo, hi) in intervals {
+            match merged.last_mut() {
+                Some((_, last)) if lo <= *last + 1 => *last = (*last).max(hi),
+                _ => merged.push((lo, hi)),
+            }
+        }
+        if matches!(pred, PosPred(_)) {
+            return Some(merged);
+        }
+        let mut complement = Vec::new();
+        let mut next_lo = 0;
+        for (lo, hi) in merged {
+            if lo > next_lo {
+                complement.push((next_lo, lo - 1));
+            }
+            next_lo = hi + 1;
+        }
+        if next_lo <= char::MAX as u32 {
+            complement.push((next_lo, char::MAX as u32));
+        }
+        Some(complement)
+    }
+
+    // Items of a sequence however it is nested
+    pub fn sequence(expr: &Expr) -> Vec<Expr> {
+        match expr {
+            Seq(lhs, rhs) => {
+                let mut items = Self::sequence(lhs);
+                items.extend(Self::sequence(rhs));
+                items
+            }
+            expr => vec![expr.clone()],
+        }
+    }
+
+    // Intervals of a choice of single characters, ranges and character builtins
+    fn class_intervals(expr: &Expr) -> Option<Vec<(u32, u32)>> {
+        match expr {
+            Choice(lhs, rhs) => {
+                let mut intervals = Self::class_intervals(lhs)?;
+                intervals.extend(Self::class_intervals(rhs)?);
+                Some(intervals)
+            }
+            Str(s) if s.chars().count() == 1 => {
+                let c = s.chars().next()? as u32;
+                Some(vec![(c, c)])
+            }
+            Ident(name) if Self::char_class(expr).is_none() => {
+                let rows = Self::expand_terminals(&format!("terminal_{}", name));
+                let chars: Vec<u32> = rows
+                    .iter()
+                    .map(|row| {
+                        let mut chars = row[0].chars();
+                        match (chars.next(), chars.next()) {
+                            (Some(c), None) => Some(c as u32),
+                            _ => None,
+                        }
+                    })
+                    .collect::<Option<_>>()?;
+                (!chars.is_empty()).then(|| chars.iter().map(|&c| (c, c)).collect())
+            }
+            Range(..) | Ident(_) => Self::char_class(expr),
+            _ => None,
+        }
+    }
+
     // Helper function to handle the expansion of ASCII/ANY rules
     fn expand_terminals(rule_name: &str) -> Vec<Vec<String>> {
         let mut expanded_rules = Vec::new();
@@ -337,7 +1111,7 @@ impl GrammarGraph {
             "terminal_ASCII_BIN_DIGIT" => vec!['0'..='1'],
             "terminal_ASCII_OCT_DIGIT" => vec!['0'..='7'],
             "terminal_ASCII_HEX_DIGIT" => vec!['0'..='9', 'a'..='f', 'A'..='F'],
-            "terminl_ASCII_ALPHA_LOWER" => vec!['a'..='z'],
+            "terminal_ASCII_ALPHA_LOWER" => vec!['a'..='z'],
             "terminal_ASCII_ALPHA_UPPER" => vec!['A'..='Z'],
             "terminal_ASCII_ALPHA" => vec!['a'..='z', 'A'..='Z'],
             "terminal_ASCII_ALPHANUMERIC" => vec!['a'..='z', 'A'..='Z', '0'..='9'],
@@ -347,6 +1121,24 @@ impl GrammarGraph {
             _ => vec![],
         };
 
+        // POP and PEEK re-read the popped span, DROP reads nothing
+        match rule_name {
+            "terminal_POP" | "terminal_PEEK" => {
+                return vec![
+                    vec![
+                        STACK_END.to_string(),
+                        STACK_TAIL.to_string(),
+                        rule_name.to_string(),
+                    ],
+                    vec![STACK_END.to_string(), rule_name.to_string()],
+                ];
+            }
+            "terminal_DROP" => {
+                return vec![vec![STACK_END.to_string(), rule_name.to_string()]];
+            }
+            _ => {}
+        }
+
         if rule_name == "terminal_NEWLINE" {
             // Special handling for NEWLINE since it's not a character range
             return vec![
@@ -440,9 +1232,16 @@ impl GrammarGraph {
                 }
             }
             Expr::Insens(s) => {
-                let mut variants = Vec::new();
-                variants.push(s.clone()); // Original string
-                variants.push(s.to_uppercase()); // Uppercase version
+                // Every casing, the lookahead can match any of them
+                let mut variants = vec![String::new()];
+                for c in s.chars() {
+                    let mut cases = vec![c.to_ascii_lowercase(), c.to_ascii_uppercase()];
+                    cases.dedup();
+                    variants = variants
+                        .iter()
+                        .flat_map(|v| cases.iter().map(move |c| format!("{}{}", v, c)))
+                        .collect();
+                }
                 variants
             }
             _ => Vec::new(), // Handle other cases as needed
@@ -487,11 +1286,13 @@ impl GrammarGraph {
 
                 let mut seq2_string = String::new();
                 let mut has_negpred = false;
+                let mut has_pospred = false;
 
                 {
                     // Limit scope of seq1 and seq2 references
-                    if let Expr::NegPred(first) = &**seq1 {
+                    if let Expr::NegPred(first) | Expr::PosPred(first) = &**seq1 {
                         has_negpred = true;
+                        has_pospred = matches!(&**seq1, Expr::PosPred(_));
 
                         let mut visited = HashSet::new();
                         exclude = self.collect_strings_from_negpred(first.as_ref(), &mut visited);
@@ -503,6 +1304,9 @@ impl GrammarGraph {
                 if has_negpred {
                     let key =
                         self.modify_negpred_rule(expr, special_rules, rule_deques, negpred_count);
+                    if has_pospred {
+                        self.pp_rule_names.insert(key.clone());
+                    }
                     self.np.insert(key, (exclude.clone(), seq2_string.clone()));
 
                     let exclude_size = exclude.len();
@@ -569,6 +1373,16 @@ impl GrammarGraph {
                 new_current_path.push_front(ident_str.clone());
                 rule_deques.push(new_current_path);
             }
+            Expr::Push(inner) => {
+                self.process_expr(
+                    rule_name,
+                    inner,
+                    current_path,
+                    rule_deques,
+                    special_rules,
+                    negpred_count,
+                );
+            }
             Expr::Str(s) => {
                 let mut new_current_path = current_path.clone();
                 new_current_path.push_front(s.clone());
@@ -588,29 +1402,6 @@ impl GrammarGraph {
                 path_with_uppercase.push_front(uppercase_version);
                 rule_deques.push(path_with_uppercase);
             }
-            Expr::RepExact(expr, count) => {
-                let mut new_current_path = current_path.clone();
-                for _ in 0..*count {
-                    if let Expr::Ident(ident) = &**expr {
-                        new_current_path.push_front(ident.clone());
-                    } else {
-                        let mut temp_table: Vec<VecDeque<String>> = vec![];
-                        self.process_expr(
-                            rule_name,
-                            expr,
-                            VecDeque::new(),
-                            &mut temp_table,
-                            special_rules,
-                            negpred_count,
-                        );
-                        for item in temp_table {
-                            let mut extended_path = new_current_path.clone();
-                            extended_path.append(&mut item.clone());
-                            rule_deques.push(extended_path);
-                        }
-                    }
-                }
-            }
             _ => {
                 let mut new_current_path = current_path.clone();
                 new_current_path.push_front(format!("{:?}", expr));
@@ -626,9 +1417,10 @@ impl GrammarGraph {
     ) -> pest_meta::ast::Expr {
         match expr {
             Str(terminal) => {
-                if terminal.len() > 1 {
-                    let lhs = terminal[0..1].to_string();
-                    let rhs = terminal[1..].to_string();
+                if terminal.chars().count() > 1 {
+                    let split = terminal.chars().next().unwrap().len_utf8();
+                    let lhs = terminal[..split].to_string();
+                    let rhs = terminal[split..].to_string();
                     Seq(
                         Box::new(Self::transform_expr(&Str(lhs), new_rules)),
                         Box::new(Self::transform_expr(&Str(rhs), new_rules)),
@@ -671,7 +1463,12 @@ impl GrammarGraph {
             ),
             Range(start, end) => {
                 let range_rule_name = format!("range_{}_{}", start, end);
-                if !new_rules.contains_key(&range_rule_name) {
+                // Beyond ASCII the range is kept whole and range checked
+                if end.chars().any(|c| !c.is_ascii()) {
+                    new_rules
+                        .entry(range_rule_name.clone())
+                        .or_insert_with(|| expr.clone());
+                } else if !new_rules.contains_key(&range_rule_name) {
                     let range_characters = (start.chars().next().unwrap()
                         ..=end.chars().next().unwrap())
                         .map(|c| Str(c.to_string()))
@@ -727,17 +1524,150 @@ impl GrammarGraph {
                             new_rules,
                         )),
                     );
-
-                    new_rules.insert(rep_once_rule_name.clone(), repeated_expr);
+
+                    new_rules.insert(rep_once_rule_name.clone(), repeated_expr);
+                }
+
+                Ident(rep_once_rule_name)
+            }
+            RepExact(inner_expr, count) => {
+                let transformed_inner = Self::transform_expr(inner_expr, new_rules);
+                Self::rep_exact(&transformed_inner, *count, new_rules)
+            }
+            // e{n,} is e{n} followed by e*
+            RepMin(inner_expr, min) => {
+                let transformed_inner = Self::transform_expr(inner_expr, new_rules);
+                let rest =
+                    Self::transform_expr(&Rep(Box::new(transformed_inner.clone())), new_rules);
+                if *min == 0 {
+                    return rest;
+                }
+                let rep_min_rule_name = format!(
+                    "RepMin_{}_{}",
+                    min,
+                    Self::generate_unique_id(&transformed_inner)
+                );
+                if !new_rules.contains_key(&rep_min_rule_name) {
+                    let exact = Self::rep_exact(&transformed_inner, *min, new_rules);
+                    new_rules.insert(
+                        rep_min_rule_name.clone(),
+                        Seq(Box::new(exact), Box::new(rest)),
+                    );
+                }
+                Ident(rep_min_rule_name)
+            }
+            RepMax(inner_expr, max) => {
+                let transformed_inner = Self::transform_expr(inner_expr, new_rules);
+                Self::rep_max(&transformed_inner, *max, new_rules)
+            }
+            // e{n,m} is e{n} followed by e{,m-n}
+            RepMinMax(inner_expr, min, max) => {
+                let transformed_inner = Self::transform_expr(inner_expr, new_rules);
+                if *min == 0 {
+                    return Self::rep_max(&transformed_inner, *max, new_rules);
+                }
+                if min == max {
+                    return Self::rep_exact(&transformed_inner, *min, new_rules);
+                }
+                let rep_min_max_rule_name = format!(
+                    "RepMinMax_{}_{}_{}",
+                    min,
+                    max,
+                    Self::generate_unique_id(&transformed_inner)
+                );
+                if !new_rules.contains_key(&rep_min_max_rule_name) {
+                    let exact = Self::rep_exact(&transformed_inner, *min, new_rules);
+                    let rest = Self::rep_max(&transformed_inner, max - min, new_rules);
+                    new_rules.insert(
+                        rep_min_max_rule_name.clone(),
+                        Seq(Box::new(exact), Box::new(rest)),
+                    );
                 }
-
-                Ident(rep_once_rule_name)
+                Ident(rep_min_max_rule_name)
+            }
+            // PUSH gets a rule of its own so its span is a single node in the tree
+            Push(inner_expr) => {
+                let transformed_inner = Self::transform_expr(inner_expr, new_rules);
+                let push_rule_name =
+                    format!("PUSH_{}", Self::generate_unique_id(&transformed_inner));
+                new_rules
+                    .entry(push_rule_name.clone())
+                    .or_insert_with(|| Push(Box::new(transformed_inner)));
+                Ident(push_rule_name)
             }
             // Other expression types...
             _ => expr.clone(),
         }
     }
 
+    // e{n} as a rule of up to REP_CHUNK copies, longer runs chain chunks so rows stay short
+    fn rep_exact(
+        transformed_inner: &pest_meta::ast::Expr,
+        count: u32,
+        new_rules: &mut HashMap<String, pest_meta::ast::Expr>,
+    ) -> pest_meta::ast::Expr {
+        if count == 1 {
+            return transformed_inner.clone();
+        }
+        let rep_exact_rule_name = format!(
+            "RepExact_{}_{}",
+            count,
+            Self::generate_unique_id(transformed_inner)
+        );
+        if !new_rules.contains_key(&rep_exact_rule_name) {
+            let exact_expr = if count == 0 {
+                Str("".to_string())
+            } else if count <= REP_CHUNK {
+                (0..count)
+                    .map(|_| transformed_inner.clone())
+                    .reduce(|a, b| Seq(Box::new(a), Box::new(b)))
+                    .unwrap()
+            } else {
+                Seq(
+                    Box::new(Self::rep_exact(transformed_inner, REP_CHUNK, new_rules)),
+                    Box::new(Self::rep_exact(
+                        transformed_inner,
+                        count - REP_CHUNK,
+                        new_rules,
+                    )),
+                )
+            };
+            new_rules.insert(rep_exact_rule_name.clone(), exact_expr);
+        }
+        Ident(rep_exact_rule_name)
+    }
+
+    // e{,m} as a chain like Rep, each link either takes one more e or stops
+    fn rep_max(
+        transformed_inner: &pest_meta::ast::Expr,
+        max: u32,
+        new_rules: &mut HashMap<String, pest_meta::ast::Expr>,
+    ) -> pest_meta::ast::Expr {
+        if max == 0 {
+            return Self::rep_exact(transformed_inner, 0, new_rules);
+        }
+        let rep_max_rule_name = format!(
+            "RepMax_{}_{}",
+            max,
+            Self::generate_unique_id(transformed_inner)
+        );
+        if !new_rules.contains_key(&rep_max_rule_name) {
+            let take = if max == 1 {
+                transformed_inner.clone()
+            } else {
+                Seq(
+                    Box::new(transformed_inner.clone()),
+                    Box::new(Self::rep_max(transformed_inner, max - 1, new_rules)),
+                )
+            };
+            new_rules.insert(
+                rep_max_rule_name.clone(),
+                Choice(Box::new(take), Box::new(Str("".to_string()))),
+            );
+        }
+        Ident(rep_max_rule_name)
+    }
+
     // Function to match with pest built in terminal rules
     pub fn is_terminal(name: &str) -> bool {
         matches!(
@@ -761,7 +1691,23 @@ impl GrammarGraph {
             | "ASCII_ALPHANUMERIC"
             | "ASCII"
             | "NEWLINE"
-        )
+        ) || pest::unicode::by_name(name).is_some()
+    }
+
+    // Delimiter stack operation of a rule: 1 PUSH, 2 POP, 3 PEEK, 4 DROP, 0 for none
+    pub fn stack_kind(rule_name: &str) -> usize {
+        match rule_name {
+            name if name.starts_with("PUSH_") => 1,
+            "terminal_POP" => 2,
+            "terminal_PEEK" => 3,
+            "terminal_DROP" => 4,
+            _ => 0,
+        }
+    }
+
+    // Only grammars with PUSH, POP, PEEK or DROP need the delimiter stack
+    pub fn has_stack(&self) -> bool {
+        self.rules.keys().any(|name| Self::stack_kind(name) != 0)
     }
 
     // Generate a rule name with the appropiate type
@@ -771,6 +1717,7 @@ impl GrammarGraph {
             Str(s) => s.to_string(),
             //format!("Str_{}", s),
             NegPred(s) => format!("NegPred_{}", Self::generate_unique_id(s)),
+            PosPred(s) => format!("PosPred_{}", Self::generate_unique_id(s)),
             Ident(s) => format!("Ident_{}", s),
             Seq(left, right) => format!(
                 "Seq_{}_{}",
@@ -788,13 +1735,67 @@ impl GrammarGraph {
             Rep(inner) => format!("Rep_{}", Self::generate_unique_id(inner)),
             RepOnce(inner) => format!("RepOnce_{}", Self::generate_unique_id(inner)),
             Range(start, end) => format!("Range_{}_{}", start, end),
+            RepExact(inner, n) => format!("RepExact_{}_{}", n, Self::generate_unique_id(inner)),
+            RepMin(inner, n) => format!("RepMin_{}_{}", n, Self::generate_unique_id(inner)),
+            RepMax(inner, m) => format!("RepMax_{}_{}", m, Self::generate_unique_id(inner)),
+            RepMinMax(inner, n, m) => {
+                format!("RepMinMax_{}_{}_{}", n, m, Self::generate_unique_id(inner))
+            }
+            Push(inner) => format!("Push_{}", Self::generate_unique_id(inner)),
             // Add cases for other expression types as necessary
             _ => "Unsupported_Expr_Type".to_string(),
         }
     }
 
+    // Rebuilds the parse tree without the nodes collapse_unary_rows folded into their parent,
+    // in the same preorder so the ids stay dense and the leaves stay in document order
+    fn collapse_unary_nodes(&mut self) {
+        let mut graph = DiGraph::new();
+        for root in self.graph.externals(Direction::Incoming) {
+            self.copy_collapsed(&mut graph, root, None);
+        }
+        self.graph = graph;
+    }
+
+    fn copy_collapsed(
+        &self,
+        graph: &mut DiGraph<GrammarGraphNode, ()>,
+        node_index: NodeIndex,
+        parent_index: Option<NodeIndex>,
+    ) {
+        let node = &self.graph[node_index];
+        let copy_index = graph.add_node(node.clone());
+        if let Some(p_index) = parent_index {
+            graph.add_edge(p_index, copy_index, ());
+        }
+
+        let mut children = self.tree_children(node_index);
+        while let [child] = children[..]
+            && self.graph[child].value.is_none()
+            && self.collapsible(&node.node_type, &self.graph[child].node_type)
+        {
+            children = self.tree_children(child);
+        }
+        for child in children {
+            self.copy_collapsed(graph, child, Some(copy_index));
+        }
+    }
+
+    // Children in the order they were added, which is document order
+    fn tree_children(&self, node_index: NodeIndex) -> Vec<NodeIndex> {
+        let mut children: Vec<NodeIndex> = self
+            .graph
+            .neighbors_directed(node_index, Direction::Outgoing)
+            .collect();
+        children.reverse();
+        children
+    }
+
     #[allow(dead_code)]
     pub fn parse_and_convert_lcrs(&mut self) {
+        if self.collapse_chains {
+            self.collapse_unary_nodes();
+        }
         let mut node_map: HashMap<usize, NodeIndex> = HashMap::new();
         // Create all nodes in the LCRS tree
         for node_index in self.graph.node_indices() {
@@ -981,7 +1982,7 @@ mod tests {
     use super::*;
     use pest_meta::ast::Expr;
     use std::collections::HashMap;
-    // use std::fs;
+    use std::fs;
     // use std::path::Path;
 
     #[test]
@@ -994,10 +1995,14 @@ mod tests {
             np: HashMap::new(),
             atom: Vec::new(),
             np_rule_names: HashSet::new(),
+            pp_rule_names: HashSet::new(),
             max_rule_size: 0,
             rule_count: 0,
             rule_names: HashMap::new(),
             max_np_rule_size: 0,
+            ranges: HashMap::new(),
+            max_arity: None,
+            collapse_chains: false,
         };
 
         // Add nodes to the lcrs_tree, properly initializing all fields
@@ -1080,10 +2085,14 @@ mod tests {
             np: HashMap::new(),
             atom: Vec::new(),
             np_rule_names: HashSet::new(),
+            pp_rule_names: HashSet::new(),
             max_rule_size: 0,
             rule_count: 0,
             rule_names: HashMap::new(),
             max_np_rule_size: 0,
+            ranges: HashMap::new(),
+            max_arity: None,
+            collapse_chains: false,
         };
 
         // Adding nodes
@@ -1187,10 +2196,14 @@ mod tests {
             np: HashMap::new(),
             atom: Vec::new(),
             np_rule_names: HashSet::new(),
+            pp_rule_names: HashSet::new(),
             max_rule_size: 0,
             rule_count: 0,
             rule_names: HashMap::new(),
             max_np_rule_size: 0,
+            ranges: HashMap::new(),
+            max_arity: None,
+            collapse_chains: false,
         };
 
         // Adding nodes
@@ -1252,10 +2265,14 @@ mod tests {
             np: HashMap::new(),
             atom: Vec::new(),
             np_rule_names: HashSet::new(),
+            pp_rule_names: HashSet::new(),
             max_rule_size: 0,
             rule_count: 0,
             rule_names: HashMap::new(),
             max_np_rule_size: 0,
+            ranges: HashMap::new(),
+            max_arity: None,
+            collapse_chains: false,
         };
 
         // Adding nodes to the graph
@@ -1577,10 +2594,14 @@ mod tests {
             np: HashMap::new(),
             atom: Vec::new(),
             np_rule_names: HashSet::new(),
+            pp_rule_names: HashSet::new(),
             max_rule_size: 0,
             rule_count: 0,
             rule_names: HashMap::new(),
             max_np_rule_size: 0,
+            ranges: HashMap::new(),
+            max_arity: None,
+            collapse_chains: false,
         };
         // Assuming Expr and other related enums/types are defined properly
         let mut expr = Expr::Seq(
@@ -1634,7 +2655,7 @@ mod tests {
                 "terminal_ASCII_HEX_DIGIT",
                 vec!['0'..='9', 'a'..='f', 'A'..='F'],
             ),
-            ("terminl_ASCII_ALPHA_LOWER", vec!['a'..='z']),
+            ("terminal_ASCII_ALPHA_LOWER", vec!['a'..='z']),
             ("terminal_ASCII_ALPHA_UPPER", vec!['A'..='Z']),
             ("terminal_ASCII_ALPHA", vec!['a'..='z', 'A'..='Z']),
             (
@@ -1679,4 +2700,500 @@ mod tests {
         );
         assert!(newline_expanded.contains(&vec!["\r".to_string(), "terminal_NEWLINE".to_string()]));
     }
+
+    #[test]
+    fn test_pos_pred() {
+        let grammar = fs::read_to_string("grammars/test_pp.pest").unwrap();
+        let mut grammar_graph = GrammarGraph::new();
+        grammar_graph
+            .parse_text_and_build_graph(&grammar, "ab")
+            .expect("Failed to parse input");
+
+        assert!(grammar_graph.pp_rule_names.contains("special0"));
+        assert!(grammar_graph.np_rule_names.contains("special0"));
+        assert_eq!(
+            grammar_graph.np["special0"],
+            (vec!["b".to_string(), "c".to_string()], "r4".to_string())
+        );
+        assert_eq!(
+            grammar_graph.rules["r3"][0][..2],
+            ["special0".to_string(), "r3".to_string()]
+        );
+
+        let bad = fs::read_to_string("tests/test_docs/test_pp_bad.txt").unwrap();
+        assert!(
+            GrammarGraph::new()
+                .parse_text_and_build_graph(&grammar, &bad)
+                .is_err()
+        );
+
+        // Only single characters can be checked against the document
+        assert!(
+            GrammarGraph::new()
+                .compile_grammar("root = { &\"ab\" ~ ANY ~ ANY }")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_multi_char_neg_pred() {
+        let grammar = fs::read_to_string("grammars/test_np_multi.pest").unwrap();
+        let mut grammar_graph = GrammarGraph::new();
+        grammar_graph
+            .parse_text_and_build_graph(&grammar, "/* a* /*/,/**/")
+            .expect("Failed to parse input");
+
+        assert_eq!(grammar_graph.np["special0"].0, vec!["*/".to_string()]);
+
+        let mut grammar_graph = GrammarGraph::new();
+        grammar_graph
+            .compile_grammar("root = { (!(^\"ab\" | \"c\" ~ \"d\") ~ ANY)* }")
+            .unwrap();
+        let (excluded, _) = grammar_graph.np.values().next().unwrap();
+        assert_eq!(excluded, &["ab", "aB", "Ab", "AB", "cd"]);
+
+        // The window is packed into one field element
+        assert!(
+            GrammarGraph::new()
+                .compile_grammar("root = { (!\"12345678\" ~ ANY)* }")
+                .is_err()
+        );
+    }
+
+    // Every non-terminal node in the tree has its row in the table
+    fn assert_rows_in_table(grammar_graph: &GrammarGraph) {
+        for node in grammar_graph.lcrs_tree.node_weights() {
+            if node.is_terminal || node.rule_name == "terminal_ANY" {
+                continue;
+            }
+            let mut row: Vec<String> = grammar_graph
+                .get_all_children(node.id)
+                .iter()
+                .map(|child| child.rule_name.clone())
+                .filter(|name| name != "WHITESPACE" && name != "COMMENT")
+                .rev()
+                .collect();
+            // A class row leaves its character to the range check
+            if grammar_graph.ranges.contains_key(&node.rule_name) {
+                row = vec![String::new()];
+            }
+            row.push(node.rule_name.clone());
+            row.resize(grammar_graph.max_rule_size, String::new());
+            assert!(
+                grammar_graph.rules[&node.rule_name].contains(&row),
+                "{:?} is not in the table",
+                row
+            );
+        }
+    }
+
+    #[test]
+    fn test_split_rules() {
+        for (grammar, doc) in [
+            ("grammars/c_simple.pest", "tests/test_docs/c/c1.txt"),
+            (
+                "grammars/test_comment.pest",
+                "tests/test_docs/test_comment.txt",
+            ),
+            ("grammars/test_stack.pest", "tests/test_docs/test_stack.txt"),
+            (
+                "grammars/test_silent.pest",
+                "tests/test_docs/test_silent.txt",
+            ),
+        ] {
+            let grammar = fs::read_to_string(grammar).unwrap();
+            let doc = fs::read_to_string(doc).unwrap();
+            let build = |arity| {
+                let mut grammar_graph = GrammarGraph::new();
+                grammar_graph.max_arity = arity;
+                grammar_graph
+                    .parse_text_and_build_graph(&grammar, &doc)
+                    .unwrap();
+                grammar_graph.parse_and_convert_lcrs();
+                grammar_graph
+            };
+            let whole = build(None);
+            for arity in [2, 3] {
+                let split = build(Some(arity));
+                assert_rows_in_table(&split);
+                // The last column is the rule name
+                assert!(split.max_rule_size <= arity + 1);
+                assert!(split.lcrs_tree.node_count() >= whole.lcrs_tree.node_count());
+            }
+        }
+
+        let mut grammar_graph = GrammarGraph::new();
+        grammar_graph.max_arity = Some(1);
+        assert!(grammar_graph.compile_grammar("root = { \"a\" }").is_err());
+    }
+
+    #[test]
+    fn test_collapse_chains() {
+        for (grammar, doc) in [
+            (
+                "grammars/json.pest",
+                "tests/test_docs/json/test_json_128.txt",
+            ),
+            ("grammars/c_simple.pest", "tests/test_docs/c/c1.txt"),
+            (
+                "grammars/test_comment.pest",
+                "tests/test_docs/test_comment.txt",
+            ),
+            ("grammars/test_stack.pest", "tests/test_docs/test_stack.txt"),
+            (
+                "grammars/test_silent.pest",
+                "tests/test_docs/test_silent.txt",
+            ),
+        ] {
+            let grammar = fs::read_to_string(grammar).unwrap();
+            let doc = fs::read_to_string(doc).unwrap();
+            let build = |collapse_chains| {
+                let mut grammar_graph = GrammarGraph::new();
+                grammar_graph.collapse_chains = collapse_chains;
+                grammar_graph
+                    .parse_text_and_build_graph(&grammar, &doc)
+                    .unwrap();
+                grammar_graph.parse_and_convert_lcrs();
+                grammar_graph
+            };
+            let leaves = |grammar_graph: &GrammarGraph| -> Vec<String> {
+                grammar_graph
+                    .lcrs_tree
+                    .node_weights()
+                    .filter(|node| node.is_terminal)
+                    .map(|node| node.rule_name.clone())
+                    .collect()
+            };
+            let whole = build(false);
+            let collapsed = build(true);
+            assert_rows_in_table(&collapsed);
+            assert_eq!(leaves(&collapsed), leaves(&whole));
+            assert_eq!(collapsed.max_rule_size, whole.max_rule_size);
+            assert!(collapsed.lcrs_tree.node_count() <= whole.lcrs_tree.node_count());
+        }
+
+        // value and number only name their child
+        let mut grammar_graph = GrammarGraph::new();
+        grammar_graph.collapse_chains = true;
+        grammar_graph
+            .parse_text_and_build_graph(
+                "root = { value ~ EOI }\nvalue = { number | \"x\" }\nnumber = { ASCII_DIGIT }",
+                "7",
+            )
+            .unwrap();
+        grammar_graph.parse_and_convert_lcrs();
+        let names: Vec<&str> = grammar_graph
+            .lcrs_tree
+            .node_weights()
+            .map(|node| node.rule_name.as_str())
+            .collect();
+        assert_eq!(names, ["root", "value", "7", "EOI", ""]);
+        assert!(
+            grammar_graph.rules["value"]
+                .iter()
+                .any(|row| row[..2] == ["7", "value"])
+        );
+    }
+
+    #[test]
+    fn test_stack_ops() {
+        let grammar = fs::read_to_string("grammars/test_stack.pest").unwrap();
+        let mut grammar_graph = GrammarGraph::new();
+        grammar_graph
+            .parse_text_and_build_graph(&grammar, "<ab>12</ab>[x]x")
+            .expect("Failed to parse input");
+        grammar_graph.parse_and_convert_lcrs();
+
+        let push = "PUSH_Ident_name";
+        assert_eq!(GrammarGraph::stack_kind(push), 1);
+        assert_eq!(
+            grammar_graph.rules[push][0][..3],
+            [STACK_END.to_string(), "name".to_string(), push.to_string()]
+        );
+
+        assert_rows_in_table(&grammar_graph);
+
+        // POP re-reads the pushed span before closing the scope
+        let pop = grammar_graph
+            .lcrs_tree
+            .node_weights()
+            .find(|node| node.rule_name == "terminal_POP")
+            .unwrap();
+        let children: Vec<String> = grammar_graph
+            .get_all_children(pop.id)
+            .iter()
+            .map(|child| child.rule_name.clone())
+            .collect();
+        assert_eq!(children, [STACK_TAIL, STACK_END]);
+
+        assert!(
+            GrammarGraph::new()
+                .parse_text_and_build_graph(&grammar, "<ab>12</ac>[x]x")
+                .is_err()
+        );
+
+        // Spans past MAX_STACK_SPAN are rejected before the solver sees them
+        let name = "a".repeat(MAX_STACK_SPAN);
+        GrammarGraph::new()
+            .parse_text_and_build_graph(&grammar, &format!("<{}>1</{}>[x]x", name, name))
+            .expect("Failed to parse input");
+        let name = "a".repeat(MAX_STACK_SPAN + 1);
+        let err = GrammarGraph::new()
+            .parse_text_and_build_graph(&grammar, &format!("<{}>1</{}>[x]x", name, name))
+            .unwrap_err();
+        assert!(err.contains("at most 14"), "{}", err);
+
+        for unsupported in [
+            "root = { PUSH(\"a\") ~ PEEK[0..1] }",
+            "root = { PUSH(\"a\") ~ POP_ALL }",
+        ] {
+            assert!(GrammarGraph::new().compile_grammar(unsupported).is_err());
+        }
+    }
+
+    #[test]
+    fn test_silent_rules() {
+        let grammar = fs::read_to_string("grammars/test_silent.pest").unwrap();
+        let mut grammar_graph = GrammarGraph::new();
+        grammar_graph
+            .parse_text_and_build_graph(&grammar, "12,ab,3")
+            .expect("Failed to parse input");
+        grammar_graph.parse_and_convert_lcrs();
+
+        assert!(!grammar_graph.rules.contains_key("item"));
+        assert!(
+            grammar_graph
+                .lcrs_tree
+                .node_weights()
+                .all(|node| node.rule_name != "item")
+        );
+        assert_rows_in_table(&grammar_graph);
+
+        // Silent implicit rules keep their node, the circuit skips them by name
+        let mut grammar_graph = GrammarGraph::new();
+        grammar_graph
+            .parse_text_and_build_graph(
+                "root = { SOI ~ \"a\" ~ \"b\" ~ EOI }\nWHITESPACE = _{ \" \" }",
+                "a b",
+            )
+            .expect("Failed to parse input");
+        assert!(grammar_graph.rules.contains_key("WHITESPACE"));
+
+        assert!(
+            GrammarGraph::new()
+                .compile_grammar("root = { SOI ~ a ~ EOI }\na = _{ \"x\" ~ a | \"y\" }")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_implicit_comments() {
+        let grammar = fs::read_to_string("grammars/test_comment.pest").unwrap();
+        let doc = fs::read_to_string("tests/test_docs/test_comment.txt").unwrap();
+        let mut grammar_graph = GrammarGraph::new();
+        grammar_graph
+            .parse_text_and_build_graph(&grammar, &doc)
+            .expect("Failed to parse input");
+        grammar_graph.parse_and_convert_lcrs();
+
+        // Comments keep their node but never show up in a parent's row
+        let comments = grammar_graph
+            .lcrs_tree
+            .node_weights()
+            .filter(|node| node.rule_name == "COMMENT")
+            .count();
+        assert_eq!(comments, 2);
+        assert!(
+            grammar_graph.rules["list"]
+                .iter()
+                .all(|row| !row.contains(&"COMMENT".to_string()))
+        );
+        assert_rows_in_table(&grammar_graph);
+    }
+
+    #[test]
+    fn test_explicit_comment() {
+        // A referenced COMMENT is proven through an atomic copy, the implicit one is skipped
+        let grammar = "root = { SOI ~ line ~ \"b\" ~ EOI }\nline = ${ \"a\" ~ COMMENT }\n\
+                       COMMENT = { \"#\" ~ ASCII_DIGIT }";
+        let mut grammar_graph = GrammarGraph::new();
+        grammar_graph
+            .parse_text_and_build_graph(grammar, "a#1#2b")
+            .expect("Failed to parse input");
+        grammar_graph.parse_and_convert_lcrs();
+        let count = |name: &str| {
+            grammar_graph
+                .lcrs_tree
+                .node_weights()
+                .filter(|node| node.rule_name == name)
+                .count()
+        };
+        assert_eq!(count("explicit_COMMENT"), 1);
+        assert_eq!(count("COMMENT"), 1);
+        assert!(
+            grammar_graph.rules["line"]
+                .iter()
+                .any(|row| row.contains(&"explicit_COMMENT".to_string()))
+        );
+        assert_rows_in_table(&grammar_graph);
+
+        assert!(!parses_with_table(grammar, "ab"));
+    }
+
+    #[test]
+    fn test_whitespace_in_repetition() {
+        // (" " | "x")+ repeats a choice in a sequence, the space needs a leaf of its own
+        let mut grammar_graph = GrammarGraph::new();
+        grammar_graph
+            .parse_text_and_build_graph("root = { SOI ~ \"a\" ~ (\" \" | \"x\")+ ~ EOI }", "a x ")
+            .expect("Failed to parse input");
+        grammar_graph.parse_and_convert_lcrs();
+        assert_eq!(
+            grammar_graph
+                .lcrs_tree
+                .node_weights()
+                .filter(|node| node.is_terminal && node.rule_name == " ")
+                .count(),
+            2
+        );
+        assert_rows_in_table(&grammar_graph);
+    }
+
+    #[test]
+    fn test_unicode_classes() {
+        let grammar = fs::read_to_string("grammars/test_unicode.pest").unwrap();
+        let doc = fs::read_to_string("tests/test_docs/test_unicode.txt").unwrap();
+        let mut grammar_graph = GrammarGraph::new();
+        grammar_graph
+            .parse_text_and_build_graph(&grammar, &doc)
+            .expect("Failed to parse input");
+        grammar_graph.parse_and_convert_lcrs();
+
+        // A row per interval instead of a row per character
+        let xid_start = &grammar_graph.ranges["terminal_XID_START"];
+        assert_eq!(
+            grammar_graph.rules["terminal_XID_START"].len(),
+            xid_start.len()
+        );
+        assert!(xid_start.contains(&('a' as u32, 'z' as u32)));
+        assert!(
+            xid_start
+                .iter()
+                .any(|&(lo, hi)| lo <= 'ж' as u32 && 'ж' as u32 <= hi)
+        );
+        assert_eq!(
+            grammar_graph.ranges["range_\u{80}_\u{10ffff}"],
+            vec![(0x80, 0x10FFFF)]
+        );
+        assert!(!grammar_graph.ranges.contains_key("terminal_ASCII_DIGIT"));
+
+        // A negated property is the complement of its intervals
+        let grammar = "root = { SOI ~ (!(LETTER | \"_\") ~ ANY)+ ~ &XID_START ~ ANY ~ EOI }";
+        assert!(parses_with_table(grammar, "12 ж"));
+        assert!(!parses_with_table(grammar, "1a ж"));
+        assert!(!parses_with_table(grammar, "1_ ж"));
+        assert!(!parses_with_table(grammar, "12 1"));
+        let mut grammar_graph = GrammarGraph::new();
+        grammar_graph.compile_grammar(grammar).unwrap();
+        let not_letter = &grammar_graph.ranges["class_!(LETTER | \"_\")"];
+        assert_eq!(not_letter[0], (0, 'A' as u32 - 1));
+        assert!(not_letter.contains(&('Z' as u32 + 1, '_' as u32 - 1)));
+        assert_eq!(not_letter.last().unwrap().1, char::MAX as u32);
+
+        assert!(
+            GrammarGraph::new()
+                .compile_grammar("root = { SOI ~ (!LETTER ~ \"a\")* ~ EOI }")
+                .is_err()
+        );
+    }
+
+    // Parses input and checks the tree against the table, false if pest rejects it
+    fn parses_with_table(grammar: &str, input: &str) -> bool {
+        let mut grammar_graph = GrammarGraph::new();
+        if grammar_graph
+            .parse_text_and_build_graph(grammar, input)
+            .is_err()
+        {
+            return false;
+        }
+        grammar_graph.parse_and_convert_lcrs();
+        assert_rows_in_table(&grammar_graph);
+        true
+    }
+
+    #[test]
+    fn test_transform_rep_exact() {
+        let mut new_rules = HashMap::new();
+        let expr = RepExact(Box::new(Ident("ANY".to_string())), 6);
+        let result = GrammarGraph::transform_expr(&expr, &mut new_rules);
+
+        assert_eq!(result, Ident("RepExact_6_Ident_terminal_ANY".to_string()));
+        // Six copies are a chunk of four followed by a chunk of two
+        assert_eq!(
+            new_rules["RepExact_6_Ident_terminal_ANY"],
+            Seq(
+                Box::new(Ident("RepExact_4_Ident_terminal_ANY".to_string())),
+                Box::new(Ident("RepExact_2_Ident_terminal_ANY".to_string())),
+            )
+        );
+
+        let grammar = "root = { SOI ~ ASCII_DIGIT{6} ~ EOI }";
+        assert!(parses_with_table(grammar, "123456"));
+        assert!(!parses_with_table(grammar, "12345"));
+        assert!(!parses_with_table(grammar, "1234567"));
+    }
+
+    #[test]
+    fn test_transform_rep_min() {
+        let mut new_rules = HashMap::new();
+        let expr = RepMin(Box::new(Ident("ANY".to_string())), 2);
+        let result = GrammarGraph::transform_expr(&expr, &mut new_rules);
+
+        assert_eq!(result, Ident("RepMin_2_Ident_terminal_ANY".to_string()));
+        assert!(new_rules.contains_key("RepExact_2_Ident_terminal_ANY"));
+        assert!(new_rules.contains_key("Rep_Ident_terminal_ANY"));
+
+        let grammar = "root = { SOI ~ \"a\"{2,} ~ EOI }";
+        assert!(parses_with_table(grammar, "aa"));
+        assert!(parses_with_table(grammar, "aaaaa"));
+        assert!(!parses_with_table(grammar, "a"));
+    }
+
+    #[test]
+    fn test_transform_rep_max() {
+        let mut new_rules = HashMap::new();
+        let expr = RepMax(Box::new(Ident("ANY".to_string())), 3);
+        let result = GrammarGraph::transform_expr(&expr, &mut new_rules);
+
+        assert_eq!(result, Ident("RepMax_3_Ident_terminal_ANY".to_string()));
+        assert!(matches!(
+            new_rules["RepMax_3_Ident_terminal_ANY"],
+            Choice(_, _)
+        ));
+
+        let grammar = "root = { SOI ~ \"a\"{,3} ~ \"b\" ~ EOI }";
+        assert!(parses_with_table(grammar, "b"));
+        assert!(parses_with_table(grammar, "aaab"));
+        assert!(!parses_with_table(grammar, "aaaab"));
+    }
+
+    #[test]
+    fn test_transform_rep_min_max() {
+        let mut new_rules = HashMap::new();
+        let expr = RepMinMax(Box::new(Ident("ANY".to_string())), 2, 4);
+        let result = GrammarGraph::transform_expr(&expr, &mut new_rules);
+
+        assert_eq!(
+            result,
+            Ident("RepMinMax_2_4_Ident_terminal_ANY".to_string())
+        );
+        assert!(new_rules.contains_key("RepExact_2_Ident_terminal_ANY"));
+        assert!(new_rules.contains_key("RepMax_2_Ident_terminal_ANY"));
+
+        let grammar = "root = { SOI ~ ASCII_HEX_DIGIT{2,4} ~ \"-\" ~ EOI }";
+        assert!(parses_with_table(grammar, "ab-"));
+        assert!(parses_with_table(grammar, "abcd-"));
+        assert!(!parses_with_table(grammar, "a-"));
+        assert!(!parses_with_table(grammar, "abcde-"));
+    }
 }
@@ -15,6 +15,7 @@ use rand::rngs::OsRng;
 use segmented_circuit_memory::bellpepper::FCircuit;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
+use std::fmt;
 use std::sync::Arc;
 use std::sync::mpsc;
 use std::thread;
@@ -39,27 +40,47 @@ pub struct ProverInfo {
 #[derive(Serialize, Deserialize)]
 pub struct ProverOutput {
     pub compressed_snark: CompressedSNARK<E1, E2, C1, S1, S2>,
-    #[serde_as(as = "CompressedChecked<Option<CoralStepCircuit<AF>>>")]
-    pub empty: Option<CoralStepCircuit<AF>>,
     #[serde_as(as = "CompressedChecked<Option<kzg10::Proof<Bn254>>>")]
     pub doc_commit_proof: Option<kzg10::Proof<Bn254>>,
     pub z_0: Vec<N1>,
+    //Claimed circuit shape, the verifier rebuilds the circuit from these and the grammar
+    pub batch_size: usize,
+    pub tree_bucket: usize,
+    #[serde_as(as = "CompressedChecked<Vec<AF>>")]
+    pub perm_chal: Vec<AF>,
 }
 #[derive(CanonicalSerialize, CanonicalDeserialize)]
 
 pub struct CoralDocCommitment<'b> {
-    pub blind: AF,
+    //Blind root first, then one root per padding position
+    pub blind_roots: Vec<AF>,
     pub doc_commit: Commitment<Bn254>,
     pub commit_rand: kzg10::Randomness<AF, PolyBn254>,
     pub doc_commit_poly: PolyBn254,
     pub doc_ck: Powers<'b, Bn254>,
 }
 
-pub fn run_doc_committer<'a>(doc: &Vec<char>, ck: &Powers<'a, Bn254>) -> CoralDocCommitment<'a> {
+pub fn run_doc_committer<'a>(
+    doc: &Vec<char>,
+    ck: &Powers<'a, Bn254>,
+    max_doc_len: Option<usize>,
+) -> Result<CoralDocCommitment<'a>, String> {
     #[cfg(feature = "metrics")]
     log::tic(Component::Generator, "doc_commit");
 
-    let blind = AF::rand(&mut OsRng);
+    //Padding roots are random, so the commitment only reveals max_doc_len
+    let pad_len = match max_doc_len {
+        Some(max) if doc.len() > max => {
+            return Err(format!(
+                "Document has {} characters, more than the maximum {}",
+                doc.len(),
+                max
+            ));
+        }
+        Some(max) => max - doc.len(),
+        None => 0,
+    };
+    let blind_roots: Vec<AF> = (0..=pad_len).map(|_| AF::rand(&mut OsRng)).collect();
 
     let shift = AF::from(2_u64.pow(32));
 
@@ -71,7 +92,7 @@ pub fn run_doc_committer<'a>(doc: &Vec<char>, ck: &Powers<'a, Bn254>) -> CoralDo
             base_shift + to_F::<AF>(i)
         })
         .collect::<Vec<_>>();
-    doc_roots.push(blind);
+    doc_roots.extend_from_slice(&blind_roots);
 
     #[cfg(feature = "para")]
     let doc_commit_poly = build_root_products_para::<AF>(&doc_roots[..]);
@@ -79,24 +100,77 @@ pub fn run_doc_committer<'a>(doc: &Vec<char>, ck: &Powers<'a, Bn254>) -> CoralDo
     #[cfg(not(feature = "para"))]
     let doc_commit_poly = build_root_products::<AF>(&doc_roots[..]);
 
-    let (comms, rand) = ArkKZG::commit(ck, &doc_commit_poly, Some(1), Some(&mut OsRng)).unwrap();
+    let (comms, rand) = ArkKZG::commit(ck, &doc_commit_poly, Some(1), Some(&mut OsRng))
+        .map_err(|e| format!("Unable to commit to the document: {}", e))?;
 
     #[cfg(feature = "metrics")]
     log::stop(Component::Generator, "doc_commit");
 
-    CoralDocCommitment {
-        blind,
+    Ok(CoralDocCommitment {
+        blind_roots,
         doc_commit: comms,
         commit_rand: rand,
         doc_commit_poly,
         doc_ck: ck.clone(),
+    })
+}
+
+#[derive(Debug)]
+pub enum CoralSetupError {
+    //Setup bundle was made for a different batch size or tree bucket
+    ShapeMismatch,
+    GrammarMismatch,
+    SrsMismatch,
+    //Parse tree does not fit the requested bucket
+    TreeBucket(String),
+    Synthesis(SynthesisError),
+}
+
+impl fmt::Display for CoralSetupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoralSetupError::ShapeMismatch => {
+                write!(
+                    f,
+                    "setup bundle is for a different batch size or tree bucket"
+                )
+            }
+            CoralSetupError::GrammarMismatch => {
+                write!(f, "setup bundle was made for a different grammar")
+            }
+            CoralSetupError::SrsMismatch => {
+                write!(f, "setup bundle was made from a different SRS")
+            }
+            CoralSetupError::TreeBucket(e) => write!(f, "{}", e),
+            CoralSetupError::Synthesis(e) => write!(f, "witness generation failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CoralSetupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CoralSetupError::Synthesis(e) => Some(e),
+            _ => None,
+        }
     }
 }
 
+impl From<SynthesisError> for CoralSetupError {
+    fn from(e: SynthesisError) -> Self {
+        CoralSetupError::Synthesis(e)
+    }
+}
+
+// A setup bundle skips parameter generation, it has to match the grammar, the SRS, the batch
+// size and the tree bucket
 pub fn setup<ArkF: ArkPrimeField>(
     grammar_graph: &GrammarGraph,
     batch_size: usize,
-    doc_blind: ArkF,
+    doc_blind_roots: Vec<ArkF>,
+    tree_size_class: TreeSizeClass,
+    params: Option<SetupBundle>,
+    srs: &Srs,
 ) -> Result<
     (
         ProverInfo,
@@ -104,13 +178,49 @@ pub fn setup<ArkF: ArkPrimeField>(
         CoralStepCircuit<ArkF>,
         PublicParams<E1, E2, C1>,
     ),
-    SynthesisError,
+    CoralSetupError,
 > {
-    let mut base = CoralStepCircuit::new(grammar_graph, batch_size, doc_blind);
+    let tree_size = grammar_graph.lcrs_tree.node_count();
+    let tree_bucket = tree_size_class
+        .bucket(tree_size)
+        .map_err(CoralSetupError::TreeBucket)?;
+
+    if let Some(bundle) = &params {
+        if !bundle.matches(grammar_graph) {
+            return Err(CoralSetupError::GrammarMismatch);
+        }
+        if !bundle.matches_srs(srs) {
+            return Err(CoralSetupError::SrsMismatch);
+        }
+        if bundle.batch_size != batch_size || bundle.tree_bucket != tree_bucket {
+            return Err(CoralSetupError::ShapeMismatch);
+        }
+    }
+
+    let mut base = CoralStepCircuit::with_tree_size(
+        grammar_graph,
+        batch_size,
+        doc_blind_roots,
+        tree_size,
+        tree_bucket,
+    );
 
-    let (ic_blinds, ram_hints, mut empty) = base.solve(grammar_graph)?;
+    let (ic_blinds, ram_hints, mut empty) = base.solve(grammar_graph, srs)?;
 
-    let pp = gen_pp(&mut empty);
+    let (pp, pk) = match params {
+        Some(bundle) => (bundle.pp, bundle.pk),
+        None => {
+            let pp = gen_pp(&mut empty, srs);
+
+            #[cfg(feature = "metrics")]
+            log::tic(Component::Prover, "snark_params");
+            let (pk, _) = CompressedSNARK::<_, _, _, S1, S2>::setup(&pp).unwrap();
+
+            #[cfg(feature = "metrics")]
+            log::stop(Component::Prover, "snark_params");
+            (pp, pk)
+        }
+    };
 
     #[cfg(feature = "metrics")]
     log::tic(Component::Prover, "sample_random_layer");
@@ -120,13 +230,6 @@ pub fn setup<ArkF: ArkPrimeField>(
     #[cfg(feature = "metrics")]
     log::stop(Component::Prover, "sample_random_layer");
 
-    #[cfg(feature = "metrics")]
-    log::tic(Component::Prover, "snark_params");
-    let (pk, _) = CompressedSNARK::<_, _, _, S1, S2>::setup(&pp).unwrap();
-
-    #[cfg(feature = "metrics")]
-    log::stop(Component::Prover, "snark_params");
-
     let p_i = ProverInfo {
         ic_key_length: base.key_length,
         ic_blinds,
@@ -144,6 +247,26 @@ type Constraint<F> = (
     LinearCombination<F>,
 );
 
+// Constraints of one folding step, every step of a proof has the same shape. A single step of
+// dead nodes is enough to synthesize it
+pub fn step_constraints(g: &GrammarGraph, batch_size: usize, srs: &Srs) -> usize {
+    let mut csc = CoralStepCircuit::<AF>::public_circuit(g, batch_size, batch_size, srs);
+    let irw = InterRoundWires::new();
+
+    let cs = ConstraintSystem::<AF>::new_ref();
+    cs.set_optimization_goal(OptimizationGoal::Constraints);
+    let mut wires = CoralWires::wires_from_irw(&irw, cs.clone(), &mut csc, 0);
+    let mut memory = csc
+        .mem
+        .as_mut()
+        .unwrap()
+        .begin_new_circuit(cs.clone())
+        .unwrap();
+    multi_node_step(&mut csc, &mut wires, &mut memory, cs.clone())
+        .expect("Failed to synthesize a step");
+    cs.num_constraints()
+}
+
 pub fn make_coral_circuit<ArkF: ArkPrimeField>(
     csc: &mut CoralStepCircuit<ArkF>,
     irw: &mut InterRoundWires<ArkF>,
@@ -285,25 +408,26 @@ pub fn run_prove(
         compressed_snark: compressed_snark.unwrap(),
         z_0: z0_primary,
         doc_commit_proof: None,
-        empty: None,
+        batch_size: 0,
+        tree_bucket: 0,
+        perm_chal: Vec::new(),
     })
 }
 
 pub fn run_para_prover<ArkF: ArkPrimeField>(
-    grammar_graph: &GrammarGraph,
     base: CoralStepCircuit<AF>,
     p_i: &mut ProverInfo,
     doc_commit: CoralDocCommitment<'_>,
     pp: &PublicParams<E1, E2, C1>,
 ) -> Result<ProverOutput, NovaError> {
-    let n_rounds = u32::div_ceil(
-        grammar_graph.lcrs_tree.node_count() as u32,
-        base.batch_size as u32,
-    ) as usize;
+    let n_rounds = base.num_steps();
 
     let mut base = base;
 
     let perm_chal = base.mem.as_ref().unwrap().perm_chal.clone();
+    base.doc_chal = doc_challenge(&doc_commit.doc_commit, base.grammar_digest, &perm_chal);
+    let batch_size = base.batch_size;
+    let tree_bucket = base.tree_null_val;
 
     let mut irw = InterRoundWires::new();
 
@@ -341,6 +465,7 @@ pub fn run_para_prover<ArkF: ArkPrimeField>(
     let doc_ck = doc_commit.doc_ck.clone();
     let doc_commit_poly = doc_commit.doc_commit_poly.clone();
     let commit_rand = doc_commit.commit_rand.clone();
+    let doc_chal = base.doc_chal;
 
     let (doc_proof_sender, doc_proof_recv) = mpsc::channel();
     let now = Instant::now();
@@ -352,7 +477,7 @@ pub fn run_para_prover<ArkF: ArkPrimeField>(
         s.spawn(move || {
             #[cfg(feature = "metrics")]
             log::tic(Component::Prover, "doc_commit_proof");
-            let doc_proof = ArkKZG::open(&doc_ck, &doc_commit_poly, perm_chal[0], &commit_rand);
+            let doc_proof = ArkKZG::open(&doc_ck, &doc_commit_poly, doc_chal, &commit_rand);
             assert!(doc_proof.is_ok());
             doc_proof_sender
                 .send(doc_proof.unwrap())
@@ -380,6 +505,9 @@ pub fn run_para_prover<ArkF: ArkPrimeField>(
     let proof_ark_kzg = doc_proof_recv.recv().expect("Failed to receive doc proof");
 
     prover_output.doc_commit_proof = Some(proof_ark_kzg);
+    prover_output.batch_size = batch_size;
+    prover_output.tree_bucket = tree_bucket;
+    prover_output.perm_chal = perm_chal;
 
     #[cfg(feature = "metrics")]
     {
@@ -397,19 +525,21 @@ pub fn run_para_prover<ArkF: ArkPrimeField>(
 }
 
 pub fn run_prover<ArkF: ArkPrimeField>(
-    grammar_graph: &GrammarGraph,
     base: &mut CoralStepCircuit<AF>,
     p_i: &mut ProverInfo,
     doc_commit: CoralDocCommitment<'_>,
     pp: &PublicParams<E1, E2, C1>,
 ) -> Result<ProverOutput, NovaError> {
-    let n_rounds = u32::div_ceil(
-        grammar_graph.lcrs_tree.node_count() as u32,
-        base.batch_size as u32,
-    ) as usize;
+    let n_rounds = base.num_steps();
 
     println!("n rounds {:?}", n_rounds);
 
+    base.doc_chal = doc_challenge(
+        &doc_commit.doc_commit,
+        base.grammar_digest,
+        &base.mem.as_ref().unwrap().perm_chal,
+    );
+
     //Actually prove things now
     let mut irw = InterRoundWires::new();
 
@@ -485,7 +615,7 @@ pub fn run_prover<ArkF: ArkPrimeField>(
     let proof_ark_kzg = ArkKZG::open(
         &doc_commit.doc_ck,
         &doc_commit.doc_commit_poly,
-        base.mem.as_mut().unwrap().perm_chal[0],
+        base.doc_chal,
         &doc_commit.commit_rand,
     );
     assert!(proof_ark_kzg.is_ok());
@@ -494,6 +624,8 @@ pub fn run_prover<ArkF: ArkPrimeField>(
         compressed_snark: compressed_snark.unwrap(),
         z_0: z0_primary,
         doc_commit_proof: Some(proof_ark_kzg.unwrap()),
-        empty: None,
+        batch_size: base.batch_size,
+        tree_bucket: base.tree_null_val,
+        perm_chal: base.mem.as_ref().unwrap().perm_chal.clone(),
     })
 }
@@ -9,7 +9,7 @@ use petgraph::{Direction, visit::EdgeRef};
 use segmented_circuit_memory::memory::mem_type::MemType;
 use segmented_circuit_memory::memory::nebula::{MemBuilder, RunningMem};
 use sha2::{Digest, Sha256};
-use std::{cmp::max, usize};
+use std::{cmp::max, ops::Range, str::FromStr, usize};
 
 #[cfg(feature = "metrics")]
 use metrics::metrics::{log, log::Component};
@@ -58,7 +58,8 @@ impl<F: ArkPrimeField> NodeElem<F> {
 
 pub fn coral_hash<F: ArkPrimeField>(obj: &str) -> F {
     let mut out: F;
-    if obj.len() == 1 {
+    // A single character is its code point, so character classes can be range checked
+    if obj.chars().count() == 1 {
         let char = obj.chars().next().unwrap();
         out = F::from(char as u32);
     } else {
@@ -72,7 +73,11 @@ pub fn coral_hash<F: ArkPrimeField>(obj: &str) -> F {
     out
 }
 
-pub fn make_node_elem<F: ArkPrimeField>(id: usize, g: &GrammarGraph) -> NodeElem<F> {
+pub fn make_node_elem<F: ArkPrimeField>(
+    id: usize,
+    g: &GrammarGraph,
+    tree_null_val: usize,
+) -> NodeElem<F> {
     let n = g.get_node(id).unwrap();
     let node_index = g
         .lcrs_tree
@@ -87,7 +92,7 @@ pub fn make_node_elem<F: ArkPrimeField>(id: usize, g: &GrammarGraph) -> NodeElem
 
     let child_index = match has_child {
         Some(e) => e.target().index(),
-        None => g.lcrs_tree.node_count(),
+        None => tree_null_val,
     };
 
     let has_sib = g
@@ -96,12 +101,12 @@ pub fn make_node_elem<F: ArkPrimeField>(id: usize, g: &GrammarGraph) -> NodeElem
         .find(|edge| edge.weight() == &EdgeType::Sibling);
     let sib_index = match has_sib {
         Some(e) => e.target().index(),
-        None => g.lcrs_tree.node_count(),
+        None => tree_null_val,
     };
 
     let parent = match n.parent_id {
         Some(x) => x,
-        None => g.lcrs_tree.node_count(),
+        None => tree_null_val,
     };
 
     NodeElem::new(
@@ -114,6 +119,49 @@ pub fn make_node_elem<F: ArkPrimeField>(id: usize, g: &GrammarGraph) -> NodeElem
     )
 }
 
+// Public size class the parse tree is padded up to, the verifier only learns the bucket
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TreeSizeClass {
+    #[default]
+    Exact,
+    NextPow2,
+    Max(usize),
+}
+
+impl TreeSizeClass {
+    pub fn bucket(&self, tree_size: usize) -> Result<usize, String> {
+        match self {
+            TreeSizeClass::Exact => Ok(tree_size),
+            TreeSizeClass::NextPow2 => Ok(tree_size.next_power_of_two()),
+            TreeSizeClass::Max(max) if tree_size > *max => Err(format!(
+                "Parse tree has {} nodes, the bucket only fits {}",
+                tree_size, max
+            )),
+            TreeSizeClass::Max(max) => Ok(*max),
+        }
+    }
+}
+
+impl FromStr for TreeSizeClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(TreeSizeClass::Exact),
+            "pow2" => Ok(TreeSizeClass::NextPow2),
+            _ => s.parse::<usize>().map(TreeSizeClass::Max).map_err(|_| {
+                format!(
+                    "Expected \"exact\", \"pow2\" or a maximum node count, got {}",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+// A pushed span packs seven 32 bit characters into each limb
+pub const STACK_SPAN_LIMBS: usize = MAX_STACK_SPAN.div_ceil(7);
+
 #[derive(Clone, Debug)]
 pub struct CoralWires<F: ArkPrimeField> {
     pub cur_node_id: FpVar<F>,
@@ -129,6 +177,11 @@ pub struct CoralWires<F: ArkPrimeField> {
     pub doc_ctr: FpVar<F>,
     pub prev_t_sp: FpVar<F>,
     pub prev_step_t_ops: Boolean<F>,
+    pub stk_kind: FpVar<F>,
+    pub stk_mult: FpVar<F>,
+    pub stk_limb: FpVar<F>,
+    pub stk_span: Vec<FpVar<F>>,
+    pub doc_chal: FpVar<F>,
 }
 
 pub fn print_wires<F: ArkPrimeField>(wires: &CoralWires<F>) {
@@ -151,6 +204,12 @@ pub fn print_wires<F: ArkPrimeField>(wires: &CoralWires<F>) {
     );
     println!("np_sp: {:?}", wires.np_sp.value().unwrap());
     println!("atom_sp: {:?}", wires.atom_sp.value().unwrap());
+    println!("stk_kind: {:?}", wires.stk_kind.value().unwrap());
+    println!("stk_mult: {:?}", wires.stk_mult.value().unwrap());
+    println!("stk_limb: {:?}", wires.stk_limb.value().unwrap());
+    for limb in wires.stk_span.iter() {
+        println!("stk_span: {:?}", limb.value().unwrap());
+    }
 }
 
 impl<F: ArkPrimeField> CoralWires<F> {
@@ -169,6 +228,11 @@ impl<F: ArkPrimeField> CoralWires<F> {
             prev_step_t_ops: old_wires.prev_step_t_ops.clone(),
             np_sp: old_wires.np_sp.clone(),
             atom_sp: old_wires.atom_sp.clone(),
+            stk_kind: old_wires.stk_kind.clone(),
+            stk_mult: old_wires.stk_mult.clone(),
+            stk_limb: old_wires.stk_limb.clone(),
+            stk_span: old_wires.stk_span.clone(),
+            doc_chal: old_wires.doc_chal.clone(),
         }
     }
 
@@ -199,6 +263,15 @@ impl<F: ArkPrimeField> CoralWires<F> {
                 .unwrap(),
             np_sp: FpVar::<F>::new_witness(cs.clone(), || Ok(irw.np_sp)).unwrap(),
             atom_sp: FpVar::<F>::new_witness(cs.clone(), || Ok(irw.atom_sp)).unwrap(),
+            stk_kind: FpVar::<F>::new_witness(cs.clone(), || Ok(irw.stk_kind)).unwrap(),
+            stk_mult: FpVar::<F>::new_witness(cs.clone(), || Ok(irw.stk_mult)).unwrap(),
+            stk_limb: FpVar::<F>::new_witness(cs.clone(), || Ok(irw.stk_limb)).unwrap(),
+            stk_span: irw
+                .stk_span
+                .iter()
+                .map(|x| FpVar::<F>::new_witness(cs.clone(), || Ok(x)).unwrap())
+                .collect(),
+            doc_chal: FpVar::<F>::new_witness(cs.clone(), || Ok(csc.doc_chal)).unwrap(),
         }
     }
 }
@@ -216,6 +289,10 @@ pub struct InterRoundWires<F: ArkPrimeField> {
     pub prev_step_t_ops: bool,
     pub count: F,
     pub doc_ctr: F,
+    pub stk_kind: F,
+    pub stk_mult: F,
+    pub stk_limb: F,
+    pub stk_span: Vec<F>,
 }
 
 impl<F: ArkPrimeField> Default for InterRoundWires<F> {
@@ -238,6 +315,10 @@ impl<F: ArkPrimeField> InterRoundWires<F> {
             prev_step_t_ops: false,
             atom_sp: F::ZERO,
             np_sp: F::ZERO,
+            stk_kind: F::ZERO,
+            stk_mult: F::ONE,
+            stk_limb: F::ZERO,
+            stk_span: vec![F::ZERO; STACK_SPAN_LIMBS],
         }
     }
 
@@ -253,6 +334,104 @@ impl<F: ArkPrimeField> InterRoundWires<F> {
         self.prev_step_t_ops = res.prev_step_t_ops.value().unwrap();
         self.atom_sp = res.atom_sp.value().unwrap();
         self.np_sp = res.np_sp.value().unwrap();
+        self.stk_kind = res.stk_kind.value().unwrap();
+        self.stk_mult = res.stk_mult.value().unwrap();
+        self.stk_limb = res.stk_limb.value().unwrap();
+        self.stk_span = res.stk_span.iter().map(|x| x.value().unwrap()).collect();
+    }
+}
+
+// Public IO of one step, in the order ivcify allocates it. The running memory comes first
+// (its accumulators, then one pointer per stack), followed by Coral's own state.
+#[derive(Clone, Debug)]
+pub struct PublicIoLayout {
+    pub mem_accs: Range<usize>,
+    pub stack_ptrs: Range<usize>,
+    pub running_eval: usize,
+    pub doc_chal: usize,
+    pub doc_ctr: usize,
+    pub cur_node_id: usize,
+    pub parent_id: usize,
+    pub np_rule: usize,
+    pub atom_parent_id: usize,
+    pub atom_flag: usize,
+    pub np_parent_id: usize,
+    pub count: usize,
+    //stk_kind, stk_mult, stk_limb and the span limbs, empty without the delimiter stack
+    pub stk: Range<usize>,
+    pub grammar_digest: usize,
+}
+
+impl PublicIoLayout {
+    // Rule and transition stacks, these must be empty once the tree is done
+    pub const N_TREE_STACKS: usize = 2;
+
+    pub fn n_stacks(has_stack: bool) -> usize {
+        Self::N_TREE_STACKS + has_stack as usize
+    }
+
+    pub fn coral_len(has_stack: bool) -> usize {
+        11 + if has_stack { 3 + STACK_SPAN_LIMBS } else { 0 }
+    }
+
+    pub fn new(z_len: usize, has_stack: bool) -> Self {
+        assert!(z_len >= Self::n_stacks(has_stack) + Self::coral_len(has_stack));
+        let coral = z_len - Self::coral_len(has_stack);
+        let stacks = coral - Self::n_stacks(has_stack);
+        let stk_len = Self::coral_len(has_stack) - 11;
+
+        PublicIoLayout {
+            mem_accs: 0..stacks,
+            stack_ptrs: stacks..coral,
+            running_eval: coral,
+            doc_chal: coral + 1,
+            doc_ctr: coral + 2,
+            cur_node_id: coral + 3,
+            parent_id: coral + 4,
+            np_rule: coral + 5,
+            atom_parent_id: coral + 6,
+            atom_flag: coral + 7,
+            np_parent_id: coral + 8,
+            count: coral + 9,
+            stk: coral + 10..coral + 10 + stk_len,
+            grammar_digest: coral + 10 + stk_len,
+        }
+    }
+
+    // Every step 0 slot. The memory accumulators are the ones the public circuit starts from.
+    pub fn initial_state<F: ArkPrimeField>(
+        &self,
+        tree_null_val: usize,
+        grammar_digest: F,
+        doc_chal: F,
+        mem_accs: &[F],
+    ) -> Vec<(usize, F)> {
+        let irw = InterRoundWires::<F>::new();
+
+        let mut state: Vec<(usize, F)> = self
+            .mem_accs
+            .clone()
+            .zip(mem_accs.iter().copied())
+            .collect();
+        state.extend(self.stack_ptrs.clone().map(|i| (i, F::ONE)));
+        state.extend([
+            (self.running_eval, irw.running_eval),
+            (self.doc_chal, doc_chal),
+            (self.doc_ctr, irw.doc_ctr),
+            (self.cur_node_id, F::ZERO),
+            (self.parent_id, to_F(tree_null_val)),
+            (self.np_rule, irw.np_rule),
+            (self.atom_parent_id, irw.atom_parent_id),
+            (self.atom_flag, to_F(irw.atom_flag as usize)),
+            (self.np_parent_id, irw.np_parent_id),
+            (self.count, irw.count),
+        ]);
+        let stk = [irw.stk_kind, irw.stk_mult, irw.stk_limb]
+            .into_iter()
+            .chain(irw.stk_span);
+        state.extend(self.stk.clone().zip(stk));
+        state.push((self.grammar_digest, grammar_digest));
+        state
     }
 }
 
@@ -262,6 +441,7 @@ pub fn make_rule_vector<F: ArkPrimeField>(g: &GrammarGraph) -> Vec<Vec<F>> {
     for (rule_name, rules) in g.rules.iter() {
         let is_atomic = g.atom.contains(&rule_name.clone());
         let is_np = g.np_rule_names.contains(&rule_name.clone());
+        let ranges = g.ranges.get(rule_name);
         for i in 0..rules.len() {
             let mut rule: Vec<F> = rules[i].iter().map(|x| coral_hash(x)).collect();
             let rule_len = rule.len();
@@ -270,33 +450,78 @@ pub fn make_rule_vector<F: ArkPrimeField>(g: &GrammarGraph) -> Vec<Vec<F>> {
             }
             rule.push(to_F(is_atomic as usize));
             rule.push(to_F(is_np as usize));
+            rule.push(to_F(GrammarGraph::stack_kind(rule_name)));
+            //Half-open code point interval of a character class
+            let (lo, end) = ranges.map_or((0, 0), |r| (r[i].0 as usize, r[i].1 as usize + 1));
+            rule.push(to_F(lo));
+            rule.push(to_F(end));
             out.push(rule.clone());
         }
     }
     out.sort();
 
     let mut any = vec![F::ZERO, coral_hash("terminal_ANY")];
-    for _ in 0..g.max_rule_size {
+    for _ in 0..g.max_rule_size + 3 {
         any.push(F::ZERO);
     }
     out.push(any);
     out
 }
 
-pub fn make_np_vector<F: ArkPrimeField>(g: &GrammarGraph) -> Vec<Vec<F>> {
+// Predicate rows are the rule name, 1 for a PosPred (0 for a NegPred), then the characters
+pub fn np_size(g: &GrammarGraph) -> usize {
+    max(g.max_np_rule_size, 1) + 2
+}
+
+// Characters the predicates read at once, 1 unless one excludes a longer string
+pub fn np_lookahead(g: &GrammarGraph) -> usize {
+    g.np.values()
+        .flat_map(|(vals, _)| vals.iter())
+        .map(|val| val.chars().count())
+        .max()
+        .unwrap_or(1)
+        .max(1)
+}
+
+// Memory and stack operations of one tree node, every step of the circuit pays for them
+pub fn node_ops(g: &GrammarGraph) -> (usize, usize) {
+    let np_lookahead = np_lookahead(g);
+    //Only multi-character predicates read the document
+    let doc_reads = if np_lookahead > 1 { np_lookahead } else { 0 };
+    //The delimiter stack pops and pushes once more
+    let stack_ops = if g.has_stack() { 5 } else { 3 };
+    (3 + doc_reads, g.max_rule_size + stack_ops)
+}
+
+// Code points 32 bits apart, with the length above them so windows of every length can share
+// one vanishing polynomial
+pub fn np_pack<F: ArkPrimeField>(val: &str) -> F {
+    let shift = F::from(1u64 << 32);
+    let mut packed = F::ZERO;
+    let mut power = F::ONE;
+    for c in val.chars() {
+        packed += F::from(c as u32) * power;
+        power *= shift;
+    }
+    packed + F::from(val.chars().count() as u64) * shift.pow([NP_MAX_LOOKAHEAD as u64])
+}
+
+fn make_np_row<F: ArkPrimeField>(g: &GrammarGraph, rule_name: &str, vals: &[String]) -> Vec<F> {
     let np_filler: F = to_F(std::u32::MAX as usize + 1);
-    let np_rule_len = max(g.max_np_rule_size + 1, 1);
-    let mut out: Vec<Vec<F>> = Vec::new();
-    for (rule_name, rule) in g.np.iter() {
-        let mut np_rule: Vec<F> = vec![coral_hash(rule_name)];
-        for val in rule.0.iter() {
-            np_rule.push(coral_hash(val));
-        }
-        for _ in 0..np_rule_len - np_rule.len() {
-            np_rule.push(np_filler);
-        }
-        out.push(np_rule.clone());
+    let is_pp = g.pp_rule_names.contains(rule_name);
+    let mut np_rule: Vec<F> = vec![coral_hash(rule_name), to_F(is_pp as usize)];
+    for val in vals.iter() {
+        np_rule.push(np_pack(val));
     }
+    np_rule.resize(np_size(g), np_filler);
+    np_rule
+}
+
+pub fn make_np_vector<F: ArkPrimeField>(g: &GrammarGraph) -> Vec<Vec<F>> {
+    let mut out: Vec<Vec<F>> =
+        g.np.iter()
+            .map(|(rule_name, rule)| make_np_row(g, rule_name, &rule.0))
+            .collect();
     out.sort();
 
     out
@@ -305,12 +530,8 @@ pub fn make_np_vector<F: ArkPrimeField>(g: &GrammarGraph) -> Vec<Vec<F>> {
 pub fn make_whitespace_vec<F: ArkPrimeField>(g: &GrammarGraph) -> Vec<F> {
     let ws_filler: F = to_F(std::u32::MAX as usize + 1);
     let mut out: Vec<F> = vec![ws_filler];
-    let symbols = g.rules.get("WHITESPACE");
-    match symbols {
-        None => {
-            return out;
-        }
-        Some(rules) => {
+    for implicit in ["WHITESPACE", "COMMENT"] {
+        if let Some(rules) = g.rules.get(implicit) {
             for rule in rules.iter() {
                 out.push(coral_hash(&rule[0]));
             }
@@ -319,6 +540,40 @@ pub fn make_whitespace_vec<F: ArkPrimeField>(g: &GrammarGraph) -> Vec<F> {
     out
 }
 
+// Canonical digest of the transformed grammar tables, every proof is bound to it
+pub fn grammar_digest<F: ArkPrimeField>(g: &GrammarGraph) -> F {
+    let mut bytes = Vec::new();
+    let tables: [(&[u8], Vec<Vec<F>>); 3] = [
+        (b"rules", make_rule_vector(g)),
+        (b"np", make_np_vector(g)),
+        (b"whitespace", vec![make_whitespace_vec(g)]),
+    ];
+    for (label, table) in tables.iter() {
+        bytes.extend_from_slice(label);
+        table.serialize_compressed(&mut bytes).unwrap();
+    }
+
+    let hash = Sha256::digest(&bytes);
+    F::from_le_bytes_mod_order(&hash)
+}
+
+// Point the document polynomial is opened at. It is hashed from the doc commitment, the
+// grammar and the memory challenges, so the verifier recomputes it instead of reading it
+// from the proof.
+pub fn doc_challenge<F: ArkPrimeField>(
+    doc_commit: &impl CanonicalSerialize,
+    grammar_digest: F,
+    perm_chal: &[F],
+) -> F {
+    let mut bytes = b"doc_chal".to_vec();
+    doc_commit.serialize_compressed(&mut bytes).unwrap();
+    grammar_digest.serialize_compressed(&mut bytes).unwrap();
+    perm_chal.serialize_compressed(&mut bytes).unwrap();
+
+    let hash = Sha256::digest(&bytes);
+    F::from_le_bytes_mod_order(&hash)
+}
+
 pub fn vec_search<F: ArkPrimeField>(find: &Vec<F>, among: &Vec<Vec<F>>) -> usize {
     let loc = among.iter().position(|x| x == find);
     assert!(loc.is_some());
@@ -331,12 +586,13 @@ pub fn to_F<F: ArkPrimeField>(num: usize) -> F {
     F::from(num as u64)
 }
 
-pub fn make_rule<F: ArkPrimeField>(g: &GrammarGraph, node: &NodeElem<F>, whitespace: F) -> Vec<F> {
+// Implicit WHITESPACE and COMMENT children are never part of a rule row
+pub fn make_rule<F: ArkPrimeField>(g: &GrammarGraph, node: &NodeElem<F>, implicit: &[F]) -> Vec<F> {
     let mut rule: Vec<F> = g
         .get_all_children(node.id)
         .iter()
         .map(|x| coral_hash(&x.rule_name))
-        .filter(|x| *x != whitespace)
+        .filter(|x| !implicit.contains(x))
         .collect();
 
     rule.reverse();
@@ -344,36 +600,61 @@ pub fn make_rule<F: ArkPrimeField>(g: &GrammarGraph, node: &NodeElem<F>, whitesp
     rule
 }
 
-pub fn converted_np_map<F: ArkPrimeField>(
-    g: &GrammarGraph,
-    vals_size: usize,
-) -> HashMap<F, Vec<F>> {
-    let np_filler: F = to_F(std::u32::MAX as usize + 1);
+// Leaf characters in id order, which is the order the circuit visits them
+pub fn doc_symbols<F: ArkPrimeField>(g: &GrammarGraph) -> Vec<F> {
+    let mut leaves: Vec<&LcrsGraphNode> = g
+        .lcrs_tree
+        .node_weights()
+        .filter(|node| node.is_terminal && !node.rule_name.is_empty())
+        .collect();
+    leaves.sort_by_key(|node| node.id);
+    leaves
+        .iter()
+        .map(|node| coral_hash(&node.rule_name))
+        .collect()
+}
+
+pub fn converted_np_map<F: ArkPrimeField>(g: &GrammarGraph) -> HashMap<F, Vec<F>> {
     let mut out: HashMap<F, Vec<F>> = new_hash_map();
     for (rule, poly) in g.np.iter() {
-        let mut vec: Vec<F> = vec![coral_hash(rule)];
-        for val in &poly.0 {
-            vec.push(coral_hash(val));
-        }
-        for _ in 0..vals_size - vec.len() {
-            vec.push(np_filler);
-        }
-        out.insert(coral_hash(rule), vec);
+        out.insert(coral_hash(rule), make_np_row(g, rule, &poly.0));
     }
     out
 }
 
+// Open PUSH, POP, PEEK or DROP scope while solving, mirrors the stk_* wires
+struct DelimScope<F: ArkPrimeField> {
+    kind: usize,
+    mult: F,
+    limb: usize,
+    span: Vec<F>,
+}
+
+impl<F: ArkPrimeField> DelimScope<F> {
+    fn new() -> Self {
+        DelimScope {
+            kind: 0,
+            mult: F::ONE,
+            limb: 0,
+            span: vec![F::ZERO; STACK_SPAN_LIMBS],
+        }
+    }
+}
+
 #[derive(Debug, Clone, CanonicalDeserialize, CanonicalSerialize)]
 pub struct CoralStepCircuit<F: ArkPrimeField> {
     //empty bool
     pub empty: bool,
     //Blind for hashchain
-    pub blind: F,
+    pub blind_roots: Vec<F>,
+    //Evaluation point for the doc commitment, see doc_challenge
+    pub doc_chal: F,
     //Public Values
     pub ws_pts: Vec<F>,
     pub rule_size: usize,
     pub n_rules: usize,
     pub whitespace_rule_val: F,
+    pub comment_rule_val: F,
     pub any_rule_val: F,
     pub epsilon_val: F,
     pub tree_ram_offset: usize,
@@ -382,17 +663,31 @@ pub struct CoralStepCircuit<F: ArkPrimeField> {
     pub rule_ram_offset: usize,
     pub np_ram_tag: usize,
     pub np_ram_offset: usize,
+    pub doc_ram_tag: usize,
+    pub doc_ram_offset: usize,
     pub rule_stack_tag: usize,
     pub trans_stack_tag: usize,
+    pub delim_stack_tag: usize,
     pub mem_ops: usize,
     pub stack_ops: usize,
     pub batch_size: usize,
     pub atom: HashSet<F>,
     pub np: HashSet<F>,
+    pub stack_push: HashSet<F>,
+    //POP, PEEK and DROP
+    pub stack_op_vals: [F; 3],
+    pub stack_end_val: F,
+    pub span_limb_cap: F,
+    //Character class, low code point, end code point (exclusive)
+    pub char_ranges: Vec<[F; 3]>,
     pub n_np: usize,
     pub np_size: usize,
+    pub np_lookahead: usize,
+    //Delimiter stack, its public IO and its ops are only there for PUSH and POP
+    pub has_stack: bool,
     pub negative_one: F,
     pub shift_powers: [F; 7],
+    pub grammar_digest: F,
     //Private Tree Information
     pub tree_size: F,
     pub tree_size_usize: usize,
@@ -414,14 +709,26 @@ pub struct CoralStepCircuit<F: ArkPrimeField> {
 }
 
 impl<F: ArkPrimeField> CoralStepCircuit<F> {
-    pub fn new(g: &GrammarGraph, batch_size: usize, doc_blind: F) -> Self {
+    // tree_size stays private, only tree_bucket shapes the circuit
+    pub fn with_tree_size(
+        g: &GrammarGraph,
+        batch_size: usize,
+        doc_blind_roots: Vec<F>,
+        tree_size: usize,
+        tree_bucket: usize,
+    ) -> Self {
+        assert!(tree_size <= tree_bucket);
         let epsilon_val_hash: F = coral_hash("");
 
-        let tree_size = g.lcrs_tree.node_count();
-
         let tree_ram_offset = 1;
-        let rule_ram_offset = tree_ram_offset + (tree_size + 1);
+        let rule_ram_offset = tree_ram_offset + (tree_bucket + 1);
         let np_ram_offset = rule_ram_offset + g.rule_count + 1;
+        let doc_ram_offset = np_ram_offset + max(g.np.len(), 1);
+        let np_lookahead = np_lookahead(g);
+        let has_stack = g.has_stack();
+        //RAM tags follow the stacks
+        let ram_tag = if has_stack { 3 } else { 2 };
+        let (node_mem_ops, node_stack_ops) = node_ops(g);
         let mut shift_powers = [F::ONE; 7];
         let mut power = F::from(1u64 << 32);
         for p in &mut shift_powers[1..] {
@@ -429,42 +736,71 @@ impl<F: ArkPrimeField> CoralStepCircuit<F> {
             power.square_in_place();
         }
 
-        let np_size = max(g.max_np_rule_size + 1, 1);
+        let np_size = np_size(g);
+
+        let span_limb_cap = (0..7).fold(F::ONE, |cap, _| cap * shift_powers[1]);
 
         Self {
             empty: false,
-            //Blind for KZG
-            blind: doc_blind,
+            //Blind and padding roots for KZG
+            blind_roots: doc_blind_roots,
+            //Set by the prover once the doc commitment is known
+            doc_chal: F::ZERO,
             //Public Values
             negative_one: F::from(-1),
             ws_pts: make_whitespace_vec(g),
             rule_size: g.max_rule_size,
             n_rules: g.rule_count + 1,
             whitespace_rule_val: coral_hash("WHITESPACE"),
+            comment_rule_val: coral_hash("COMMENT"),
             any_rule_val: coral_hash("terminal_ANY"),
             epsilon_val: epsilon_val_hash,
             batch_size,
             atom: g.atom.iter().map(|x| coral_hash(x)).collect(),
             np: g.np_rule_names.iter().map(|x| coral_hash(x)).collect(),
+            stack_push: g
+                .rules
+                .keys()
+                .filter(|x| GrammarGraph::stack_kind(x) == 1)
+                .map(|x| coral_hash(x))
+                .collect(),
+            stack_op_vals: ["terminal_POP", "terminal_PEEK", "terminal_DROP"].map(coral_hash),
+            stack_end_val: coral_hash(STACK_END),
+            span_limb_cap,
+            char_ranges: g
+                .ranges
+                .iter()
+                .flat_map(|(name, ranges)| {
+                    ranges
+                        .iter()
+                        .map(move |&(lo, hi)| [coral_hash(name), F::from(lo), F::from(hi + 1)])
+                })
+                .collect(),
             n_np: g.np.len(),
             np_size,
+            np_lookahead,
+            has_stack,
             shift_powers,
+            grammar_digest: grammar_digest(g),
             //Private Tree Info
-            tree_null_val: tree_size,
+            tree_null_val: tree_bucket,
             tree_size_usize: tree_size,
             tree_size: to_F(tree_size),
             //Memory
             mem: None,
             rule_stack_tag: 0,
             trans_stack_tag: 1,
+            delim_stack_tag: 2,
             tree_ram_offset,
-            tree_ram_tag: 2,
-            rule_ram_tag: 3,
+            tree_ram_tag: ram_tag,
+            rule_ram_tag: ram_tag + 1,
             rule_ram_offset,
-            np_ram_tag: 4,
+            np_ram_tag: ram_tag + 2,
             np_ram_offset,
-            mem_ops: 3 * batch_size,
-            stack_ops: (g.max_rule_size + 3) * batch_size,
+            doc_ram_tag: ram_tag + 3,
+            doc_ram_offset,
+            mem_ops: node_mem_ops * batch_size,
+            stack_ops: node_stack_ops * batch_size,
             key_length: 0,
             //Rule lookup witnesses
             switch_wits: Vec::new(),
@@ -485,11 +821,30 @@ impl<F: ArkPrimeField> CoralStepCircuit<F> {
         F::from(num as u64)
     }
 
+    // Same numbering as GrammarGraph::stack_kind
+    pub fn stack_kind(&self, symbol: F) -> usize {
+        if self.stack_push.contains(&symbol) {
+            return 1;
+        }
+        match self.stack_op_vals.iter().position(|x| *x == symbol) {
+            Some(i) => i + 2,
+            None => 0,
+        }
+    }
+
+    // Interval of a character class that holds the character, zeros for any other rule
+    pub fn char_range(&self, symbol: F, c: F) -> [F; 2] {
+        self.char_ranges
+            .iter()
+            .find(|row| row[0] == symbol && row[1] <= c && c < row[2])
+            .map_or([F::ZERO; 2], |row| [row[1], row[2]])
+    }
+
     pub fn make_emtpy(self) -> Self {
         let mut empty = self.clone();
 
         empty.empty = true;
-        empty.blind = F::ZERO;
+        empty.blind_roots = Vec::new();
 
         let filler_vec_rule: Vec<F> = (0..self.rule_size).map(|_| F::ZERO).collect();
         let filler_vec_np: Vec<F> = (0..self.np_size).map(|_| F::ZERO).collect();
@@ -504,14 +859,7 @@ impl<F: ArkPrimeField> CoralStepCircuit<F> {
         empty.parent_node_wits = Vec::new();
         empty.switch_wits = Vec::new();
 
-        let dead_node = NodeElem {
-            id: empty.tree_null_val,
-            terminal: true,
-            parent: empty.tree_null_val,
-            symbol: F::ZERO,
-            child: empty.tree_null_val,
-            sib: empty.tree_null_val,
-        };
+        let dead_node = empty.dead_node();
 
         for _ in 0..self.batch_size {
             empty.node_wits.push(dead_node.clone());
@@ -529,13 +877,29 @@ impl<F: ArkPrimeField> CoralStepCircuit<F> {
     }
 
     pub fn init_set(&mut self, g: &GrammarGraph) -> (MemBuilder<F>, Vec<Vec<F>>, Vec<Vec<F>>) {
-        let mut mem_builder = MemBuilder::new(vec![
+        let mut mem_types = vec![
             MemType::PrivROM(self.tree_ram_tag, 5),
-            MemType::PubROM(self.rule_ram_tag, self.rule_size + 2),
+            MemType::PubROM(self.rule_ram_tag, self.rule_size + 5),
             MemType::PubROM(self.np_ram_tag, self.np_size),
             MemType::Stack(self.rule_stack_tag, 2),
             MemType::Stack(self.trans_stack_tag, 2),
-        ]);
+        ];
+        if self.has_stack {
+            mem_types.push(MemType::Stack(self.delim_stack_tag, STACK_SPAN_LIMBS));
+        }
+        if self.np_lookahead > 1 {
+            mem_types.push(MemType::PrivROM(self.doc_ram_tag, 1));
+        }
+        let mut mem_builder = MemBuilder::new(mem_types);
+
+        //Document characters in tree order, zero past the end so the size only shows the bucket
+        if self.np_lookahead > 1 {
+            let doc = doc_symbols::<F>(g);
+            for i in 0..self.tree_null_val + self.np_lookahead {
+                let c = doc.get(i).copied().unwrap_or(F::ZERO);
+                mem_builder.init(i + self.doc_ram_offset, vec![c], self.doc_ram_tag);
+            }
+        }
 
         let np_vector = make_np_vector(g);
 
@@ -571,6 +935,7 @@ impl<F: ArkPrimeField> CoralStepCircuit<F> {
     pub fn solve(
         &mut self,
         g: &GrammarGraph,
+        srs: &Srs,
     ) -> Result<(Vec<Vec<N1>>, Vec<Vec<N1>>, CoralStepCircuit<F>), SynthesisError> {
         #[cfg(feature = "metrics")]
         {
@@ -580,12 +945,12 @@ impl<F: ArkPrimeField> CoralStepCircuit<F> {
 
         let (mut mem_builder, np_vec, rule_vec) = self.init_set(g);
 
-        let converted_np_map = converted_np_map(g, self.np_size);
+        let converted_np_map = converted_np_map(g);
 
-        let ws_f = coral_hash("WHITESPACE");
+        let implicit = [self.whitespace_rule_val, self.comment_rule_val];
 
         let mut any = vec![F::ZERO, self.any_rule_val];
-        for _ in 0..self.rule_size {
+        for _ in 0..self.rule_size + 3 {
             any.push(F::ZERO);
         }
 
@@ -595,7 +960,7 @@ impl<F: ArkPrimeField> CoralStepCircuit<F> {
 
         let atom_f_set: HashSet<F> = g.atom.iter().map(|x| coral_hash(x)).collect();
 
-        let mut node = make_node_elem(0, g);
+        let mut node = make_node_elem(0, g, self.tree_null_val);
 
         let mut parent = NodeElem {
             id: self.tree_null_val,
@@ -618,8 +983,12 @@ impl<F: ArkPrimeField> CoralStepCircuit<F> {
         let filler_vec_stack: Vec<F> = (0..2).map(|_| F::ZERO).collect();
 
         let mut np_rule: F = F::ZERO;
+        let mut doc_ctr = 0;
+
+        let mut scope = DelimScope::new();
 
         for w in 0..g.lcrs_tree.node_count() {
+            let cur = node.clone();
             self.node_wits.push(node.clone());
             node.clone().mem_init(self, &mut mem_builder);
             mem_builder.read(node.id + self.tree_ram_offset, self.tree_ram_tag);
@@ -630,7 +999,7 @@ impl<F: ArkPrimeField> CoralStepCircuit<F> {
                 np_rule = node.symbol;
             };
 
-            if node.symbol != ws_f {
+            if !implicit.contains(&node.symbol) {
                 let pop_cond = node.id != 0;
                 let top = mem_builder.cond_pop(pop_cond, self.rule_stack_tag);
                 if pop_cond {
@@ -662,6 +1031,12 @@ impl<F: ArkPrimeField> CoralStepCircuit<F> {
                 }
                 mem_builder.cond_read(is_np_rule, np_rule_addr, self.np_ram_tag);
 
+                let is_char = node.symbol != self.epsilon_val;
+                self.doc_step(&mut mem_builder, is_char, is_np_rule, doc_ctr);
+                if is_char {
+                    doc_ctr += 1;
+                }
+
                 let should_trans_pop =
                     (w < g.lcrs_tree.node_count() - 1) & (node.sib == self.tree_null_val);
                 mem_builder.cond_pop(should_trans_pop, self.trans_stack_tag);
@@ -675,12 +1050,12 @@ impl<F: ArkPrimeField> CoralStepCircuit<F> {
                     match next {
                         None => {}
                         Some(t) => {
-                            node = make_node_elem(t.0, g);
-                            parent = make_node_elem(t.1, g);
+                            node = make_node_elem(t.0, g, self.tree_null_val);
+                            parent = make_node_elem(t.1, g, self.tree_null_val);
                         }
                     }
                 } else if node.sib != self.tree_null_val {
-                    node = make_node_elem(node.sib, g);
+                    node = make_node_elem(node.sib, g, self.tree_null_val);
                 }
                 //not terminal trans push
                 mem_builder.cond_push(false, self.trans_stack_tag, filler_vec_stack.clone());
@@ -702,11 +1077,12 @@ impl<F: ArkPrimeField> CoralStepCircuit<F> {
                 self.np_memory_vec_wits.push(filler_vec_np.clone());
                 self.np_memory_addr_wits.push(0);
                 mem_builder.cond_read(false, 0, self.np_ram_tag);
+                self.doc_step(&mut mem_builder, false, false, 0);
 
                 //terminal trans stack pop
                 mem_builder.cond_pop(false, self.trans_stack_tag);
 
-                let mut children_rule: Vec<F> = make_rule(g, &node, self.whitespace_rule_val);
+                let mut children_rule: Vec<F> = make_rule(g, &node, &implicit);
 
                 if node.symbol == self.any_rule_val {
                     assert!(children_rule.len() == 2)
@@ -750,8 +1126,19 @@ impl<F: ArkPrimeField> CoralStepCircuit<F> {
                 };
                 children_rule.push(np);
 
+                children_rule.push(to_F(self.stack_kind(node.symbol)));
+
+                let [lo, end] = self.char_range(node.symbol, children_rule[0]);
+                children_rule.push(lo);
+                children_rule.push(end);
+
                 let addr = if node.symbol == self.any_rule_val {
                     any_addr
+                } else if end != F::ZERO {
+                    //Class rows hold no character, the circuit checks the interval
+                    let mut class_row = children_rule.clone();
+                    class_row[0] = F::ZERO;
+                    vec_search(&class_row, &rule_vec) + self.rule_ram_offset
                 } else {
                     vec_search(&children_rule, &rule_vec) + self.rule_ram_offset
                 };
@@ -776,93 +1163,245 @@ impl<F: ArkPrimeField> CoralStepCircuit<F> {
                 mem_builder.read(addr, self.rule_ram_tag);
 
                 parent = node.clone();
-                node = make_node_elem(node.child, g);
+                node = make_node_elem(node.child, g, self.tree_null_val);
+            }
+
+            if self.has_stack {
+                self.delim_step(&cur, &mut scope, &mut mem_builder)?;
+            }
+        }
+
+        //Ids between the real tree and the bucket hold dead nodes
+        for id in g.lcrs_tree.node_count()..self.tree_null_val {
+            let mut dead_node = self.dead_node();
+            dead_node.id = id;
+            dead_node.mem_init(self, &mut mem_builder);
+        }
+
+        let padding_needed = self.num_steps() * self.batch_size - g.lcrs_tree.node_count();
+
+        for _ in 0..padding_needed {
+            self.push_dead_node(&mut mem_builder);
+        }
+
+        #[cfg(feature = "metrics")]
+        log::stop(Component::Solver, "wit_solving");
+
+        #[cfg(feature = "metrics")]
+        log::tic(Component::Solver, "ic");
+        println!("rule size {:?} ", self.rule_size);
+        let (blinds, ram_hints) = self.build_running_mem(mem_builder, srs);
+
+        #[cfg(feature = "metrics")]
+        {
+            log::stop(Component::Solver, "ic");
+            log::tic(Component::Solver, "e2e_solving");
+        }
+
+        let empty = self.clone().make_emtpy();
+
+        Ok((blinds, ram_hints, empty))
+    }
+
+    // Document reads of one node, in the order doc_lookahead issues them in the circuit: the
+    // character itself, then the rest of a predicate's window
+    fn doc_step(
+        &self,
+        mem_builder: &mut MemBuilder<F>,
+        is_char: bool,
+        is_np: bool,
+        doc_ctr: usize,
+    ) {
+        if self.np_lookahead < 2 {
+            return;
+        }
+        let addr = |cond: bool, j: usize| {
+            if cond {
+                doc_ctr + j + self.doc_ram_offset
+            } else {
+                0
+            }
+        };
+        mem_builder.cond_read(is_char, addr(is_char, 0), self.doc_ram_tag);
+        for j in 1..self.np_lookahead {
+            mem_builder.cond_read(is_np, addr(is_np, j), self.doc_ram_tag);
+        }
+    }
+
+    // Delimiter stack ops of one node, in the order delim_stack issues them in the circuit
+    fn delim_step(
+        &self,
+        node: &NodeElem<F>,
+        scope: &mut DelimScope<F>,
+        mem_builder: &mut MemBuilder<F>,
+    ) -> Result<(), SynthesisError> {
+        if node.terminal && scope.kind != 0 && node.symbol != self.epsilon_val {
+            //build_graph rejects longer spans, a tree that got past it can't be proven
+            if scope.limb >= STACK_SPAN_LIMBS {
+                return Err(SynthesisError::Unsatisfiable);
+            }
+            scope.span[scope.limb] += node.symbol * scope.mult;
+            scope.mult *= self.shift_powers[1];
+            if scope.mult == self.span_limb_cap {
+                scope.mult = F::ONE;
+                scope.limb += 1;
+            }
+        }
+
+        let is_end = !node.terminal && node.symbol == self.stack_end_val;
+
+        let pop_cond = is_end && scope.kind >= 2;
+        let top = mem_builder.cond_pop(pop_cond, self.delim_stack_tag);
+        if pop_cond && scope.kind != 4 && top != scope.span {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+
+        let push_cond = is_end && (scope.kind == 1 || scope.kind == 3);
+        let push_val = if push_cond {
+            scope.span.clone()
+        } else {
+            vec![F::ZERO; STACK_SPAN_LIMBS]
+        };
+        mem_builder.cond_push(push_cond, self.delim_stack_tag, push_val);
+
+        if is_end {
+            *scope = DelimScope::new();
+        }
+
+        let kind = if node.terminal {
+            0
+        } else {
+            self.stack_kind(node.symbol)
+        };
+        if kind != 0 {
+            if scope.kind != 0 {
+                return Err(SynthesisError::Unsatisfiable);
             }
+            scope.kind = kind;
         }
 
-        let dead_node = NodeElem {
+        Ok(())
+    }
+
+    pub fn num_steps(&self) -> usize {
+        usize::div_ceil(self.tree_null_val, self.batch_size)
+    }
+
+    fn dead_node(&self) -> NodeElem<F> {
+        NodeElem {
             id: self.tree_null_val,
             terminal: true,
             parent: self.tree_null_val,
             symbol: F::ZERO,
             child: self.tree_null_val,
             sib: self.tree_null_val,
-        };
-
-        let padding_needed = if g.lcrs_tree.node_count().is_multiple_of(self.batch_size) {
-            0
-        } else {
-            self.batch_size - (g.lcrs_tree.node_count() % self.batch_size)
-        };
+        }
+    }
 
-        for _ in 0..padding_needed {
-            self.node_wits.push(dead_node.clone());
-            self.parent_node_wits.push(dead_node.clone());
+    // Fills one node slot with a dead node; every memory op is issued with a false condition
+    fn push_dead_node(&mut self, mem_builder: &mut MemBuilder<F>) {
+        let dead_node = self.dead_node();
+        let filler_vec_rule: Vec<F> = (0..self.rule_size).map(|_| F::ZERO).collect();
+        let filler_vec_np: Vec<F> = (0..self.np_size).map(|_| F::ZERO).collect();
+        let filler_vec_stack: Vec<F> = (0..2).map(|_| F::ZERO).collect();
 
-            self.np_memory_vec_wits.push(filler_vec_np.clone());
-            self.np_memory_addr_wits.push(0);
+        self.node_wits.push(dead_node.clone());
+        self.parent_node_wits.push(dead_node);
 
-            self.rule_memory_addr_wits.push(0);
-            self.rule_memory_vec_wits.push(filler_vec_rule.clone());
-            self.switch_wits.push(F::zero());
+        self.np_memory_vec_wits.push(filler_vec_np);
+        self.np_memory_addr_wits.push(0);
 
-            //Is node
-            mem_builder.cond_read(
-                false,
-                self.tree_null_val + self.tree_ram_offset,
-                self.tree_ram_tag,
-            );
+        self.rule_memory_addr_wits.push(0);
+        self.rule_memory_vec_wits.push(filler_vec_rule);
+        self.switch_wits.push(F::zero());
 
-            mem_builder.cond_pop(false, self.rule_stack_tag);
+        //Is node
+        mem_builder.cond_read(
+            false,
+            self.tree_null_val + self.tree_ram_offset,
+            self.tree_ram_tag,
+        );
 
-            //Is terminal
-            mem_builder.cond_read(false, 0, self.np_ram_tag);
-            mem_builder.cond_pop(false, self.trans_stack_tag);
+        mem_builder.cond_pop(false, self.rule_stack_tag);
 
-            //Is not terminal
-            mem_builder.cond_push(false, self.trans_stack_tag, filler_vec_stack.clone());
+        //Is terminal
+        mem_builder.cond_read(false, 0, self.np_ram_tag);
+        self.doc_step(mem_builder, false, false, 0);
+        mem_builder.cond_pop(false, self.trans_stack_tag);
 
-            for _ in 0..self.rule_size {
-                mem_builder.cond_push(false, self.rule_stack_tag, filler_vec_stack.clone());
-            }
+        //Is not terminal
+        mem_builder.cond_push(false, self.trans_stack_tag, filler_vec_stack.clone());
 
-            mem_builder.cond_read(false, 0, self.rule_ram_tag);
+        for _ in 0..self.rule_size {
+            mem_builder.cond_push(false, self.rule_stack_tag, filler_vec_stack.clone());
         }
 
-        #[cfg(feature = "metrics")]
-        log::stop(Component::Solver, "wit_solving");
+        mem_builder.cond_read(false, 0, self.rule_ram_tag);
 
-        #[cfg(feature = "metrics")]
-        log::tic(Component::Solver, "ic");
-        println!("rule size {:?} ", self.rule_size);
-        let (blinds, ram_hints, ram_batch_size, rm) = mem_builder.new_running_mem(
-            vec![
-                (self.tree_ram_tag, self.batch_size),
-                (self.np_ram_tag, self.batch_size),
-                (self.rule_ram_tag, self.batch_size),
-            ],
-            vec![
-                (
-                    self.rule_stack_tag,
-                    self.rule_size * self.batch_size,
-                    self.batch_size,
-                ),
-                (self.trans_stack_tag, self.batch_size, self.batch_size),
-            ],
-            false,
-            "./ppot_0080_23.ptau",
-        );
+        //Delimiter stack
+        if self.has_stack {
+            mem_builder.cond_pop(false, self.delim_stack_tag);
+            mem_builder.cond_push(false, self.delim_stack_tag, vec![F::ZERO; STACK_SPAN_LIMBS]);
+        }
+    }
+
+    fn build_running_mem(
+        &mut self,
+        mem_builder: MemBuilder<F>,
+        srs: &Srs,
+    ) -> (Vec<Vec<N1>>, Vec<Vec<N1>>) {
+        let mut rams = vec![
+            (self.tree_ram_tag, self.batch_size),
+            (self.np_ram_tag, self.batch_size),
+            (self.rule_ram_tag, self.batch_size),
+        ];
+        if self.np_lookahead > 1 {
+            rams.push((self.doc_ram_tag, self.np_lookahead * self.batch_size));
+        }
+        let mut stacks = vec![
+            (
+                self.rule_stack_tag,
+                self.rule_size * self.batch_size,
+                self.batch_size,
+            ),
+            (self.trans_stack_tag, self.batch_size, self.batch_size),
+        ];
+        if self.has_stack {
+            stacks.push((self.delim_stack_tag, self.batch_size, self.batch_size));
+        }
+        let (blinds, ram_hints, ram_batch_size, rm) =
+            mem_builder.new_running_mem(rams, stacks, false, &srs.path);
         self.mem = Some(rm);
         self.key_length = ram_batch_size;
 
-        #[cfg(feature = "metrics")]
-        {
-            log::stop(Component::Solver, "ic");
-            log::tic(Component::Solver, "e2e_solving");
+        (blinds, ram_hints)
+    }
+
+    // Builds the empty step circuit using only the public grammar and the public tree bucket.
+    // The private tree ROM is filled with dead nodes and every memory op is issued with a
+    // false condition, so the resulting circuit has the same layout as the prover's.
+    pub fn public_circuit(
+        g: &GrammarGraph,
+        batch_size: usize,
+        tree_bucket: usize,
+        srs: &Srs,
+    ) -> Self {
+        let mut csc = Self::with_tree_size(g, batch_size, Vec::new(), tree_bucket, tree_bucket);
+
+        let (mut mem_builder, _, _) = csc.init_set(g);
+
+        for id in 0..=tree_bucket {
+            let mut node = csc.dead_node();
+            node.id = id;
+            node.mem_init(&csc, &mut mem_builder);
         }
 
-        let empty = self.clone().make_emtpy();
+        for _ in 0..csc.num_steps() * batch_size {
+            csc.push_dead_node(&mut mem_builder);
+        }
 
-        Ok((blinds, ram_hints, empty))
+        csc.build_running_mem(mem_builder, srs);
+
+        csc.make_emtpy()
     }
 }
@@ -1,19 +1,29 @@
-use crate::{parser::GrammarGraph, prover::make_coral_circuit, solver::*};
-use ark_bn254::Bn254;
-use ark_ff::{BigInteger256, FftField, PrimeField};
+use crate::{
+    frontend::read_rules,
+    parser::GrammarGraph,
+    prover::{make_coral_circuit, step_constraints},
+    solver::*,
+};
+use ark_bn254::{Bn254, Fq, Fq2, G1Affine, G2Affine};
+use ark_ff::{BigInt, BigInteger256, FftField, PrimeField, Zero};
 use ark_poly::DenseUVPolynomial;
 use ark_poly::univariate::DensePolynomial;
 use ark_poly_commit::Error;
 use ark_poly_commit::kzg10::{self, Powers, UniversalParams, VerifierKey};
+use ark_serialize::CompressedChecked;
 use csv::Writer;
 use nova_snark::{
-    nova::PublicParams,
+    nova::{self, CompressedSNARK, PublicParams},
     provider::{Bn256EngineKZG, GrumpkinEngine},
     traits::{Engine, snark::default_ck_hint},
 };
-use rand::rngs::OsRng;
 use segmented_circuit_memory::bellpepper::FCircuit;
-use std::fs::OpenOptions;
+use segmented_circuit_memory::memory::nebula::RunningMem;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
 use std::path::PathBuf;
 use std::time::SystemTime;
 use std::{fs, usize};
@@ -25,6 +35,82 @@ pub trait ArkPrimeField: PrimeField<BigInt = BigInteger256> {}
 
 impl<F: PrimeField<BigInt = BigInteger256>> ArkPrimeField for F {}
 
+pub const PTAU_PATH: &str = "./ppot_0080_23.ptau";
+
+// Powers of Tau file shared by Nova, the running memory and the doc commitment. The SHA-256 of
+// its contents is its identity.
+#[derive(Clone, Debug)]
+pub struct Srs {
+    pub path: String,
+    pub sha256: [u8; 32],
+}
+
+// SHA-256 of the Perpetual Powers of Tau files Coral is run with, by file name. Only hashes
+// checked against a downloaded file belong here.
+pub const KNOWN_SRS_SHA256: &[(&str, &str)] = &[];
+
+impl Srs {
+    // Checks the file against the expected hash, given directly, through a sha256sum style
+    // <path>.sha256 file next to it, or known for its file name. A missing or different hash is
+    // an error unless allow_unverified is set.
+    pub fn open(
+        path: &str,
+        expected_sha256: Option<&str>,
+        allow_unverified: bool,
+    ) -> io::Result<Self> {
+        let srs = Srs {
+            path: path.to_string(),
+            sha256: srs_sha256(path)?,
+        };
+
+        let expected = match expected_sha256 {
+            Some(hex) => Some(hex.to_string()),
+            None => fs::read_to_string(format!("{}.sha256", path))
+                .ok()
+                .and_then(|s| s.split_whitespace().next().map(str::to_string))
+                .or_else(|| known_srs_sha256(path).map(str::to_string)),
+        };
+        let problem = match expected {
+            Some(hex) if hex.eq_ignore_ascii_case(&srs.sha256_hex()) => return Ok(srs),
+            Some(hex) => format!(
+                "{} has SHA-256 {}, expected {}",
+                path,
+                srs.sha256_hex(),
+                hex
+            ),
+            None => format!(
+                "no known SHA-256 for {}, pass --srs-sha256 or add {}.sha256",
+                path, path
+            ),
+        };
+
+        if allow_unverified {
+            eprintln!("{}, using it unverified", problem);
+            Ok(srs)
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidData, problem))
+        }
+    }
+
+    pub fn sha256_hex(&self) -> String {
+        self.sha256.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+fn known_srs_sha256(path: &str) -> Option<&'static str> {
+    let name = std::path::Path::new(path).file_name()?.to_str()?;
+    KNOWN_SRS_SHA256
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, hex)| *hex)
+}
+
+fn srs_sha256(path: &str) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut BufReader::new(File::open(path)?), &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
 pub type AF = ark_bn254::Fr;
 pub type PolyBn254 = DensePolynomial<AF>;
 pub type ArkKZG = kzg10::KZG10<Bn254, PolyBn254>;
@@ -101,20 +187,71 @@ pub fn build_root_products_para<F: FftField>(roots: &[F]) -> LDE<F> {
     left_poly * right_poly
 }
 
-pub fn read_graph(pest_file: String, input: String) -> (GrammarGraph, Vec<char>) {
-    let grammar = fs::read_to_string(pest_file).expect("Failed to read grammar file");
+pub fn read_graph(
+    pest_file: String,
+    input: String,
+    max_arity: Option<usize>,
+    collapse_chains: bool,
+) -> (GrammarGraph, Vec<char>) {
+    let rules = read_rules(&pest_file).expect("Failed to read grammar file");
     let input_text = fs::read_to_string(input).expect("Failed to read input file");
 
     let mut grammar_graph = GrammarGraph::new();
+    grammar_graph.max_arity = max_arity;
+    grammar_graph.collapse_chains = collapse_chains;
     grammar_graph
-        .parse_text_and_build_graph(&grammar, &input_text)
+        .parse_rules_and_build_graph(rules, &input_text)
         .expect("Failed to parse input");
 
     grammar_graph.parse_and_convert_lcrs();
     (grammar_graph, input_text.chars().collect())
 }
 
-pub fn gen_pp<AF: ArkPrimeField>(empty_csc: &mut CoralStepCircuit<AF>) -> PublicParams<E1, E2, C1> {
+pub fn read_grammar(
+    pest_file: String,
+    max_arity: Option<usize>,
+    collapse_chains: bool,
+) -> GrammarGraph {
+    let rules = read_rules(&pest_file).expect("Failed to read grammar file");
+
+    let mut grammar_graph = GrammarGraph::new();
+    grammar_graph.max_arity = max_arity;
+    grammar_graph.collapse_chains = collapse_chains;
+    grammar_graph
+        .compile_rules(rules)
+        .expect("Failed to compile grammar");
+    grammar_graph
+}
+
+// What splitting the rules down to max_arity children and collapsing chains do to a proof of
+// the document: the table and row size, the tree size and the estimated constraints, those of
+// one step times a step per batch_size nodes
+pub fn shape_report(
+    before: &GrammarGraph,
+    after: &GrammarGraph,
+    batch_size: usize,
+    srs: &Srs,
+) -> String {
+    let measure = |grammar_graph: &GrammarGraph| {
+        let tree_size = grammar_graph.lcrs_tree.node_count();
+        (
+            grammar_graph.rule_count,
+            grammar_graph.max_rule_size,
+            tree_size,
+            step_constraints(grammar_graph, batch_size, srs) * tree_size.div_ceil(batch_size),
+        )
+    };
+    let (before, after) = (measure(before), measure(after));
+    format!(
+        "rule rows {} -> {}, max_rule_size {} -> {}, tree size {} -> {}, estimated constraints {} -> {}",
+        before.0, after.0, before.1, after.1, before.2, after.2, before.3, after.3
+    )
+}
+
+pub fn gen_pp<AF: ArkPrimeField>(
+    empty_csc: &mut CoralStepCircuit<AF>,
+    srs: &Srs,
+) -> PublicParams<E1, E2, C1> {
     #[cfg(feature = "metrics")]
     log::tic(Component::Generator, "nova_pp_gen");
     let mut irw = InterRoundWires::new();
@@ -126,7 +263,7 @@ pub fn gen_pp<AF: ArkPrimeField>(empty_csc: &mut CoralStepCircuit<AF>) -> Public
         &*default_ck_hint(),
         &*default_ck_hint(),
         vec![empty_csc.key_length],
-        Some("./ppot_0080_23.ptau"),
+        Some(&srs.path),
     )
     .unwrap();
     #[cfg(feature = "metrics")]
@@ -134,6 +271,105 @@ pub fn gen_pp<AF: ArkPrimeField>(empty_csc: &mut CoralStepCircuit<AF>) -> Public
     pp
 }
 
+pub const SETUP_BUNDLE_MAGIC: &[u8; 8] = b"CORALSB\0";
+pub const SETUP_BUNDLE_VERSION: u32 = 4;
+
+// Everything prove and verify need that only depends on the grammar, the batch size and the
+// tree bucket, along with the SRS it was derived from. The R1CS matrices travel inside pp as its
+// shape. On disk it follows a magic and version header, so an old bundle is rejected before its
+// payload is deserialized.
+#[serde_with::serde_as]
+#[derive(Serialize, Deserialize)]
+pub struct SetupBundle {
+    #[serde_as(as = "CompressedChecked<AF>")]
+    pub grammar_digest: AF,
+    pub batch_size: usize,
+    pub tree_bucket: usize,
+    pub srs_sha256: [u8; 32],
+    pub pp: PublicParams<E1, E2, C1>,
+    pub pk: nova::ProverKey<E1, E2, C1, S1, S2>,
+    pub vk: nova::VerifierKey<E1, E2, C1, S1, S2>,
+    //Running memory and step 0 accumulators of the public circuit, for the verifier
+    #[serde_as(as = "CompressedChecked<RunningMem<AF>>")]
+    pub mem: RunningMem<AF>,
+    #[serde_as(as = "CompressedChecked<Vec<AF>>")]
+    pub initial_mem: Vec<AF>,
+}
+
+impl SetupBundle {
+    pub fn generate(
+        grammar_graph: &GrammarGraph,
+        batch_size: usize,
+        tree_bucket: usize,
+        srs: &Srs,
+    ) -> Self {
+        let mut empty_circuit =
+            CoralStepCircuit::<AF>::public_circuit(grammar_graph, batch_size, tree_bucket, srs);
+        let initial_mem = crate::verifier::initial_mem_accs(&empty_circuit);
+
+        let pp = gen_pp(&mut empty_circuit, srs);
+
+        #[cfg(feature = "metrics")]
+        log::tic(Component::Generator, "snark_params");
+        let (pk, vk) = CompressedSNARK::<_, _, _, S1, S2>::setup(&pp).unwrap();
+
+        #[cfg(feature = "metrics")]
+        log::stop(Component::Generator, "snark_params");
+
+        SetupBundle {
+            grammar_digest: grammar_digest(grammar_graph),
+            batch_size,
+            tree_bucket,
+            srs_sha256: srs.sha256,
+            pp,
+            pk,
+            vk,
+            mem: empty_circuit.mem.unwrap(),
+            initial_mem,
+        }
+    }
+
+    pub fn matches(&self, grammar_graph: &GrammarGraph) -> bool {
+        self.grammar_digest == grammar_digest::<AF>(grammar_graph)
+    }
+
+    pub fn matches_srs(&self, srs: &Srs) -> bool {
+        self.srs_sha256 == srs.sha256
+    }
+
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        let mut data = SETUP_BUNDLE_MAGIC.to_vec();
+        data.extend_from_slice(&SETUP_BUNDLE_VERSION.to_le_bytes());
+        bincode::serialize_into(&mut data, self).map_err(io::Error::other)?;
+        fs::write(path, data)
+    }
+
+    pub fn read(path: &str) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != SETUP_BUNDLE_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a setup bundle",
+            ));
+        }
+        let version = read_u32(&mut reader)?;
+        if version != SETUP_BUNDLE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "setup bundle version {}, expected {}",
+                    version, SETUP_BUNDLE_VERSION
+                ),
+            ));
+        }
+
+        bincode::deserialize_from(reader).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
 pub fn trim<'a>(
     pp: UniversalParams<Bn254>,
     mut supported_degree: usize,
@@ -161,15 +397,240 @@ pub fn trim<'a>(
     Ok((powers, vk))
 }
 
-pub fn gen_ark_pp<'a>(doc_len: usize) -> (Powers<'a, Bn254>, kzg10::VerifierKey<Bn254>) {
-    let rng = &mut OsRng;
-    let ark_kzg_pp = ArkKZG::setup(doc_len + 1, false, rng).unwrap();
+// Doc commitment key comes out of the same Powers of Tau as Nova, so the committer never
+// knows the trapdoor
+pub fn gen_ark_pp<'a>(doc_len: usize, srs: &Srs) -> (Powers<'a, Bn254>, kzg10::VerifierKey<Bn254>) {
+    let supported_degree = usize::max(doc_len + 1, 2);
+    let ark_kzg_pp =
+        read_ptau_kzg(&srs.path, supported_degree + 1).expect("Failed to read ptau file");
 
-    let (ck, vk) = trim(ark_kzg_pp, doc_len + 1).unwrap();
+    let (ck, vk) = trim(ark_kzg_pp, supported_degree).unwrap();
 
     (ck, vk)
 }
 
+// The KZG verifier key does not depend on the document length
+pub fn gen_ark_vk(srs: &Srs) -> io::Result<kzg10::VerifierKey<Bn254>> {
+    let ark_kzg_pp = read_ptau_kzg(&srs.path, 3)?;
+
+    trim(ark_kzg_pp, 2)
+        .map(|(_, vk)| vk)
+        .map_err(|e| ptau_err(&e.to_string()))
+}
+
+fn ptau_err(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("ptau: {}", msg))
+}
+
+pub(crate) fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub(crate) fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_fq_repr<R: Read>(reader: &mut R) -> io::Result<BigInt<4>> {
+    let mut limbs = [0u64; 4];
+    for limb in limbs.iter_mut() {
+        *limb = read_u64(reader)?;
+    }
+    Ok(BigInt::new(limbs))
+}
+
+// Coordinates are stored little endian in Montgomery form
+fn read_fq<R: Read>(reader: &mut R) -> io::Result<Fq> {
+    let repr = read_fq_repr(reader)?;
+    if repr >= Fq::MODULUS {
+        return Err(ptau_err("coordinate out of range"));
+    }
+    Ok(Fq::new_unchecked(repr))
+}
+
+fn read_g1<R: Read>(reader: &mut R) -> io::Result<G1Affine> {
+    let x = read_fq(reader)?;
+    let y = read_fq(reader)?;
+    if x.is_zero() && y.is_zero() {
+        return Ok(G1Affine::identity());
+    }
+    let p = G1Affine::new_unchecked(x, y);
+    if !p.is_on_curve() || !p.is_in_correct_subgroup_assuming_on_curve() {
+        return Err(ptau_err("invalid G1 point"));
+    }
+    Ok(p)
+}
+
+fn read_g2<R: Read>(reader: &mut R) -> io::Result<G2Affine> {
+    let x = Fq2::new(read_fq(reader)?, read_fq(reader)?);
+    let y = Fq2::new(read_fq(reader)?, read_fq(reader)?);
+    if x.is_zero() && y.is_zero() {
+        return Ok(G2Affine::identity());
+    }
+    let p = G2Affine::new_unchecked(x, y);
+    if !p.is_on_curve() || !p.is_in_correct_subgroup_assuming_on_curve() {
+        return Err(ptau_err("invalid G2 point"));
+    }
+    Ok(p)
+}
+
+// Reads the first num_powers of [tau^i]G1 and [alpha tau^i]G1 plus [1]G2, [tau]G2 out of a
+// snarkjs .ptau file. alpha takes the role of gamma in the hiding KZG commitment.
+pub fn read_ptau_kzg(ptau_path: &str, num_powers: usize) -> io::Result<UniversalParams<Bn254>> {
+    let mut reader = BufReader::new(File::open(ptau_path)?);
+
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != b"ptau" {
+        return Err(ptau_err("bad magic"));
+    }
+    let _version = read_u32(&mut reader)?;
+    let n_sections = read_u32(&mut reader)?;
+
+    let mut sections: HashMap<u32, u64> = new_hash_map();
+    for _ in 0..n_sections {
+        let id = read_u32(&mut reader)?;
+        let size = read_u64(&mut reader)?;
+        sections.insert(id, reader.stream_position()?);
+        reader.seek(SeekFrom::Current(size as i64))?;
+    }
+    let seek_section = |reader: &mut BufReader<File>, id: u32| -> io::Result<()> {
+        let pos = sections
+            .get(&id)
+            .ok_or_else(|| ptau_err(&format!("missing section {}", id)))?;
+        reader.seek(SeekFrom::Start(*pos))?;
+        Ok(())
+    };
+
+    //Header: n8, q, power
+    seek_section(&mut reader, 1)?;
+    let n8 = read_u32(&mut reader)?;
+    if n8 != 32 || read_fq_repr(&mut reader)? != Fq::MODULUS {
+        return Err(ptau_err("not a BN254 ptau file"));
+    }
+    let power = read_u32(&mut reader)?;
+    if num_powers > (1usize << power) {
+        return Err(ptau_err("file has too few powers"));
+    }
+
+    seek_section(&mut reader, 2)?;
+    let powers_of_g = (0..num_powers)
+        .map(|_| read_g1(&mut reader))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    seek_section(&mut reader, 3)?;
+    let h = read_g2(&mut reader)?;
+    let beta_h = read_g2(&mut reader)?;
+
+    seek_section(&mut reader, 4)?;
+    let mut powers_of_gamma_g = BTreeMap::new();
+    for i in 0..num_powers {
+        powers_of_gamma_g.insert(i, read_g1(&mut reader)?);
+    }
+
+    Ok(UniversalParams {
+        powers_of_g,
+        powers_of_gamma_g,
+        h,
+        beta_h,
+        neg_powers_of_h: BTreeMap::new(),
+        prepared_h: h.into(),
+        prepared_beta_h: beta_h.into(),
+    })
+}
+
+#[cfg(any(test, feature = "test-srs"))]
+fn write_fq<W: io::Write>(writer: &mut W, x: &Fq) -> io::Result<()> {
+    for limb in x.0.0.iter() {
+        writer.write_all(&limb.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(any(test, feature = "test-srs"))]
+fn write_g1<W: io::Write>(writer: &mut W, p: &G1Affine) -> io::Result<()> {
+    write_fq(writer, &p.x)?;
+    write_fq(writer, &p.y)
+}
+
+#[cfg(any(test, feature = "test-srs"))]
+fn write_g2<W: io::Write>(writer: &mut W, p: &G2Affine) -> io::Result<()> {
+    write_fq(writer, &p.x.c0)?;
+    write_fq(writer, &p.x.c1)?;
+    write_fq(writer, &p.y.c0)?;
+    write_fq(writer, &p.y.c1)
+}
+
+// Writes a snarkjs style .ptau file with 2^power powers from a seeded RNG. tau is derivable from
+// the seed, so this is only fit for tests.
+#[cfg(any(test, feature = "test-srs"))]
+pub fn write_test_ptau(ptau_path: &str, power: u32, seed: u64) -> io::Result<()> {
+    use ark_bn254::{Fr, G1Projective, G2Projective};
+    use ark_ec::{PrimeGroup, scalar_mul::ScalarMul};
+    use ark_std::{One, UniformRand};
+    use rand::{SeedableRng, rngs::StdRng};
+    use std::io::Write;
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let tau = Fr::rand(&mut rng);
+    let alpha = Fr::rand(&mut rng);
+    let beta = Fr::rand(&mut rng);
+
+    let n = 1usize << power;
+    let mut tau_powers = Vec::with_capacity(2 * n - 1);
+    let mut cur = Fr::one();
+    for _ in 0..2 * n - 1 {
+        tau_powers.push(cur);
+        cur *= tau;
+    }
+    let scaled = |c: Fr| tau_powers[..n].iter().map(|t| *t * c).collect::<Vec<_>>();
+
+    let g1 = G1Projective::generator();
+    let g2 = G2Projective::generator();
+    let tau_g1 = g1.batch_mul(&tau_powers);
+    let tau_g2 = g2.batch_mul(&tau_powers[..n]);
+    let alpha_tau_g1 = g1.batch_mul(&scaled(alpha));
+    let beta_tau_g1 = g1.batch_mul(&scaled(beta));
+    let beta_g2 = g2.batch_mul(&[beta]);
+
+    let mut sections: Vec<Vec<u8>> = vec![Vec::new(); 11];
+    sections[0].extend_from_slice(&32u32.to_le_bytes());
+    for limb in Fq::MODULUS.0.iter() {
+        sections[0].extend_from_slice(&limb.to_le_bytes());
+    }
+    sections[0].extend_from_slice(&power.to_le_bytes());
+    sections[0].extend_from_slice(&power.to_le_bytes());
+    for p in tau_g1.iter() {
+        write_g1(&mut sections[1], p)?;
+    }
+    for p in tau_g2.iter() {
+        write_g2(&mut sections[2], p)?;
+    }
+    for p in alpha_tau_g1.iter() {
+        write_g1(&mut sections[3], p)?;
+    }
+    for p in beta_tau_g1.iter() {
+        write_g1(&mut sections[4], p)?;
+    }
+    write_g2(&mut sections[5], &beta_g2[0])?;
+    //No contributions
+    sections[6].extend_from_slice(&0u32.to_le_bytes());
+
+    let mut writer = io::BufWriter::new(File::create(ptau_path)?);
+    writer.write_all(b"ptau")?;
+    writer.write_all(&1u32.to_le_bytes())?;
+    writer.write_all(&(sections.len() as u32).to_le_bytes())?;
+    for (i, data) in sections.iter().enumerate() {
+        writer.write_all(&(i as u32 + 1).to_le_bytes())?;
+        writer.write_all(&(data.len() as u64).to_le_bytes())?;
+        writer.write_all(data)?;
+    }
+    writer.flush()
+}
+
 pub fn metrics_file(
     metrics: Option<PathBuf>,
     grammar: &String,
@@ -206,3 +667,23 @@ pub fn metrics_file(
     #[cfg(feature = "metrics")]
     log::write_csv(metrics.unwrap().to_str().unwrap()).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srs_without_matching_hash_rejected() {
+        let path =
+            std::env::temp_dir().join(format!("coral_srs_check_{}.ptau", std::process::id()));
+        fs::write(&path, b"not a ceremony file").unwrap();
+        let path = path.to_str().unwrap();
+
+        assert!(Srs::open(path, None, false).is_err());
+        assert!(Srs::open(path, Some(&"00".repeat(32)), false).is_err());
+
+        let srs = Srs::open(path, None, true).unwrap();
+        assert_eq!(srs.sha256_hex().len(), 64);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,6 +1,10 @@
-use crate::{prover::ProverOutput, solver::*, util::*};
+use crate::{
+    parser::GrammarGraph,
+    prover::{ProverOutput, make_coral_circuit},
+    solver::*,
+    util::*,
+};
 use ark_bn254::Bn254;
-use ark_ff::PrimeField as arkPrimeField;
 use ark_poly_commit::kzg10;
 use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
 use nova_snark::nova::CompressedSNARK;
@@ -10,80 +14,316 @@ use nova_snark::{
 };
 use segmented_circuit_memory::memory::nebula::RunningMem;
 
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
 use std::usize;
 
 #[cfg(feature = "metrics")]
 use metrics::metrics::{log, log::Component};
 
-pub struct VerifierInfo<ArkF: arkPrimeField> {
-    pub tree_size: usize,
+pub struct VerifierInfo {
+    pub tree_bucket: usize,
+    pub batch_size: usize,
     pub pp: PublicParams<E1, E2, C1>,
     pub num_steps: usize,
+    pub has_stack: bool,
     pub mem: RunningMem<AF>,
+    //Memory accumulators of the public circuit at step 0
+    pub initial_mem: Vec<AF>,
     pub snark_vk: VerifierKey<E1, E2, C1, S1, S2>,
-    pub perm_chal: Vec<ArkF>,
+    pub grammar_digest: AF,
+    pub doc_commit_vk: kzg10::VerifierKey<Bn254>,
+}
+
+#[derive(Debug)]
+pub enum CoralVerifyError {
+    //Proof was made for a different batch size or tree bucket
+    ShapeMismatch,
+    Snark(NovaError),
+    //Verifier key could not be derived from the public params
+    SnarkSetup(NovaError),
+    InitialState,
+    TreeNotFinished,
+    StackPointer { stack: usize },
+    MemoryConsistency(MemoryCheckError),
+    DocCommitOpening,
+    GrammarMismatch,
+    //Setup bundle was derived from a different SRS
+    SrsMismatch,
+    //The ptau file could not be read for the doc commitment key
+    SrsUnreadable(std::io::Error),
+}
+
+impl CoralVerifyError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CoralVerifyError::ShapeMismatch => 2,
+            CoralVerifyError::Snark(_) | CoralVerifyError::SnarkSetup(_) => 3,
+            CoralVerifyError::InitialState => 4,
+            CoralVerifyError::TreeNotFinished => 5,
+            CoralVerifyError::StackPointer { .. } => 6,
+            CoralVerifyError::MemoryConsistency(_) => 7,
+            CoralVerifyError::DocCommitOpening => 8,
+            CoralVerifyError::GrammarMismatch => 9,
+            CoralVerifyError::SrsMismatch => 10,
+            CoralVerifyError::SrsUnreadable(_) => 11,
+        }
+    }
+}
+
+impl fmt::Display for CoralVerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoralVerifyError::ShapeMismatch => {
+                write!(f, "proof is for a different batch size or tree bucket")
+            }
+            CoralVerifyError::Snark(e) => write!(f, "compressed SNARK did not verify: {}", e),
+            CoralVerifyError::SnarkSetup(e) => write!(f, "compressed SNARK setup failed: {}", e),
+            CoralVerifyError::InitialState => {
+                write!(f, "proof does not start from the initial state")
+            }
+            CoralVerifyError::TreeNotFinished => {
+                write!(f, "parse tree did not end inside the bucket")
+            }
+            CoralVerifyError::StackPointer { stack } => {
+                write!(f, "stack {} is not empty at the end", stack)
+            }
+            CoralVerifyError::MemoryConsistency(e) => {
+                write!(f, "memory consistency checks failed: {}", e)
+            }
+            CoralVerifyError::DocCommitOpening => {
+                write!(f, "document commitment opening failed")
+            }
+            CoralVerifyError::GrammarMismatch => {
+                write!(f, "proof was made for a different grammar")
+            }
+            CoralVerifyError::SrsMismatch => {
+                write!(f, "setup bundle was made from a different SRS")
+            }
+            CoralVerifyError::SrsUnreadable(e) => write!(f, "unable to read SRS: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CoralVerifyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CoralVerifyError::Snark(e) | CoralVerifyError::SnarkSetup(e) => Some(e),
+            CoralVerifyError::MemoryConsistency(e) => Some(e),
+            CoralVerifyError::SrsUnreadable(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MemoryCheckError {
+    //Final state is not as wide as the initial one
+    FinalState { expected: usize, found: usize },
+    //Running memory products do not match
+    Accumulators,
+}
+
+impl fmt::Display for MemoryCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryCheckError::FinalState { expected, found } => {
+                write!(f, "final state has {} slots, expected {}", found, expected)
+            }
+            MemoryCheckError::Accumulators => write!(f, "running memory products do not match"),
+        }
+    }
+}
+
+impl std::error::Error for MemoryCheckError {}
+
+// Final state checks for the running memory. Everything RunningMem::verifier_checks indexes is
+// checked here first, its product checks assert, so a failure there comes back as Accumulators
+// and never unwinds out of the verifier.
+pub fn memory_checks(
+    z_0: &[N1],
+    zn: &[N1],
+    product_checks: impl FnOnce(),
+) -> Result<(), MemoryCheckError> {
+    if zn.len() != z_0.len() {
+        return Err(MemoryCheckError::FinalState {
+            expected: z_0.len(),
+            found: zn.len(),
+        });
+    }
+
+    panic::catch_unwind(AssertUnwindSafe(product_checks))
+        .map_err(|_| MemoryCheckError::Accumulators)
 }
 
 #[derive(CanonicalSerialize, CanonicalDeserialize, Debug)]
 pub struct VerifierDocCommit {
     pub doc_commit: kzg10::Commitment<Bn254>,
-    pub doc_commit_vk: kzg10::VerifierKey<Bn254>,
 }
 
-pub fn setup(empty_circuit: &mut CoralStepCircuit<AF>) -> VerifierInfo<AF> {
-    let pp = gen_pp(empty_circuit);
+// Rebuilds the step circuit from the public grammar and the claimed shape, nothing is taken
+// from the prover. A setup bundle already holds everything the circuit would give.
+pub fn setup(
+    grammar_graph: &GrammarGraph,
+    batch_size: usize,
+    tree_bucket: usize,
+    params: Option<SetupBundle>,
+    srs: &Srs,
+) -> Result<VerifierInfo, CoralVerifyError> {
+    let (pp, vk, mem, initial_mem) = match params {
+        Some(bundle) => {
+            if !bundle.matches(grammar_graph) {
+                return Err(CoralVerifyError::GrammarMismatch);
+            }
+            if !bundle.matches_srs(srs) {
+                return Err(CoralVerifyError::SrsMismatch);
+            }
+            if bundle.batch_size != batch_size || bundle.tree_bucket != tree_bucket {
+                return Err(CoralVerifyError::ShapeMismatch);
+            }
+            (bundle.pp, bundle.vk, bundle.mem, bundle.initial_mem)
+        }
+        None => {
+            let mut empty_circuit =
+                CoralStepCircuit::<AF>::public_circuit(grammar_graph, batch_size, tree_bucket, srs);
+            let initial_mem = initial_mem_accs(&empty_circuit);
+            let pp = gen_pp(&mut empty_circuit, srs);
 
-    #[cfg(feature = "metrics")]
-    log::tic(Component::Verifier, "snark_params");
-    let (_, vk) = CompressedSNARK::<_, _, _, S1, S2>::setup(&pp).unwrap();
+            #[cfg(feature = "metrics")]
+            log::tic(Component::Verifier, "snark_params");
+            let (_, vk) = CompressedSNARK::<_, _, _, S1, S2>::setup(&pp)
+                .map_err(CoralVerifyError::SnarkSetup)?;
 
-    #[cfg(feature = "metrics")]
-    log::stop(Component::Verifier, "snark_params");
+            #[cfg(feature = "metrics")]
+            log::stop(Component::Verifier, "snark_params");
+            (pp, vk, empty_circuit.mem.unwrap(), initial_mem)
+        }
+    };
 
-    VerifierInfo {
-        tree_size: empty_circuit.tree_size_usize,
+    Ok(VerifierInfo {
+        tree_bucket,
+        batch_size,
         pp,
-        num_steps: usize::div_ceil(empty_circuit.tree_size_usize, empty_circuit.batch_size),
-        mem: empty_circuit.mem.clone().unwrap(),
+        num_steps: usize::div_ceil(tree_bucket, batch_size),
+        has_stack: grammar_graph.has_stack(),
+        mem,
+        initial_mem,
         snark_vk: vk,
-        perm_chal: empty_circuit.mem.as_ref().unwrap().perm_chal.clone(),
-    }
+        grammar_digest: grammar_digest(grammar_graph),
+        //Derived from the ptau, not from anything the committer sends
+        doc_commit_vk: gen_ark_vk(srs).map_err(CoralVerifyError::SrsUnreadable)?,
+    })
+}
+
+// Memory accumulators in z_0 of the circuit's first step, they only depend on its shape and
+// the public ROMs
+pub fn initial_mem_accs(empty_circuit: &CoralStepCircuit<AF>) -> Vec<AF> {
+    let mut probe = empty_circuit.clone();
+    let mut irw = InterRoundWires::new();
+    let circuit = make_coral_circuit(&mut probe, &mut irw, 0, None);
+    let z0 = circuit.get_zi()[probe.key_length..].to_vec();
+
+    PublicIoLayout::new(z0.len(), empty_circuit.has_stack)
+        .mem_accs
+        .map(|i| segmented_circuit_memory::bellpepper::nova_to_ark_field(&z0[i]))
+        .collect()
+}
+
+// First z_0 slot that is not where the verifier expects the proof to start
+pub fn initial_mismatch(expected: &[(usize, AF)], z_0: &[N1]) -> Option<usize> {
+    expected.iter().find_map(|&(i, value)| {
+        let claimed: AF = segmented_circuit_memory::bellpepper::nova_to_ark_field(&z_0[i]);
+        (claimed != value).then_some(i)
+    })
 }
 
 pub fn verify(
     p_o: &mut ProverOutput,
-    v_i: VerifierInfo<AF>,
+    mut v_i: VerifierInfo,
     v_dc: VerifierDocCommit,
-) -> Result<(), NovaError> {
+) -> Result<(), CoralVerifyError> {
     #[cfg(feature = "metrics")]
     log::tic(Component::Verifier, "full_verify");
 
+    // The proof has to be for the circuit the verifier built
+    if p_o.batch_size != v_i.batch_size
+        || p_o.tree_bucket != v_i.tree_bucket
+        || p_o.perm_chal.len() != v_i.mem.perm_chal.len()
+        || p_o.z_0.len()
+            < PublicIoLayout::n_stacks(v_i.has_stack) + PublicIoLayout::coral_len(v_i.has_stack)
+    {
+        return Err(CoralVerifyError::ShapeMismatch);
+    }
+    //Memory challenges are bound to the commitments ci by verifier_checks
+    v_i.mem.perm_chal = p_o.perm_chal.clone();
+
+    //The doc opening point is never read from the proof, it is hashed from the commitments
+    let doc_chal = doc_challenge(&v_dc.doc_commit, v_i.grammar_digest, &p_o.perm_chal);
+
+    let layout = PublicIoLayout::new(p_o.z_0.len(), v_i.has_stack);
+    if layout.mem_accs.len() != v_i.initial_mem.len() {
+        return Err(CoralVerifyError::ShapeMismatch);
+    }
+
+    //Proof must start where the verifier says, which also binds it to the grammar
+    let expected = layout.initial_state(
+        v_i.tree_bucket,
+        v_i.grammar_digest,
+        doc_chal,
+        &v_i.initial_mem,
+    );
+    if let Some(i) = initial_mismatch(&expected, &p_o.z_0) {
+        return Err(if i == layout.grammar_digest {
+            CoralVerifyError::GrammarMismatch
+        } else if i == layout.doc_chal {
+            CoralVerifyError::DocCommitOpening
+        } else {
+            CoralVerifyError::InitialState
+        });
+    }
+
     #[cfg(feature = "metrics")]
     log::tic(Component::Verifier, "snark_verify");
 
-    let comp_snark_result = p_o
+    let (zn, ci) = p_o
         .compressed_snark
-        .verify(&v_i.snark_vk, v_i.num_steps, &p_o.z_0);
-    assert!(comp_snark_result.is_ok());
+        .verify(&v_i.snark_vk, v_i.num_steps, &p_o.z_0)
+        .map_err(CoralVerifyError::Snark)?;
 
     #[cfg(feature = "metrics")]
     log::stop(Component::Verifier, "snark_verify");
 
-    // check final cmt outputs
-    let (zn, ci) = comp_snark_result.unwrap();
-
     #[cfg(feature = "metrics")]
     log::tic(Component::Verifier, "eq_checks");
 
-    v_i.mem.verifier_checks(&zn, &ci);
+    // check final cmt outputs
+    memory_checks(&p_o.z_0, &zn, || v_i.mem.verifier_checks(&zn, &ci))
+        .map_err(CoralVerifyError::MemoryConsistency)?;
 
-    //Have to get stack ptrs out
-    let sp_offset = 11;
-    let sp_0 = zn[sp_offset];
-    let sp_1 = zn[sp_offset + 1];
+    //Real tree ended inside the bucket
+    let final_node: AF =
+        segmented_circuit_memory::bellpepper::nova_to_ark_field(&zn[layout.cur_node_id]);
+    if final_node != to_F::<AF>(v_i.tree_bucket) {
+        return Err(CoralVerifyError::TreeNotFinished);
+    }
 
-    assert_eq!(sp_0, N1::from(1));
-    assert_eq!(sp_1, N1::from(1));
+    //Stacks are empty at the end, pest does not require the delimiter stack to be
+    for (stack, i) in layout
+        .stack_ptrs
+        .clone()
+        .take(PublicIoLayout::N_TREE_STACKS)
+        .enumerate()
+    {
+        if zn[i] != N1::from(1) {
+            return Err(CoralVerifyError::StackPointer { stack });
+        }
+    }
+
+    let claimed_digest: AF =
+        segmented_circuit_memory::bellpepper::nova_to_ark_field(&zn[layout.grammar_digest]);
+    if claimed_digest != v_i.grammar_digest {
+        return Err(CoralVerifyError::GrammarMismatch);
+    }
 
     #[cfg(feature = "metrics")]
     {
@@ -91,19 +331,23 @@ pub fn verify(
         log::tic(Component::Verifier, "doc_commit_check");
     }
 
-    //Have to get calimed eval out of zn
     //Check doc commitment
-    let eval_offset = sp_offset + 2;
-    let claimed_eval = zn[eval_offset];
+    let claimed_eval = zn[layout.running_eval];
+    let doc_commit_proof = p_o
+        .doc_commit_proof
+        .as_ref()
+        .ok_or(CoralVerifyError::DocCommitOpening)?;
     let kzg_check = ArkKZG::check(
-        &v_dc.doc_commit_vk,
+        &v_i.doc_commit_vk,
         &v_dc.doc_commit,
-        v_i.perm_chal[0],
+        doc_chal,
         segmented_circuit_memory::bellpepper::nova_to_ark_field(&claimed_eval),
-        p_o.doc_commit_proof.as_ref().unwrap(),
+        doc_commit_proof,
     )
-    .unwrap();
-    assert!(kzg_check);
+    .unwrap_or(false);
+    if !kzg_check {
+        return Err(CoralVerifyError::DocCommitOpening);
+    }
 
     println!("Verified Successfully!");
 
@@ -115,3 +359,26 @@ pub fn verify(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_checks_report_errors() {
+        let z = vec![N1::from(1); 4];
+
+        assert!(memory_checks(&z, &z, || ()).is_ok());
+        assert!(matches!(
+            memory_checks(&z, &z[..3], || unreachable!()),
+            Err(MemoryCheckError::FinalState {
+                expected: 4,
+                found: 3
+            })
+        ));
+        assert!(matches!(
+            memory_checks(&z, &z, || panic!("products differ")),
+            Err(MemoryCheckError::Accumulators)
+        ));
+    }
+}